@@ -0,0 +1,768 @@
+use std::sync::Arc;
+
+use crate::{
+    camera::SimpleCamera,
+    color::{black, color3, Color3},
+    common::{F, I, PI, S},
+    film::Film,
+    integrator::Integrator,
+    interaction::Interaction,
+    light::VisibilityTester,
+    material::{TransportMode, BXDF_ALL, BXDF_SPECULAR},
+    ray::Ray,
+    rng::{RngGen, CONNECTION_STREAM, LIGHT_STREAM},
+    scene::Scene,
+    vector::{point3, Point2, Point3, Vec3},
+};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum VertexType {
+    Camera,
+    Light,
+    Surface,
+    Medium,
+}
+
+/// A vertex of a camera or light subpath.
+#[derive(Clone)]
+struct Vertex {
+    kind: VertexType,
+    beta: Color3,
+    inter: Interaction,
+    /// Index into `Scene::lights` for light vertices. Light vertices without one were created
+    /// by camera rays escaping the scene, and stand for all infinite lights.
+    light: Option<S>,
+    /// Whether the vertex scattered with a delta distribution, so it can't be connected to.
+    delta: bool,
+    /// Density of sampling this vertex from the previous one, per unit area.
+    pdf_fwd: F,
+    /// Density of sampling this vertex from the next one, per unit area.
+    pdf_rev: F,
+}
+
+/// Per-path state that the vertex methods need to look things up.
+struct PathContext<'a> {
+    scene: &'a Scene,
+    camera: &'a SimpleCamera,
+    world_radius: F,
+}
+
+fn remap0(f: F) -> F {
+    if f != 0.0 {
+        f
+    } else {
+        1.0
+    }
+}
+
+/// Corrects for the asymmetry that shading normals introduce when transporting importance.
+fn correct_shading_normal(inter: &Interaction, wo: &Vec3, wi: &Vec3, mode: TransportMode) -> F {
+    if mode == TransportMode::Radiance {
+        return 1.0;
+    }
+    let (ns, ng) = match (&inter.shading, inter.n) {
+        (Some(shading), Some(ng)) => (shading.n, ng),
+        _ => return 1.0,
+    };
+    let num = wo.dot(&ns).abs() * wi.dot(&ng).abs();
+    let denom = wo.dot(&ng).abs() * wi.dot(&ns).abs();
+    if denom == 0.0 {
+        0.0
+    } else {
+        num / denom
+    }
+}
+
+/// Density of the infinite lights emitting towards `-w`, including the choice of light.
+fn infinite_light_density(scene: &Scene, w: &Vec3) -> F {
+    let dummy = Interaction::new_general(point3(0.0, 0.0, 0.0), 0.0);
+    let pdf: F = scene
+        .lights
+        .iter()
+        .filter(|light| light.is_infinite())
+        .map(|light| light.pdf_li(&dummy, &-w))
+        .sum();
+    pdf / scene.lights.len() as F
+}
+
+impl Vertex {
+    fn new(kind: VertexType, inter: Interaction, beta: Color3) -> Self {
+        Self {
+            kind,
+            beta,
+            inter,
+            light: None,
+            delta: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn new_light(inter: Interaction, light: Option<S>, beta: Color3, pdf_fwd: F) -> Self {
+        Self {
+            light,
+            pdf_fwd,
+            ..Self::new(VertexType::Light, inter, beta)
+        }
+    }
+
+    /// Creates a surface or medium vertex, converting the solid angle density `pdf` of sampling
+    /// it from `prev` to an area density.
+    fn new_scattering(
+        scene: &Scene,
+        inter: Interaction,
+        beta: Color3,
+        pdf: F,
+        prev: &Vertex,
+    ) -> Self {
+        let kind = if inter.is_medium_interaction() {
+            VertexType::Medium
+        } else {
+            VertexType::Surface
+        };
+        let mut v = Self::new(kind, inter, beta);
+        v.pdf_fwd = prev.convert_density(scene, pdf, &v);
+        v
+    }
+
+    fn p(&self) -> Point3 {
+        self.inter.p
+    }
+
+    fn ng(&self) -> Option<Vec3> {
+        self.inter.n
+    }
+
+    fn ns(&self) -> Option<Vec3> {
+        match self.inter.shading {
+            Some(ref shading) => Some(shading.n),
+            None => self.inter.n,
+        }
+    }
+
+    fn is_on_surface(&self) -> bool {
+        self.inter.n.is_some()
+    }
+
+    fn is_light(&self) -> bool {
+        self.kind == VertexType::Light
+    }
+
+    fn is_infinite_light(&self, scene: &Scene) -> bool {
+        self.kind == VertexType::Light
+            && match self.light {
+                Some(light) => scene.lights[light].is_infinite(),
+                None => true,
+            }
+    }
+
+    fn is_delta_light(&self, scene: &Scene) -> bool {
+        match self.light {
+            Some(light) => {
+                self.kind == VertexType::Light && scene.lights[light].is_delta_position()
+            }
+            None => false,
+        }
+    }
+
+    fn is_connectible(&self) -> bool {
+        match self.kind {
+            VertexType::Camera | VertexType::Light | VertexType::Medium => true,
+            VertexType::Surface => self
+                .inter
+                .bsdf
+                .as_ref()
+                .map(|bsdf| bsdf.num_components(BXDF_ALL & !BXDF_SPECULAR) > 0)
+                .unwrap_or(false),
+        }
+    }
+
+    /// Evaluates the scattering function at this vertex for light going towards `next`.
+    fn f(&self, next: &Vertex, mode: TransportMode) -> Color3 {
+        let wi = (next.p() - self.p()).normalize();
+        let wo = self.inter.wo.unwrap().normalize();
+        match self.kind {
+            VertexType::Surface => {
+                let bsdf = self.inter.bsdf.as_ref().unwrap();
+                bsdf.f(&wo, &wi, BXDF_ALL) * correct_shading_normal(&self.inter, &wo, &wi, mode)
+            }
+            VertexType::Medium => {
+                let p = self.inter.phase.as_ref().unwrap().p(&wo, &wi);
+                color3(p, p, p)
+            }
+            _ => black(),
+        }
+    }
+
+    /// Converts a solid angle density of sampling `next` from this vertex to an area density.
+    fn convert_density(&self, scene: &Scene, pdf: F, next: &Vertex) -> F {
+        if next.is_infinite_light(scene) {
+            return pdf;
+        }
+        let w = next.p() - self.p();
+        let inv_dist2 = 1.0 / w.magnitude_squared();
+        let mut pdf = pdf;
+        if let Some(ng) = next.ng() {
+            pdf *= ng.dot(&(w * inv_dist2.sqrt())).abs();
+        }
+        pdf * inv_dist2
+    }
+
+    /// Density per unit area of sampling `next` given that the path arrived here from `prev`.
+    fn pdf(&self, ctx: &PathContext, prev: Option<&Vertex>, next: &Vertex) -> F {
+        if self.kind == VertexType::Light {
+            return self.pdf_light(ctx, next);
+        }
+        let wn = (next.p() - self.p()).normalize();
+        let wp = prev.map(|prev| (prev.p() - self.p()).normalize());
+        let pdf = match self.kind {
+            VertexType::Camera => {
+                let ray = Ray::new_non_differential(
+                    self.p(),
+                    wn,
+                    0.0,
+                    F::INFINITY,
+                    self.inter.time,
+                    None,
+                );
+                ctx.camera.pdf_we(&ray).1
+            }
+            VertexType::Surface => {
+                self.inter
+                    .bsdf
+                    .as_ref()
+                    .unwrap()
+                    .pdf(&wp.unwrap(), &wn, BXDF_ALL)
+            }
+            VertexType::Medium => self.inter.phase.as_ref().unwrap().p(&wp.unwrap(), &wn),
+            VertexType::Light => unreachable!(),
+        };
+        self.convert_density(ctx.scene, pdf, next)
+    }
+
+    /// Density per unit area of this light emitting towards `next`.
+    fn pdf_light(&self, ctx: &PathContext, next: &Vertex) -> F {
+        let mut w = next.p() - self.p();
+        let inv_dist2 = 1.0 / w.magnitude_squared();
+        w *= inv_dist2.sqrt();
+        let mut pdf = if self.is_infinite_light(ctx.scene) {
+            1.0 / (PI * ctx.world_radius * ctx.world_radius)
+        } else {
+            let light = &ctx.scene.lights[self.light.unwrap()];
+            let ray =
+                Ray::new_non_differential(self.p(), w, 0.0, F::INFINITY, self.inter.time, None);
+            light.pdf_le(&ray, &self.ng().unwrap_or(w)).1 * inv_dist2
+        };
+        if let Some(ng) = next.ng() {
+            pdf *= ng.dot(&w).abs();
+        }
+        pdf
+    }
+
+    /// Density per unit area of picking this point on a light, including the choice of light.
+    fn pdf_light_origin(&self, ctx: &PathContext, next: &Vertex) -> F {
+        let w = (next.p() - self.p()).normalize();
+        if self.is_infinite_light(ctx.scene) {
+            return infinite_light_density(ctx.scene, &w);
+        }
+        let light = &ctx.scene.lights[self.light.unwrap()];
+        let ray = Ray::new_non_differential(self.p(), w, 0.0, F::INFINITY, self.inter.time, None);
+        let pdf_choice = 1.0 / ctx.scene.lights.len() as F;
+        light.pdf_le(&ray, &self.ng().unwrap_or(w)).0 * pdf_choice
+    }
+
+    /// Radiance emitted from this vertex towards `prev`.
+    fn le(&self, scene: &Scene, prev: &Vertex) -> Color3 {
+        if !self.is_light() {
+            return black();
+        }
+        // TODO: area lights once primitives can be emitters.
+        let mut l = black();
+        if self.is_infinite_light(scene) {
+            let w = (prev.p() - self.p()).normalize();
+            let ray =
+                Ray::new_non_differential(self.p(), -w, 0.0, F::INFINITY, self.inter.time, None);
+            for light in scene.lights.iter().filter(|light| light.is_infinite()) {
+                l += light.le(&ray);
+            }
+        }
+        l
+    }
+}
+
+/// Geometric term between two vertices, including the transmittance of any media in between.
+fn g(scene: &Scene, v0: &Vertex, v1: &Vertex, rng: &RngGen) -> Color3 {
+    let mut d = v0.p() - v1.p();
+    let mut g = 1.0 / d.magnitude_squared();
+    d *= g.sqrt();
+    if v0.is_on_surface() {
+        g *= v0.ns().unwrap().dot(&d).abs();
+    }
+    if v1.is_on_surface() {
+        g *= v1.ns().unwrap().dot(&d).abs();
+    }
+    let vis = VisibilityTester {
+        p0: Arc::new(v0.inter.clone()),
+        p1: Arc::new(v1.inter.clone()),
+    };
+    vis.transmittance(scene, rng) * g
+}
+
+/// Bidirectional path tracer. Every pixel sample traces a subpath from the camera and one from
+/// a light, then connects every prefix of one to every prefix of the other, weighting the
+/// resulting strategies with multiple importance sampling. Light subpaths connected straight
+/// to the camera (`t = 1`) land on arbitrary pixels and are splatted.
+pub struct BdptIntegrator {
+    max_depth: S,
+    camera: Option<SimpleCamera>,
+    world_radius: F,
+    splats: Option<Film>,
+}
+
+impl BdptIntegrator {
+    pub fn new(max_depth: S) -> Self {
+        Self {
+            max_depth,
+            camera: None,
+            world_radius: 0.0,
+            splats: None,
+        }
+    }
+
+    /// Follows `ray` through the scene, appending up to `max_depth` scattering vertices to
+    /// `path`. `pdf` is the solid angle density of having sampled the ray's direction.
+    fn random_walk(
+        &self,
+        ctx: &PathContext,
+        mut ray: Ray,
+        mut beta: Color3,
+        pdf: F,
+        max_depth: S,
+        mode: TransportMode,
+        path: &mut Vec<Vertex>,
+        rng: &RngGen,
+    ) {
+        if max_depth == 0 {
+            return;
+        }
+        let mut bounces = 0;
+        let mut pdf_fwd = pdf;
+        loop {
+            let inter_opt = ctx.scene.intersect(&mut ray);
+            let mut medium_inter = None;
+            if let Some(ref medium) = ray.medium {
+                let (weight, mi) = medium.sample(&ray, rng);
+                beta.component_mul_assign(&weight);
+                medium_inter = mi;
+            }
+            if beta == black() {
+                break;
+            }
+
+            let pdf_rev;
+            if let Some(mi) = medium_inter {
+                let vertex =
+                    Vertex::new_scattering(ctx.scene, mi, beta, pdf_fwd, path.last().unwrap());
+                path.push(vertex);
+                bounces += 1;
+                if bounces >= max_depth {
+                    break;
+                }
+                let mi = &path.last().unwrap().inter;
+                let wo = -ray.direction.normalize();
+                let (wi, p) = mi
+                    .phase
+                    .as_ref()
+                    .unwrap()
+                    .sample_p(&wo, &rng.uniform_sample_point2());
+                pdf_fwd = p;
+                pdf_rev = p;
+                ray = mi.spawn_ray(wi);
+            } else {
+                let inter = match inter_opt {
+                    Some(inter) => inter,
+                    None => {
+                        // Camera rays that escape pick up the infinite lights.
+                        if mode == TransportMode::Radiance {
+                            let mut inter = Interaction::new_general(ray.at(1.0), ray.time);
+                            inter.n = Some(-ray.direction.normalize());
+                            path.push(Vertex::new_light(inter, None, beta, pdf_fwd));
+                        }
+                        break;
+                    }
+                };
+                if inter.bsdf.is_none() {
+                    // Medium boundaries don't count as a bounce.
+                    ray = inter.spawn_ray(ray.direction);
+                    continue;
+                }
+                let vertex =
+                    Vertex::new_scattering(ctx.scene, inter, beta, pdf_fwd, path.last().unwrap());
+                path.push(vertex);
+                bounces += 1;
+                if bounces >= max_depth {
+                    break;
+                }
+
+                let inter = &path.last().unwrap().inter;
+                let bsdf = inter.bsdf.as_ref().unwrap();
+                let ns = inter.shading.as_ref().unwrap().n;
+                let wo = -ray.direction.normalize();
+                let (f, pdf, wi, flags) =
+                    match bsdf.sample_f(&wo, &rng.uniform_sample_point2(), BXDF_ALL) {
+                        Some((f, pdf, wi, flags)) if f != black() && pdf > 0.0 => {
+                            (f, pdf, wi, flags)
+                        }
+                        _ => break,
+                    };
+                beta.component_mul_assign(&(f * wi.dot(&ns).abs() / pdf));
+                let specular = flags & BXDF_SPECULAR != 0;
+                if specular {
+                    pdf_fwd = 0.0;
+                    pdf_rev = 0.0;
+                } else {
+                    pdf_fwd = pdf;
+                    pdf_rev = bsdf.pdf(&wi, &wo, BXDF_ALL);
+                }
+                beta *= bsdf.transport_scale(&wo, flags, mode);
+                beta *= correct_shading_normal(inter, &wo, &wi, mode);
+                ray = inter.spawn_ray(wi);
+                path.last_mut().unwrap().delta = specular;
+            }
+
+            // Now that the direction out of the newest vertex is known, fill in the density
+            // of sampling the previous vertex in reverse.
+            let n = path.len();
+            let pdf_rev = path[n - 1].convert_density(ctx.scene, pdf_rev, &path[n - 2]);
+            path[n - 2].pdf_rev = pdf_rev;
+        }
+    }
+
+    fn generate_camera_subpath(&self, ctx: &PathContext, ray: &Ray, rng: &RngGen) -> Vec<Vertex> {
+        let mut path = Vec::with_capacity(self.max_depth + 2);
+        let (_, pdf_dir) = ctx.camera.pdf_we(ray);
+        let camera_inter = Interaction::new_general(ray.origin, ray.time);
+        let beta = color3(1.0, 1.0, 1.0);
+        path.push(Vertex::new(VertexType::Camera, camera_inter, beta));
+        self.random_walk(
+            ctx,
+            ray.clone(),
+            beta,
+            pdf_dir,
+            self.max_depth + 1,
+            TransportMode::Radiance,
+            &mut path,
+            rng,
+        );
+        path
+    }
+
+    fn generate_light_subpath(&self, ctx: &PathContext, time: F, rng: &RngGen) -> Vec<Vertex> {
+        let mut path = Vec::with_capacity(self.max_depth + 1);
+        let scene = ctx.scene;
+        if scene.lights.is_empty() {
+            return path;
+        }
+        let light_pdf = 1.0 / scene.lights.len() as F;
+        let light_idx =
+            ((rng.sample_0_1() * scene.lights.len() as F) as S).min(scene.lights.len() - 1);
+        let light = &scene.lights[light_idx];
+        let le = match light.sample_le(
+            rng.uniform_sample_point2(),
+            rng.uniform_sample_point2(),
+            time,
+        ) {
+            Some(le) if le.pdf_pos > 0.0 && le.pdf_dir > 0.0 && le.col != black() => le,
+            _ => return path,
+        };
+
+        let mut light_inter = Interaction::new_general(le.ray.origin, time);
+        light_inter.n = Some(le.n_light);
+        path.push(Vertex::new_light(
+            light_inter,
+            Some(light_idx),
+            le.col,
+            le.pdf_pos * light_pdf,
+        ));
+        let beta = le.col * le.n_light.dot(&le.ray.direction).abs()
+            / (light_pdf * le.pdf_pos * le.pdf_dir);
+        self.random_walk(
+            ctx,
+            le.ray.clone(),
+            beta,
+            le.pdf_dir,
+            self.max_depth,
+            TransportMode::Importance,
+            &mut path,
+            rng,
+        );
+
+        // Infinite lights sample their origin on a disk rather than on the light itself, so
+        // the densities of the first two vertices are expressed differently.
+        if light.is_infinite() {
+            if path.len() > 1 {
+                path[1].pdf_fwd = le.pdf_pos;
+                if let Some(ng) = path[1].ng() {
+                    path[1].pdf_fwd *= ng.dot(&le.ray.direction).abs();
+                }
+            }
+            path[0].pdf_fwd = infinite_light_density(scene, &le.ray.direction);
+        }
+        path
+    }
+
+    /// Computes the multiple importance sampling weight of the path made of the first `s`
+    /// light vertices and the first `t` camera vertices, using the power heuristic with an
+    /// exponent of 2. `sampled` replaces the endpoint that was resampled for `s = 1` or `t = 1`.
+    fn mis_weight(
+        &self,
+        ctx: &PathContext,
+        light_vertices: &[Vertex],
+        camera_vertices: &[Vertex],
+        sampled: Option<&Vertex>,
+        s: S,
+        t: S,
+    ) -> F {
+        if s + t == 2 {
+            return 1.0;
+        }
+        let light_v = |i: S| match sampled {
+            Some(sampled) if s == 1 && i == 0 => sampled,
+            _ => &light_vertices[i],
+        };
+        let camera_v = |i: S| match sampled {
+            Some(sampled) if t == 1 && i == 0 => sampled,
+            _ => &camera_vertices[i],
+        };
+        // Work on copies of the densities so the subpaths can be reused by other strategies.
+        let mut light_pdfs: Vec<(F, F, bool)> = (0..s)
+            .map(|i| (light_v(i).pdf_fwd, light_v(i).pdf_rev, light_v(i).delta))
+            .collect();
+        let mut camera_pdfs: Vec<(F, F, bool)> = (0..t)
+            .map(|i| (camera_v(i).pdf_fwd, camera_v(i).pdf_rev, camera_v(i).delta))
+            .collect();
+
+        let pt = camera_v(t - 1);
+        let pt_minus = if t > 1 { Some(camera_v(t - 2)) } else { None };
+        let qs = if s > 0 { Some(light_v(s - 1)) } else { None };
+        let qs_minus = if s > 1 { Some(light_v(s - 2)) } else { None };
+
+        // The connection vertices can always be connected to.
+        camera_pdfs[t - 1].2 = false;
+        if s > 0 {
+            light_pdfs[s - 1].2 = false;
+        }
+        camera_pdfs[t - 1].1 = match qs {
+            Some(qs) => qs.pdf(ctx, qs_minus, pt),
+            None => pt.pdf_light_origin(ctx, pt_minus.unwrap()),
+        };
+        if let Some(pt_minus) = pt_minus {
+            camera_pdfs[t - 2].1 = match qs {
+                Some(qs) => pt.pdf(ctx, Some(qs), pt_minus),
+                None => pt.pdf_light(ctx, pt_minus),
+            };
+        }
+        if let Some(qs) = qs {
+            light_pdfs[s - 1].1 = pt.pdf(ctx, pt_minus, qs);
+            if let Some(qs_minus) = qs_minus {
+                light_pdfs[s - 2].1 = qs.pdf(ctx, Some(pt), qs_minus);
+            }
+        }
+
+        // Sum the ratios of each alternative strategy's density to this one's.
+        let mut sum_ri = 0.0;
+        let mut ri = 1.0;
+        for i in (1..t).rev() {
+            ri *= remap0(camera_pdfs[i].1) / remap0(camera_pdfs[i].0);
+            if !camera_pdfs[i].2 && !camera_pdfs[i - 1].2 {
+                sum_ri += ri;
+            }
+        }
+        ri = 1.0;
+        for i in (0..s).rev() {
+            ri *= remap0(light_pdfs[i].1) / remap0(light_pdfs[i].0);
+            let delta_light_vertex = if i > 0 {
+                light_pdfs[i - 1].2
+            } else {
+                light_v(0).is_delta_light(ctx.scene)
+            };
+            if !light_pdfs[i].2 && !delta_light_vertex {
+                sum_ri += ri;
+            }
+        }
+        1.0 / (1.0 + sum_ri)
+    }
+
+    /// Connects the first `s` light vertices to the first `t` camera vertices. For `t = 1` the
+    /// contribution belongs to the returned raster position rather than the current pixel.
+    fn connect(
+        &self,
+        ctx: &PathContext,
+        light_vertices: &[Vertex],
+        camera_vertices: &[Vertex],
+        s: S,
+        t: S,
+        rng: &RngGen,
+    ) -> (Color3, Option<Point2>) {
+        let scene = ctx.scene;
+        // Light vertices on the camera subpath can only be used as they are.
+        if t > 1 && s != 0 && camera_vertices[t - 1].kind == VertexType::Light {
+            return (black(), None);
+        }
+
+        let mut l = black();
+        let mut sampled = None;
+        let mut p_raster = None;
+        if s == 0 {
+            let pt = &camera_vertices[t - 1];
+            l = pt
+                .le(scene, &camera_vertices[t - 2])
+                .component_mul(&pt.beta);
+        } else if t == 1 {
+            let qs = &light_vertices[s - 1];
+            if qs.is_connectible() {
+                if let Some(wi) = ctx
+                    .camera
+                    .sample_wi(&qs.inter, &rng.uniform_sample_point2())
+                {
+                    if wi.pdf > 0.0 && wi.we > 0.0 {
+                        let we = wi.we / wi.pdf;
+                        let camera = Vertex::new(
+                            VertexType::Camera,
+                            (*wi.vis.p1).clone(),
+                            color3(we, we, we),
+                        );
+                        l = qs
+                            .beta
+                            .component_mul(&qs.f(&camera, TransportMode::Importance))
+                            .component_mul(&camera.beta);
+                        if qs.is_on_surface() {
+                            l *= wi.wi.dot(&qs.ns().unwrap()).abs();
+                        }
+                        if l != black() {
+                            l.component_mul_assign(&wi.vis.transmittance(scene, rng));
+                        }
+                        sampled = Some(camera);
+                        p_raster = Some(wi.p_raster);
+                    }
+                }
+            }
+        } else if s == 1 {
+            let pt = &camera_vertices[t - 1];
+            if pt.is_connectible() {
+                let light_pdf = 1.0 / scene.lights.len() as F;
+                let light_idx =
+                    ((rng.sample_0_1() * scene.lights.len() as F) as S).min(scene.lights.len() - 1);
+                let light = &scene.lights[light_idx];
+                if let Some(li) =
+                    light.sample_li(Arc::new(pt.inter.clone()), rng.uniform_sample_point2())
+                {
+                    if li.pdf > 0.0 && li.col != black() {
+                        let mut vertex = Vertex::new_light(
+                            (*li.vis.p1).clone(),
+                            Some(light_idx),
+                            li.col / (li.pdf * light_pdf),
+                            0.0,
+                        );
+                        vertex.pdf_fwd = vertex.pdf_light_origin(ctx, pt);
+                        l = pt
+                            .beta
+                            .component_mul(&pt.f(&vertex, TransportMode::Radiance))
+                            .component_mul(&vertex.beta);
+                        if pt.is_on_surface() {
+                            l *= li.wi.dot(&pt.ns().unwrap()).abs();
+                        }
+                        if l != black() {
+                            l.component_mul_assign(&li.vis.transmittance(scene, rng));
+                        }
+                        sampled = Some(vertex);
+                    }
+                }
+            }
+        } else {
+            let qs = &light_vertices[s - 1];
+            let pt = &camera_vertices[t - 1];
+            if qs.is_connectible() && pt.is_connectible() {
+                l = qs
+                    .beta
+                    .component_mul(&qs.f(pt, TransportMode::Importance))
+                    .component_mul(&pt.f(qs, TransportMode::Radiance))
+                    .component_mul(&pt.beta);
+                if l != black() {
+                    l.component_mul_assign(&g(scene, qs, pt, rng));
+                }
+            }
+        }
+
+        if l == black() {
+            return (black(), None);
+        }
+        let weight = self.mis_weight(ctx, light_vertices, camera_vertices, sampled.as_ref(), s, t);
+        (l * weight, p_raster)
+    }
+}
+
+impl Integrator for BdptIntegrator {
+    fn preprocess(&mut self, scene: &Scene, cam: &SimpleCamera) {
+        self.camera = Some(cam.clone());
+        self.world_radius = scene.world_bounds().bounding_sphere().1;
+        self.splats = Some(Film::new(cam.pixel_bounds(), cam.filter.clone()));
+    }
+
+    fn splats(&self) -> Option<&Film> {
+        self.splats.as_ref()
+    }
+
+    fn li(&self, ray: &mut Ray, scene: &Scene, depth: S, rng: &RngGen) -> Color3 {
+        let (out_color, splats) = self.li_with_splats(ray, scene, rng);
+        if let Some(buffer) = self.splats.as_ref() {
+            for (p_raster, l) in splats {
+                buffer.add_splat(&p_raster, l);
+            }
+        }
+        out_color
+    }
+
+    fn li_with_splats(
+        &self,
+        ray: &mut Ray,
+        scene: &Scene,
+        rng: &RngGen,
+    ) -> (Color3, Vec<(Point2, Color3)>) {
+        let ctx = PathContext {
+            scene,
+            camera: self
+                .camera
+                .as_ref()
+                .expect("BdptIntegrator used before preprocess()"),
+            world_radius: self.world_radius,
+        };
+        let camera_vertices = self.generate_camera_subpath(&ctx, ray, rng);
+        rng.start_stream(LIGHT_STREAM);
+        let light_vertices = self.generate_light_subpath(&ctx, ray.time, rng);
+        rng.start_stream(CONNECTION_STREAM);
+
+        let mut out_color = black();
+        let mut splats = vec![];
+        for t in 1..=camera_vertices.len() {
+            for s in 0..=light_vertices.len() {
+                let depth = t as I + s as I - 2;
+                if (s == 1 && t == 1) || depth < 0 || depth > self.max_depth as I {
+                    continue;
+                }
+                let (l, p_raster) =
+                    self.connect(&ctx, &light_vertices, &camera_vertices, s, t, rng);
+                if t == 1 {
+                    if let Some(p_raster) = p_raster {
+                        if l != black() {
+                            splats.push((p_raster, l));
+                        }
+                    }
+                } else {
+                    out_color += l;
+                }
+            }
+        }
+        (out_color, splats)
+    }
+}
@@ -0,0 +1,481 @@
+use std::sync::Arc;
+
+use rayon::prelude::*;
+
+use crate::{
+    color::{black, color3, Color3},
+    common::*,
+    interaction::Interaction,
+    interpolation::{
+        catmull_rom_weights, integrate_catmull_rom, invert_catmull_rom, sample_catmull_rom_2d,
+    },
+    material::{fr_dielectric, BXDFType, Bxdf, BXDF_DIFFUSE, BXDF_REFLECTION},
+    media::phase_hg,
+    ray::Ray,
+    scene::Scene,
+    vector::*,
+};
+
+/// First moment of the Fresnel reflectance of a dielectric over the hemisphere.
+pub fn fresnel_moment1(eta: F) -> F {
+    let (eta2, eta3) = (eta * eta, eta * eta * eta);
+    let (eta4, eta5) = (eta3 * eta, eta3 * eta2);
+    if eta < 1.0 {
+        0.45966 - 1.73965 * eta + 3.37668 * eta2 - 3.904_945 * eta3 + 2.49277 * eta4
+            - 0.68441 * eta5
+    } else {
+        -4.61686 + 11.1136 * eta - 10.4646 * eta2 + 5.11455 * eta3 - 1.27198 * eta4 + 0.12746 * eta5
+    }
+}
+
+/// Second moment of the Fresnel reflectance of a dielectric over the hemisphere.
+pub fn fresnel_moment2(eta: F) -> F {
+    let (eta2, eta3) = (eta * eta, eta * eta * eta);
+    let (eta4, eta5) = (eta3 * eta, eta3 * eta2);
+    if eta < 1.0 {
+        0.27614 - 0.87350 * eta + 1.12077 * eta2 - 0.65095 * eta3 + 0.07883 * eta4 + 0.04860 * eta5
+    } else {
+        let r_eta = 1.0 / eta;
+        let (r_eta2, r_eta3) = (r_eta * r_eta, r_eta * r_eta * r_eta);
+        -547.033 + 45.3087 * r_eta3 - 218.725 * r_eta2 + 458.843 * r_eta + 404.557 * eta
+            - 189.519 * eta2
+            + 54.9327 * eta3
+            - 9.00603 * eta4
+            + 0.63942 * eta5
+    }
+}
+
+/// Radiant exitance at distance `r` from where a beam enters a medium, due to light scattered
+/// more than once, by photon beam diffusion (Habel et al.) with unit extinction.
+fn beam_diffusion_ms(sigma_s: F, sigma_a: F, g: F, eta: F, r: F) -> F {
+    const N_SAMPLES: S = 100;
+    // Reduced scattering coefficients and the diffusion coefficient.
+    let sigmap_s = sigma_s * (1.0 - g);
+    let sigmap_t = sigma_a + sigmap_s;
+    let rhop = sigmap_s / sigmap_t;
+    let d_g = (2.0 * sigma_a + sigmap_s) / (3.0 * sigmap_t * sigmap_t);
+    let sigma_tr = F::sqrt(sigma_a / d_g);
+    // Extrapolation distance of the linear boundary condition.
+    let fm1 = fresnel_moment1(eta);
+    let fm2 = fresnel_moment2(eta);
+    let ze = -2.0 * d_g * (1.0 + 3.0 * fm2) / (1.0 - 2.0 * fm1);
+    let c_phi = 0.25 * (1.0 - 2.0 * fm1);
+    let c_e = 0.5 * (1.0 - 3.0 * fm2);
+
+    let mut ed = 0.0;
+    for i in 0..N_SAMPLES {
+        // Point sources along the beam, as real and mirrored virtual pairs.
+        let zr = -F::ln(1.0 - (i as F + 0.5) / N_SAMPLES as F) / sigmap_t;
+        let zv = -zr + 2.0 * ze;
+        let dr = F::sqrt(r * r + zr * zr);
+        let dv = F::sqrt(r * r + zv * zv);
+        let phi_d =
+            1.0 / (4.0 * PI) / d_g * (F::exp(-sigma_tr * dr) / dr - F::exp(-sigma_tr * dv) / dv);
+        let ed_n = 1.0 / (4.0 * PI)
+            * (zr * (1.0 + sigma_tr * dr) * F::exp(-sigma_tr * dr) / (dr * dr * dr)
+                - zv * (1.0 + sigma_tr * dv) * F::exp(-sigma_tr * dv) / (dv * dv * dv));
+        let e = phi_d * c_phi + ed_n * c_e;
+        let kappa = 1.0 - F::exp(-2.0 * sigmap_t * (dr + zr));
+        ed += kappa * rhop * rhop * e;
+    }
+    ed / N_SAMPLES as F
+}
+
+/// Radiant exitance at distance `r` from where a beam enters a medium, due to light scattered
+/// exactly once, with unit extinction.
+fn beam_diffusion_ss(sigma_s: F, sigma_a: F, g: F, eta: F, r: F) -> F {
+    const N_SAMPLES: S = 100;
+    let sigma_t = sigma_a + sigma_s;
+    let rho = sigma_s / sigma_t;
+    // Closer than this along the beam, light can't leave through the boundary.
+    let t_crit = r * F::sqrt(eta * eta - 1.0);
+    let mut ess = 0.0;
+    for i in 0..N_SAMPLES {
+        let ti = t_crit - F::ln(1.0 - (i as F + 0.5) / N_SAMPLES as F) / sigma_t;
+        let d = F::sqrt(r * r + ti * ti);
+        let cos_theta_o = ti / d;
+        ess += rho * F::exp(-sigma_t * (d + t_crit)) / (d * d)
+            * phase_hg(cos_theta_o, g)
+            * (1.0 - fr_dielectric(-cos_theta_o, 1.0, eta))
+            * cos_theta_o.abs();
+    }
+    ess / N_SAMPLES as F
+}
+
+/// The radial scattering profile of a medium with unit extinction, tabulated over single
+/// scattering albedo and optical radius. Media with other extinction coefficients are
+/// handled by scaling the radius.
+pub struct BssrdfTable {
+    rho_samples: Vec<F>,
+    radius_samples: Vec<F>,
+    /// `2πr` times the profile, per albedo and then radius.
+    profile: Vec<F>,
+    /// Running integrals of `profile` over radius.
+    profile_cdf: Vec<F>,
+    /// Total reflectance for each albedo.
+    rho_eff: Vec<F>,
+}
+
+impl BssrdfTable {
+    /// Tabulates the photon beam diffusion profile for a medium with Henyey-Greenstein
+    /// asymmetry `g` behind a boundary with relative index of refraction `eta`.
+    pub fn new_beam_diffusion(g: F, eta: F) -> Self {
+        const N_RHO_SAMPLES: S = 100;
+        const N_RADIUS_SAMPLES: S = 64;
+        // Radii grow exponentially and albedos crowd towards one, where the profile changes
+        // the most.
+        let mut radius_samples = vec![0.0, 2.5e-3];
+        for i in 2..N_RADIUS_SAMPLES {
+            radius_samples.push(radius_samples[i - 1] * 1.2);
+        }
+        let rho_samples: Vec<F> = (0..N_RHO_SAMPLES)
+            .map(|i| {
+                (1.0 - F::exp(-8.0 * i as F / (N_RHO_SAMPLES - 1) as F)) / (1.0 - F::exp(-8.0))
+            })
+            .collect();
+
+        let rows: Vec<(Vec<F>, Vec<F>, F)> = rho_samples
+            .par_iter()
+            .map(|&rho| {
+                let profile: Vec<F> = radius_samples
+                    .iter()
+                    .map(|&r| {
+                        2.0 * PI
+                            * r
+                            * (beam_diffusion_ss(rho, 1.0 - rho, g, eta, r)
+                                + beam_diffusion_ms(rho, 1.0 - rho, g, eta, r))
+                    })
+                    .collect();
+                let mut cdf = vec![0.0; N_RADIUS_SAMPLES];
+                let rho_eff = integrate_catmull_rom(&radius_samples, &profile, &mut cdf);
+                (profile, cdf, rho_eff)
+            })
+            .collect();
+
+        let mut table = Self {
+            rho_samples,
+            radius_samples,
+            profile: vec![],
+            profile_cdf: vec![],
+            rho_eff: vec![],
+        };
+        for (profile, cdf, rho_eff) in rows {
+            table.profile.extend(profile);
+            table.profile_cdf.extend(cdf);
+            table.rho_eff.push(rho_eff);
+        }
+        table
+    }
+
+    /// Finds the absorption and scattering coefficients that give a medium with mean free
+    /// path `mfp` the total diffuse reflectance `rho_eff`.
+    pub fn subsurface_from_diffuse(&self, rho_eff: &Color3, mfp: &Color3) -> (Color3, Color3) {
+        let mut sigma_a = black();
+        let mut sigma_s = black();
+        for c in 0..3 {
+            let rho = invert_catmull_rom(&self.rho_samples, &self.rho_eff, rho_eff[c]);
+            sigma_s[c] = rho / mfp[c];
+            sigma_a[c] = (1.0 - rho) / mfp[c];
+        }
+        (sigma_a, sigma_s)
+    }
+}
+
+/// Measured scattering properties `(name, sigma'_s, sigma_a)` in inverse millimeters, from
+/// Jensen et al., "A Practical Model for Subsurface Light Transport".
+const MEASURED_MEDIA: [(&str, [F; 3], [F; 3]); 11] = [
+    ("apple", [2.29, 2.39, 1.97], [0.0030, 0.0034, 0.046]),
+    ("chicken", [0.15, 0.21, 0.38], [0.015, 0.077, 0.19]),
+    ("cream", [7.38, 5.47, 3.15], [0.0002, 0.0028, 0.0163]),
+    ("ketchup", [0.18, 0.07, 0.03], [0.061, 0.97, 1.45]),
+    ("marble", [2.19, 2.62, 3.00], [0.0021, 0.0041, 0.0071]),
+    ("potato", [0.68, 0.70, 0.55], [0.0024, 0.0090, 0.12]),
+    ("skimmilk", [0.70, 1.22, 1.90], [0.0014, 0.0025, 0.0142]),
+    ("skin1", [0.74, 0.88, 1.01], [0.032, 0.17, 0.48]),
+    ("skin2", [1.09, 1.59, 1.79], [0.013, 0.070, 0.145]),
+    ("spectralon", [11.6, 20.4, 14.9], [0.00, 0.00, 0.00]),
+    ("wholemilk", [2.55, 3.21, 3.77], [0.0011, 0.0024, 0.014]),
+];
+
+/// Scattering and absorption coefficients, in inverse millimeters, of a measured material
+/// such as `skin1` or `marble`. These assume isotropic scattering.
+pub fn measured_medium(name: &str) -> Option<(Color3, Color3)> {
+    let name = name.to_lowercase();
+    let (_, sigma_s, sigma_a) = MEASURED_MEDIA.iter().find(|(n, _, _)| *n == name)?;
+    Some((
+        color3(sigma_s[0], sigma_s[1], sigma_s[2]),
+        color3(sigma_a[0], sigma_a[1], sigma_a[2]),
+    ))
+}
+
+/// Light entering a translucent object at one point and leaving at another, assumed to
+/// factor into a spatial profile depending only on the distance between the points and a
+/// directional term at the exit (a separable BSSRDF), with the profile looked up in a
+/// `BssrdfTable`.
+pub struct TabulatedBssrdf {
+    /// Where light leaves towards the viewer, and the shading frame there.
+    po: Point3,
+    wo: Vec3,
+    time: F,
+    ns: Normal3,
+    ss: Vec3,
+    ts: Vec3,
+    eta: F,
+    sigma_t: Color3,
+    rho: Color3,
+    table: Arc<BssrdfTable>,
+}
+
+impl TabulatedBssrdf {
+    /// A BSSRDF for light leaving at `po`, whose BSDF must already be set.
+    pub fn new(
+        po: &Interaction,
+        eta: F,
+        sigma_a: &Color3,
+        sigma_s: &Color3,
+        table: Arc<BssrdfTable>,
+    ) -> Self {
+        let bsdf = po.bsdf.as_ref().unwrap();
+        let sigma_t = sigma_a + sigma_s;
+        let rho = Color3::from_fn(|c, _| {
+            if sigma_t[c] != 0.0 {
+                sigma_s[c] / sigma_t[c]
+            } else {
+                0.0
+            }
+        });
+        Self {
+            po: po.p,
+            wo: po.wo.unwrap_or_default(),
+            time: po.time,
+            ns: bsdf.local_to_world(&vec3(0.0, 0.0, 1.0)),
+            ss: bsdf.local_to_world(&vec3(1.0, 0.0, 0.0)),
+            ts: bsdf.local_to_world(&vec3(0.0, 1.0, 0.0)),
+            eta,
+            sigma_t,
+            rho,
+            table,
+        }
+    }
+
+    /// The radial profile at distance `r`.
+    fn sr(&self, r: F) -> Color3 {
+        let table = &self.table;
+        let n_radius = table.radius_samples.len();
+        let mut sr = black();
+        for c in 0..3 {
+            // Look up the unit-extinction profile at the equivalent optical radius.
+            let r_optical = r * self.sigma_t[c];
+            let (rho_offset, rho_weights) =
+                match catmull_rom_weights(&table.rho_samples, self.rho[c]) {
+                    Some(w) => w,
+                    None => continue,
+                };
+            let (radius_offset, radius_weights) =
+                match catmull_rom_weights(&table.radius_samples, r_optical) {
+                    Some(w) => w,
+                    None => continue,
+                };
+            let mut value = 0.0;
+            for (i, rho_weight) in rho_weights.iter().enumerate() {
+                for (j, radius_weight) in radius_weights.iter().enumerate() {
+                    let weight = rho_weight * radius_weight;
+                    if weight != 0.0 {
+                        let index =
+                            (rho_offset + i as I) as S * n_radius + (radius_offset + j as I) as S;
+                        value += weight * table.profile[index];
+                    }
+                }
+            }
+            // Undo the 2πr the table was multiplied by.
+            if r_optical != 0.0 {
+                value /= 2.0 * PI * r_optical;
+            }
+            sr[c] = F::max(0.0, value * self.sigma_t[c] * self.sigma_t[c]);
+        }
+        sr
+    }
+
+    /// Samples a radius for color channel `c`, or `None` if light doesn't scatter there.
+    fn sample_sr(&self, c: S, u: F) -> Option<F> {
+        if self.sigma_t[c] == 0.0 {
+            return None;
+        }
+        let table = &self.table;
+        let (r, _, _) = sample_catmull_rom_2d(
+            &table.rho_samples,
+            &table.radius_samples,
+            &table.profile,
+            &table.profile_cdf,
+            self.rho[c],
+            u,
+        )?;
+        Some(r / self.sigma_t[c])
+    }
+
+    fn pdf_sr(&self, c: S, r: F) -> F {
+        let table = &self.table;
+        let n_radius = table.radius_samples.len();
+        let r_optical = r * self.sigma_t[c];
+        let (rho_offset, rho_weights) = match catmull_rom_weights(&table.rho_samples, self.rho[c]) {
+            Some(w) => w,
+            None => return 0.0,
+        };
+        let (radius_offset, radius_weights) =
+            match catmull_rom_weights(&table.radius_samples, r_optical) {
+                Some(w) => w,
+                None => return 0.0,
+            };
+        let mut sr = 0.0;
+        let mut rho_eff = 0.0;
+        for (i, rho_weight) in rho_weights.iter().enumerate() {
+            if *rho_weight == 0.0 {
+                continue;
+            }
+            let row = (rho_offset + i as I) as S;
+            rho_eff += table.rho_eff[row] * rho_weight;
+            for (j, radius_weight) in radius_weights.iter().enumerate() {
+                if *radius_weight != 0.0 {
+                    let index = row * n_radius + (radius_offset + j as I) as S;
+                    sr += table.profile[index] * rho_weight * radius_weight;
+                }
+            }
+        }
+        if r_optical != 0.0 {
+            sr /= 2.0 * PI * r_optical;
+        }
+        F::max(0.0, sr * self.sigma_t[c] * self.sigma_t[c] / rho_eff)
+    }
+
+    /// Density of sampling exit point `pi` with normal `ni` through `sample_sp`, over all the
+    /// axes and channels that could have produced it.
+    fn pdf_sp(&self, pi: &Point3, ni: &Normal3) -> F {
+        let d = pi - self.po;
+        let d_local = vec3(self.ss.dot(&d), self.ts.dot(&d), self.ns.dot(&d));
+        let n_local = vec3(self.ss.dot(ni), self.ts.dot(ni), self.ns.dot(ni));
+        // Distance from `po` to `pi` projected along each axis.
+        let r_proj = [
+            F::sqrt(d_local.y * d_local.y + d_local.z * d_local.z),
+            F::sqrt(d_local.z * d_local.z + d_local.x * d_local.x),
+            F::sqrt(d_local.x * d_local.x + d_local.y * d_local.y),
+        ];
+        let axis_prob = [0.25, 0.25, 0.5];
+        let mut pdf = 0.0;
+        for axis in 0..3 {
+            for c in 0..3 {
+                pdf += self.pdf_sr(c, r_proj[axis]) * n_local[axis].abs() * axis_prob[axis] / 3.0;
+            }
+        }
+        pdf
+    }
+
+    /// Samples a point where light that leaves at `po` could have entered, by sampling a
+    /// radius from the profile around one of the shading frame's axes and finding where a
+    /// probe ray through the sphere of that radius crosses the same primitive. Returns the
+    /// entry point, with a BSDF for the directional part of the BSSRDF, the spatial profile
+    /// there and the pdf.
+    pub fn sample_s(
+        &self,
+        po: &Interaction,
+        scene: &Scene,
+        u1: F,
+        u2: &Point2,
+    ) -> Option<(Interaction, Color3, F)> {
+        // Mostly probe along the normal, but sometimes along the tangents, which finds the
+        // surface better where it's strongly curved.
+        let (vx, vy, vz, u1) = if u1 < 0.5 {
+            (self.ss, self.ts, self.ns, u1 * 2.0)
+        } else if u1 < 0.75 {
+            (self.ts, self.ns, self.ss, (u1 - 0.5) * 4.0)
+        } else {
+            (self.ns, self.ss, self.ts, (u1 - 0.75) * 4.0)
+        };
+        let c = ((u1 * 3.0) as S).min(2);
+
+        let r = self.sample_sr(c, u2.x)?;
+        let r_max = self.sample_sr(c, 0.999)?;
+        if r < 0.0 || r >= r_max {
+            return None;
+        }
+        let phi = 2.0 * PI * u2.y;
+        let l = 2.0 * F::sqrt(r_max * r_max - r * r);
+        let base = self.po + r * (vx * phi.cos() + vy * phi.sin()) - l * vz * 0.5;
+        let target = base + l * vz;
+
+        let primitive = po.primitive.as_ref()?;
+        let mut hits = vec![];
+        let mut origin = base;
+        loop {
+            let mut ray =
+                Ray::new_non_differential(origin, target - origin, 0.0, 1.0, self.time, None);
+            let hit = match scene.intersect(&mut ray) {
+                Some(hit) => hit,
+                None => break,
+            };
+            origin = hit.p + (target - hit.p).normalize() * 1e-4;
+            if (target - origin).dot(&vz) <= 0.0 {
+                break;
+            }
+            if hit
+                .primitive
+                .as_ref()
+                .is_some_and(|p| Arc::ptr_eq(&p.shape, &primitive.shape))
+            {
+                hits.push(hit);
+            }
+        }
+        if hits.is_empty() {
+            return None;
+        }
+        let n_found = hits.len();
+        let selected = ((u1 * 3.0 - c as F) * n_found as F) as S;
+        let mut pi = hits.swap_remove(selected.min(n_found - 1));
+
+        let ni = pi
+            .shading
+            .as_ref()
+            .map_or(pi.n.unwrap(), |s| s.n)
+            .normalize();
+        let pdf = self.pdf_sp(&pi.p, &ni) / n_found as F;
+        let sp = self.sr(distance3d(&self.po, &pi.p));
+        pi.wo = Some(ni);
+        pi.create_bsdf();
+        pi.add_bxdf(Arc::new(SeparableBssrdfAdapter {
+            eta: self.eta,
+            c: 1.0 - 2.0 * fresnel_moment1(1.0 / self.eta),
+        }));
+        pi.bssrdf = None;
+        Some((pi, sp, pdf))
+    }
+}
+
+/// The directional part of a separable BSSRDF where light enters, acting as a BxDF so the
+/// usual light sampling and BSDF sampling apply there.
+struct SeparableBssrdfAdapter {
+    eta: F,
+    /// Normalization making the directional term integrate to one over the hemisphere.
+    c: F,
+}
+
+impl Bxdf for SeparableBssrdfAdapter {
+    fn bxdf_type(&self) -> BXDFType {
+        BXDF_REFLECTION | BXDF_DIFFUSE
+    }
+    fn rho_2samples(
+        &self,
+        n_samples: S,
+        samples1: &[Point2],
+        samples2: &[Point2],
+    ) -> Option<Color3> {
+        None
+    }
+    fn rho(&self, n_samples: S, wo: &Vec3, samples: &[Point2]) -> Option<Color3> {
+        None
+    }
+    fn f(&self, wo: &Vec3, wi: &Vec3) -> Option<Color3> {
+        let sw = (1.0 - fr_dielectric(wi.z, 1.0, self.eta)) / (self.c * PI);
+        // Radiance is compressed as it refracts into the object.
+        let f = sw * self.eta * self.eta;
+        Some(color3(f, f, f))
+    }
+}
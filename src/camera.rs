@@ -1,29 +1,211 @@
+use std::sync::Arc;
+
+use crate::common::*;
+use crate::film::{BoxFilter, Filter, PixelBounds};
+use crate::interaction::Interaction;
+use crate::light::VisibilityTester;
+use crate::media::{Medium, MediumInterface};
 use crate::ray::Ray;
 use crate::transform::Transform;
 use crate::vector::*;
-use crate::{common::*, ASPECT_RATIO, HEIGHT, WIDTH};
 
+/// The result of sampling a direction from a point in the scene towards the camera.
+pub struct WiResult {
+    pub we: F,
+    pub wi: Vec3,
+    pub pdf: F,
+    pub vis: VisibilityTester,
+    pub p_raster: Point2,
+}
+
+#[derive(Clone)]
 pub struct SimpleCamera {
     pub fov: F,
     // pub origin: Point3,
     lookat: Transform,
+    target: Point3,
+    /// The medium the camera sits in, e.g. when rendering from inside a fog volume.
+    pub medium: Option<Arc<dyn Medium + Send + Sync>>,
+    /// Reconstructs the image from its samples. Defaults to a box over each pixel.
+    pub filter: Arc<dyn Filter + Send + Sync>,
+    /// Resolution of the full image.
+    pub width: S,
+    pub height: S,
+    /// The width of a pixel over its height, for output to devices with non-square pixels.
+    pub pixel_aspect: F,
+    /// The part of the image to render, as `(x_min, x_max, y_min, y_max)` fractions of its
+    /// width and height from the top left. Defaults to all of it.
+    pub crop_window: (F, F, F, F),
 }
 
 impl SimpleCamera {
-    pub fn new(origin: Point3, lookat: Point3, fov: F) -> Self {
+    /// A camera at `origin` looking towards `lookat`, with a vertical field of view of `fov`
+    /// degrees, rendering a `width` by `height` image.
+    pub fn new(origin: Point3, lookat: Point3, fov: F, width: S, height: S) -> Self {
         Self {
             fov,
             lookat: Transform::new_lookat(origin, lookat, vec3(0.0, 1.0, 0.0)),
+            target: lookat,
+            medium: None,
+            filter: Arc::new(BoxFilter::new(vec2(0.5, 0.5))),
+            width,
+            height,
+            pixel_aspect: 1.0,
+            crop_window: (0.0, 1.0, 0.0, 1.0),
         }
     }
 
+    /// Moves the camera to `origin`, looking towards `target`, keeping it upright.
+    pub fn look_at(&mut self, origin: Point3, target: Point3) {
+        self.lookat = Transform::new_lookat(origin, target, vec3(0.0, 1.0, 0.0));
+        self.target = target;
+    }
+
+    /// The point the camera looks towards.
+    pub fn target(&self) -> Point3 {
+        self.target
+    }
+
+    /// Width over height of the image as displayed.
+    pub fn aspect_ratio(&self) -> F {
+        self.width as F * self.pixel_aspect / self.height as F
+    }
+
+    /// The pixels inside the crop window, which are the ones rendered.
+    pub fn pixel_bounds(&self) -> PixelBounds {
+        let (x_min, x_max, y_min, y_max) = self.crop_window;
+        let to_pixel = |t: F, n: S| ((t.clamp(0.0, 1.0) * n as F).ceil() as S).min(n);
+        (
+            to_pixel(x_min, self.width),
+            to_pixel(y_min, self.height),
+            to_pixel(x_max, self.width),
+            to_pixel(y_max, self.height),
+        )
+    }
+
+    /// Width and height of the rendered image, i.e. of the crop window.
+    pub fn image_size(&self) -> (S, S) {
+        let (x0, y0, x1, y1) = self.pixel_bounds();
+        (x1.saturating_sub(x0), y1.saturating_sub(y0))
+    }
+
     pub fn get_ray(&self, xy: Point2) -> Ray {
         let angle = deg2rad(self.fov / 2.0).tan();
-        let xx = (2.0 * ((xy.x + 0.5) * (1.0 / WIDTH as F)) - 1.0) * angle * ASPECT_RATIO;
-        let yy = (1.0 - 2.0 * ((xy.y + 0.5) * (1.0 / HEIGHT as F))) * angle;
+        let xx =
+            (2.0 * ((xy.x + 0.5) * (1.0 / self.width as F)) - 1.0) * angle * self.aspect_ratio();
+        let yy = (1.0 - 2.0 * ((xy.y + 0.5) * (1.0 / self.height as F))) * angle;
         let direction = vec3(xx, yy, -1.0).normalize();
-        let ray =
-            Ray::new_non_differential(point3(0.0, 0.0, 0.0), direction, 0.0001, F::INFINITY, 0.0);
+        let ray = Ray::new_non_differential(
+            point3(0.0, 0.0, 0.0),
+            direction,
+            0.0001,
+            F::INFINITY,
+            0.0,
+            self.medium.clone(),
+        );
         self.lookat.iray(&ray)
     }
+
+    pub fn position(&self) -> Point3 {
+        self.lookat.ipt(point3(0.0, 0.0, 0.0))
+    }
+
+    pub fn forward(&self) -> Vec3 {
+        self.lookat.ivec(&vec3(0.0, 0.0, -1.0)).normalize()
+    }
+
+    /// Area of the image plane at distance 1 from the camera.
+    fn film_area(&self) -> F {
+        let angle = deg2rad(self.fov / 2.0).tan();
+        (2.0 * angle * self.aspect_ratio()) * (2.0 * angle)
+    }
+
+    /// Projects a world space point onto the film. The returned position is continuous, with
+    /// pixel `(x, y)` covering `[x, x + 1) x [y, y + 1)`, and is `None` when the point is behind
+    /// the camera or outside the film.
+    pub fn raster_position(&self, p: &Point3) -> Option<Point2> {
+        let p_camera = self.lookat.fpt(*p);
+        if p_camera.z >= 0.0 {
+            return None;
+        }
+        let angle = deg2rad(self.fov / 2.0).tan();
+        let x_ndc = p_camera.x / -p_camera.z / (angle * self.aspect_ratio());
+        let y_ndc = p_camera.y / -p_camera.z / angle;
+        let p_raster = point2(
+            (x_ndc + 1.0) / 2.0 * self.width as F,
+            (1.0 - y_ndc) / 2.0 * self.height as F,
+        );
+        if p_raster.x < 0.0
+            || p_raster.x >= self.width as F
+            || p_raster.y < 0.0
+            || p_raster.y >= self.height as F
+        {
+            return None;
+        }
+        Some(p_raster)
+    }
+
+    /// Evaluates the importance emitted along `ray`, normalized so that it integrates to one
+    /// over the film. Also returns where the ray lands on the film.
+    pub fn we(&self, ray: &Ray) -> Option<(F, Point2)> {
+        let cos_theta = ray.direction.normalize().dot(&self.forward());
+        if cos_theta <= 0.0 {
+            return None;
+        }
+        let p_raster = self.raster_position(&(ray.origin + ray.direction / cos_theta))?;
+        let cos2_theta = cos_theta * cos_theta;
+        Some((1.0 / (self.film_area() * cos2_theta * cos2_theta), p_raster))
+    }
+
+    /// Returns the positional and directional densities of the camera generating `ray`.
+    pub fn pdf_we(&self, ray: &Ray) -> (F, F) {
+        let cos_theta = ray.direction.normalize().dot(&self.forward());
+        if cos_theta <= 0.0 {
+            return (0.0, 0.0);
+        }
+        (
+            1.0,
+            1.0 / (self.film_area() * cos_theta * cos_theta * cos_theta),
+        )
+    }
+
+    /// Samples the direction from `inter` towards the (pinhole) camera, along with the importance
+    /// arriving from it. The pdf is with respect to solid angle at `inter`.
+    pub fn sample_wi(&self, inter: &Interaction, u: &Point2) -> Option<WiResult> {
+        let p_camera = self.position();
+        let mut wi = p_camera - inter.p;
+        let dist = wi.magnitude();
+        if dist == 0.0 {
+            return None;
+        }
+        wi /= dist;
+        let cos_theta = wi.dot(&self.forward()).abs();
+        if cos_theta == 0.0 {
+            return None;
+        }
+        let pdf = dist * dist / cos_theta;
+        let ray = Ray::new_non_differential(
+            p_camera,
+            -wi,
+            0.0,
+            F::INFINITY,
+            inter.time,
+            self.medium.clone(),
+        );
+        let (we, p_raster) = self.we(&ray)?;
+        let camera_inter = Interaction {
+            medium_interface: Some(MediumInterface::new_non_transition(self.medium.clone())),
+            ..Interaction::new_general(p_camera, inter.time)
+        };
+        Some(WiResult {
+            we,
+            wi,
+            pdf,
+            vis: VisibilityTester {
+                p0: Arc::new(inter.clone()),
+                p1: Arc::new(camera_inter),
+            },
+            p_raster,
+        })
+    }
 }
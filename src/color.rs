@@ -1,3 +1,4 @@
+use crate::colorspace::working_space;
 use crate::common::*;
 use crate::vector::*;
 
@@ -10,6 +11,20 @@ pub fn black() -> Color3 {
     color3(0.0, 0.0, 0.0)
 }
 
+/// Luminance (the Y of CIE XYZ) of a color in the working space.
+pub fn luminance(col: &Color3) -> F {
+    working_space().luminance(col)
+}
+
+/// The sRGB transfer function, encoding a linear value in `[0, 1]` for display.
+pub fn linear_to_srgb(v: F) -> F {
+    if v <= 0.003_130_8 {
+        12.92 * v
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
 pub fn color_to_pixel(col: Color3, gamma: F) -> [u8; 4] {
     [
         (col.x.powf(gamma).clamp(0.0, 0.9999) * 255.0) as u8,
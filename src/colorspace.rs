@@ -0,0 +1,165 @@
+use std::sync::OnceLock;
+
+use crate::color::Color3;
+use crate::common::*;
+use crate::matrix::Matrix3;
+use crate::vector::*;
+
+/// An RGB color space, defined by the CIE xy chromaticities of its primaries and white point.
+/// All its colors are linear; transfer functions are applied on output.
+#[derive(Clone, Debug)]
+pub struct ColorSpace {
+    pub name: &'static str,
+    pub r: Point2,
+    pub g: Point2,
+    pub b: Point2,
+    pub white: Point2,
+    /// Converts RGB in this space to CIE XYZ, with white at Y = 1.
+    pub rgb_to_xyz: Matrix3,
+    pub xyz_to_rgb: Matrix3,
+}
+
+const D65: (F, F) = (0.3127, 0.3290);
+
+fn xy_to_xyz(xy: Point2) -> Vec3 {
+    vec3(xy.x / xy.y, 1.0, (1.0 - xy.x - xy.y) / xy.y)
+}
+
+impl ColorSpace {
+    pub fn new(name: &'static str, r: Point2, g: Point2, b: Point2, white: Point2) -> Self {
+        // Scale each primary so that full RGB adds up to the white point.
+        let primaries = Matrix3::from_columns(&[xy_to_xyz(r), xy_to_xyz(g), xy_to_xyz(b)]);
+        let scale = primaries.try_inverse().unwrap() * xy_to_xyz(white);
+        let rgb_to_xyz = primaries * Matrix3::from_diagonal(&scale);
+        Self {
+            name,
+            r,
+            g,
+            b,
+            white,
+            rgb_to_xyz,
+            xyz_to_rgb: rgb_to_xyz.try_inverse().unwrap(),
+        }
+    }
+
+    /// sRGB and Rec.709, which share their primaries.
+    pub fn srgb() -> &'static ColorSpace {
+        static SPACE: OnceLock<ColorSpace> = OnceLock::new();
+        SPACE.get_or_init(|| {
+            ColorSpace::new(
+                "sRGB",
+                point2(0.64, 0.33),
+                point2(0.30, 0.60),
+                point2(0.15, 0.06),
+                point2(D65.0, D65.1),
+            )
+        })
+    }
+
+    /// The wide gamut of ITU-R BT.2020 (UHDTV).
+    pub fn rec2020() -> &'static ColorSpace {
+        static SPACE: OnceLock<ColorSpace> = OnceLock::new();
+        SPACE.get_or_init(|| {
+            ColorSpace::new(
+                "Rec.2020",
+                point2(0.708, 0.292),
+                point2(0.170, 0.797),
+                point2(0.131, 0.046),
+                point2(D65.0, D65.1),
+            )
+        })
+    }
+
+    /// ACES AP1 primaries with the ACES white point (close to D60), a common rendering space.
+    pub fn aces_cg() -> &'static ColorSpace {
+        static SPACE: OnceLock<ColorSpace> = OnceLock::new();
+        SPACE.get_or_init(|| {
+            ColorSpace::new(
+                "ACEScg",
+                point2(0.713, 0.293),
+                point2(0.165, 0.830),
+                point2(0.128, 0.044),
+                point2(0.32168, 0.33767),
+            )
+        })
+    }
+
+    /// DCI-P3 primaries with a D65 white point, as used by many wide gamut displays.
+    pub fn display_p3() -> &'static ColorSpace {
+        static SPACE: OnceLock<ColorSpace> = OnceLock::new();
+        SPACE.get_or_init(|| {
+            ColorSpace::new(
+                "Display P3",
+                point2(0.680, 0.320),
+                point2(0.265, 0.690),
+                point2(0.150, 0.060),
+                point2(D65.0, D65.1),
+            )
+        })
+    }
+
+    pub fn to_xyz(&self, col: &Color3) -> Vec3 {
+        self.rgb_to_xyz * col
+    }
+
+    pub fn color_from_xyz(&self, xyz: &Vec3) -> Color3 {
+        self.xyz_to_rgb * xyz
+    }
+
+    /// The Y of a color in this space.
+    pub fn luminance(&self, col: &Color3) -> F {
+        self.rgb_to_xyz.row(1).transpose().dot(col)
+    }
+
+    /// The matrix converting colors from this space to `to`, adapting white to white.
+    pub fn conversion_to(&self, to: &ColorSpace) -> Matrix3 {
+        to.xyz_to_rgb * chromatic_adaptation(self.white, to.white) * self.rgb_to_xyz
+    }
+
+    pub fn convert(&self, col: &Color3, to: &ColorSpace) -> Color3 {
+        self.conversion_to(to) * col
+    }
+
+    /// Converts a color given in this space, such as one picked in an sRGB color picker, to
+    /// the working space.
+    pub fn to_working(&self, col: &Color3) -> Color3 {
+        self.convert(col, working_space())
+    }
+}
+
+/// The Bradford transform from CIE XYZ under white point `src` to XYZ under `dst`, with both
+/// given as xy chromaticities.
+pub fn chromatic_adaptation(src: Point2, dst: Point2) -> Matrix3 {
+    if src == dst {
+        return Matrix3::identity();
+    }
+    let bradford = Matrix3::new(
+        0.8951, 0.2664, -0.1614, //
+        -0.7502, 1.7135, 0.0367, //
+        0.0389, -0.0685, 1.0296,
+    );
+    let src_lms = bradford * xy_to_xyz(src);
+    let dst_lms = bradford * xy_to_xyz(dst);
+    let scale = Matrix3::from_diagonal(&dst_lms.component_div(&src_lms));
+    bradford.try_inverse().unwrap() * scale * bradford
+}
+
+static WORKING_SPACE: OnceLock<&'static ColorSpace> = OnceLock::new();
+
+/// The space every RGB value in the renderer is in: material and light colors, spectral
+/// upsampling and the rendered image. sRGB unless chosen otherwise.
+pub fn working_space() -> &'static ColorSpace {
+    WORKING_SPACE.get_or_init(ColorSpace::srgb)
+}
+
+/// Chooses the working space. This has to happen before any color is used, since colors and
+/// cached spectral fits made in the old space would silently turn wrong.
+pub fn set_working_space(space: &'static ColorSpace) -> Result<(), String> {
+    if WORKING_SPACE.set(space).is_err() && !std::ptr::eq(working_space(), space) {
+        return Err(format!(
+            "the working color space is already {}",
+            working_space().name
+        ));
+    }
+    Ok(())
+}
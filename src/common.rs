@@ -8,6 +8,8 @@ pub type UI = u64;
 pub type S = usize;
 
 pub const EPSILON: F = 1e-10;
+/// The largest representable float strictly less than one.
+pub const ONE_MINUS_EPSILON: F = 1.0 - F::EPSILON / 2.0;
 
 pub const X_AXIS: UI = 0;
 pub const Y_AXIS: UI = 1;
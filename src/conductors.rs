@@ -0,0 +1,79 @@
+use crate::{
+    color::Color3,
+    common::*,
+    spectrum::{reflectance_to_rgb, PiecewiseLinearSpectrum, Spectrum},
+};
+
+/// Wavelengths, in nanometers, the conductor tables are sampled at.
+const LAMBDAS: [F; 7] = [400.0, 450.0, 500.0, 550.0, 600.0, 650.0, 700.0];
+
+/// Measured complex refractive indices `(name, eta, k)` of common metals, sampled coarsely
+/// from published data (Johnson & Christy for the noble metals and titanium, Rakić for
+/// aluminium).
+const CONDUCTORS: [(&str, [F; 7], [F; 7]); 6] = [
+    (
+        "au",
+        [1.66, 1.44, 0.97, 0.37, 0.25, 0.16, 0.13],
+        [1.96, 1.60, 1.87, 2.39, 2.98, 3.45, 3.95],
+    ),
+    (
+        "ag",
+        [0.17, 0.14, 0.13, 0.12, 0.12, 0.14, 0.15],
+        [1.95, 2.45, 3.00, 3.45, 3.90, 4.40, 4.80],
+    ),
+    (
+        "cu",
+        [1.18, 1.13, 1.12, 0.95, 0.25, 0.21, 0.21],
+        [2.21, 2.16, 2.58, 2.58, 3.41, 3.67, 4.20],
+    ),
+    (
+        "al",
+        [0.49, 0.62, 0.77, 0.96, 1.20, 1.47, 1.83],
+        [4.86, 5.47, 6.08, 6.69, 7.26, 7.79, 8.31],
+    ),
+    (
+        "cr",
+        [1.50, 1.65, 2.30, 2.92, 3.40, 3.90, 4.40],
+        [3.60, 3.75, 4.00, 4.23, 4.50, 4.90, 5.20],
+    ),
+    (
+        "ti",
+        [1.86, 1.96, 2.10, 2.30, 2.54, 2.76, 2.96],
+        [2.60, 2.78, 2.95, 3.15, 3.35, 3.45, 3.50],
+    ),
+];
+
+/// Complex index of refraction `eta + ik` of a conductor over wavelength, and its reduction
+/// to RGB for renderers that don't trace wavelengths.
+pub struct ConductorIor {
+    pub eta: PiecewiseLinearSpectrum,
+    pub k: PiecewiseLinearSpectrum,
+    pub eta_rgb: Color3,
+    pub k_rgb: Color3,
+}
+
+impl ConductorIor {
+    pub fn new(eta: PiecewiseLinearSpectrum, k: PiecewiseLinearSpectrum) -> Self {
+        Self {
+            eta_rgb: reflectance_to_rgb(&eta),
+            k_rgb: reflectance_to_rgb(&k),
+            eta,
+            k,
+        }
+    }
+
+    /// A measured metal by chemical symbol: `Au`, `Ag`, `Cu`, `Al`, `Cr` or `Ti`.
+    pub fn named(name: &str) -> Option<Self> {
+        let name = name.to_lowercase();
+        let (_, eta, k) = CONDUCTORS.iter().find(|(n, _, _)| *n == name)?;
+        Some(Self::new(
+            PiecewiseLinearSpectrum::new(&LAMBDAS, eta),
+            PiecewiseLinearSpectrum::new(&LAMBDAS, k),
+        ))
+    }
+
+    /// `eta` and `k` at `lambda`, in nanometers.
+    pub fn at(&self, lambda: F) -> (F, F) {
+        (self.eta.evaluate(lambda), self.k.evaluate(lambda))
+    }
+}
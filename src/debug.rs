@@ -0,0 +1,171 @@
+use std::sync::Arc;
+
+use clap::ValueEnum;
+
+use crate::{
+    camera::SimpleCamera,
+    color::{black, color3, Color3},
+    common::*,
+    distributions::Distribution1D,
+    integrator::Integrator,
+    interaction::Interaction,
+    ray::Ray,
+    rng::RngGen,
+    scene::Scene,
+    vector::*,
+};
+
+/// Ambient occlusion: the cosine-weighted fraction of the hemisphere above each visible point
+/// that isn't blocked by geometry within `max_distance`. Ignores materials and lights, so it
+/// shows the shape of a scene on its own.
+pub struct AoIntegrator {
+    n_samples: S,
+    max_distance: F,
+}
+
+impl AoIntegrator {
+    pub fn new(n_samples: S, max_distance: F) -> Self {
+        Self {
+            n_samples,
+            max_distance,
+        }
+    }
+}
+
+impl Integrator for AoIntegrator {
+    fn li(&self, ray: &mut Ray, scene: &Scene, depth: S, rng: &RngGen) -> Color3 {
+        let inter = match scene.intersect(ray) {
+            Some(inter) => inter,
+            None => return black(),
+        };
+        let mut n = inter.shading.as_ref().map_or(inter.n.unwrap(), |s| s.n);
+        n = n.normalize();
+        if n.dot(&ray.direction) > 0.0 {
+            n = -n;
+        }
+        let (s, t) = coordinate_system(&n);
+
+        let mut unoccluded = 0;
+        for _ in 0..self.n_samples {
+            // With cosine-weighted sampling each unoccluded direction contributes exactly one.
+            let w = Distribution1D::cosine_sample_hemisphere(&rng.uniform_sample_point2());
+            let wi = w.x * s + w.y * t + w.z * n;
+            let mut occlusion_ray = inter.spawn_ray(wi);
+            occlusion_ray.t_max = self.max_distance;
+            if !scene.intersect_p(&occlusion_ray) {
+                unoccluded += 1;
+            }
+        }
+        let ao = unoccluded as F / self.n_samples as F;
+        color3(ao, ao, ao)
+    }
+}
+
+/// What `DebugIntegrator` shows at the first surface each camera ray hits.
+#[derive(Clone, Copy, PartialEq, Debug, ValueEnum)]
+pub enum DebugMode {
+    ShadingNormal,
+    GeometricNormal,
+    Uv,
+    /// Distance from the camera, scaled so the far side of the scene is white.
+    Depth,
+    PrimitiveId,
+    /// Primitives sharing a material get the same color.
+    MaterialId,
+    /// Number of BxDFs making up the BSDF: black for none (medium boundaries), then blue,
+    /// green, yellow and red for four or more.
+    BsdfCount,
+}
+
+/// Shows a single property of the geometry or materials instead of lighting, for tracking
+/// down problems in a scene. Surfaces missed by every camera ray are black.
+pub struct DebugIntegrator {
+    mode: DebugMode,
+    max_depth: F,
+}
+
+impl DebugIntegrator {
+    pub fn new(mode: DebugMode) -> Self {
+        Self {
+            mode,
+            max_depth: 1.0,
+        }
+    }
+}
+
+/// Maps each component of a unit vector from `[-1, 1]` to `[0, 1]`.
+fn vector_color(v: &Vec3) -> Color3 {
+    (v.normalize() + color3(1.0, 1.0, 1.0)) / 2.0
+}
+
+/// A distinct, stable color for each index.
+fn id_color(id: S) -> Color3 {
+    // Step around the hue circle by the golden ratio so neighbouring ids differ clearly.
+    let hue = (id as F * 0.618_034).fract() * 6.0;
+    let x = 1.0 - (hue % 2.0 - 1.0).abs();
+    match hue as S {
+        0 => color3(1.0, x, 0.0),
+        1 => color3(x, 1.0, 0.0),
+        2 => color3(0.0, 1.0, x),
+        3 => color3(0.0, x, 1.0),
+        4 => color3(x, 0.0, 1.0),
+        _ => color3(1.0, 0.0, x),
+    }
+}
+
+/// Index in `scene.objs` of the primitive that was hit.
+fn primitive_index(scene: &Scene, inter: &Interaction) -> Option<S> {
+    let primitive = inter.primitive.as_ref()?;
+    scene
+        .objs
+        .iter()
+        .position(|obj| Arc::ptr_eq(&obj.shape, &primitive.shape))
+}
+
+/// Index of the first primitive in `scene.objs` using the same material as the one hit.
+fn material_index(scene: &Scene, inter: &Interaction) -> Option<S> {
+    let material = inter.primitive.as_ref()?.material.as_ref()?;
+    scene.objs.iter().position(|obj| {
+        obj.material
+            .as_ref()
+            .is_some_and(|m| Arc::ptr_eq(m, material))
+    })
+}
+
+impl Integrator for DebugIntegrator {
+    fn preprocess(&mut self, scene: &Scene, cam: &SimpleCamera) {
+        let (center, radius) = scene.world_bounds().bounding_sphere();
+        self.max_depth = distance3d(&cam.position(), &center) + radius;
+    }
+
+    fn li(&self, ray: &mut Ray, scene: &Scene, depth: S, rng: &RngGen) -> Color3 {
+        let inter = match scene.intersect(ray) {
+            Some(inter) => inter,
+            None => return black(),
+        };
+        match self.mode {
+            DebugMode::ShadingNormal => match inter.shading {
+                Some(ref shading) => vector_color(&shading.n),
+                None => black(),
+            },
+            DebugMode::GeometricNormal => vector_color(&inter.n.unwrap()),
+            DebugMode::Uv => match inter.uv {
+                Some(uv) => color3(uv.x, uv.y, 0.0),
+                None => black(),
+            },
+            DebugMode::Depth => {
+                let d = distance3d(&ray.origin, &inter.p) / self.max_depth;
+                color3(d, d, d)
+            }
+            DebugMode::PrimitiveId => primitive_index(scene, &inter).map_or(black(), id_color),
+            DebugMode::MaterialId => material_index(scene, &inter).map_or(black(), id_color),
+            DebugMode::BsdfCount => match inter.bsdf.as_ref().map_or(0, |b| b.bxdfs.len()) {
+                0 => black(),
+                1 => color3(0.0, 0.0, 1.0),
+                2 => color3(0.0, 1.0, 0.0),
+                3 => color3(1.0, 1.0, 0.0),
+                _ => color3(1.0, 0.0, 0.0),
+            },
+        }
+    }
+}
@@ -36,35 +36,41 @@ impl Distribution1D {
         self.func.len()
     }
 
-    pub fn sample_discrete(&self, u: F) -> Option<(F, F)> {
-        self.cdf
-            .iter()
-            .enumerate()
-            .find(|(_, x)| **x <= u)
-            .map(|(offset, _)| {
-                (
-                    self.func[offset] / (self.func_int / self.count() as F),
-                    (u - self.cdf[offset]) / (self.cdf[offset + 1] - self.cdf[offset]),
-                )
-            })
+    /// Index of the segment containing `u`, i.e. the last one whose cdf is at or below it.
+    fn find_interval(&self, u: F) -> S {
+        let i = self.cdf.partition_point(|x| *x <= u);
+        i.saturating_sub(1).min(self.count() - 1)
+    }
+
+    /// Picks a segment with probability proportional to its value, returning its index, that
+    /// probability and `u` remapped to `[0, 1)` within the segment.
+    pub fn sample_discrete(&self, u: F) -> Option<(S, F, F)> {
+        if self.count() == 0 {
+            return None;
+        }
+        let offset = self.find_interval(u);
+        let mut du = u - self.cdf[offset];
+        if self.cdf[offset + 1] - self.cdf[offset] > 0.0 {
+            du /= self.cdf[offset + 1] - self.cdf[offset];
+        }
+        Some((offset, self.discrete_pdf(offset), du))
     }
     pub fn discrete_pdf(&self, index: S) -> F {
         self.func[index] / (self.func_int * self.count() as F)
     }
 
     pub fn sample_continuous(&self, u: F) -> Option<(F, F, S)> {
-        if let Some((offset, _)) = self.cdf.iter().enumerate().find(|(_, x)| **x <= u) {
-            let mut du = u - self.cdf[offset];
-            if self.cdf[offset + 1] - self.cdf[offset] > 0.0 {
-                du /= self.cdf[offset + 1] - self.cdf[offset];
-            }
-            let pdf = self.func[offset] / self.func_int;
-            Some(((offset as F + du) / self.count() as F, pdf, offset))
-        } else {
-            None
+        if self.count() == 0 {
+            return None;
         }
+        let offset = self.find_interval(u);
+        let mut du = u - self.cdf[offset];
+        if self.cdf[offset + 1] - self.cdf[offset] > 0.0 {
+            du /= self.cdf[offset + 1] - self.cdf[offset];
+        }
+        let pdf = self.func[offset] / self.func_int;
+        Some(((offset as F + du) / self.count() as F, pdf, offset))
     }
-
     pub fn uniform_sample_hemisphere(u: &Point2) -> Vec3 {
         let z = u.x;
         let r = F::sqrt(F::max(0.0, 1.0 - z * z));
@@ -103,7 +109,10 @@ impl Distribution1D {
         }
         let (theta, r) = match u_offset.x.abs() > u_offset.y.abs() {
             true => ((PI / 4.0) * (u_offset.y / u_offset.x), u_offset.x),
-            false => ((PI / 4.0) * (u_offset.x / u_offset.y), u_offset.y),
+            false => (
+                PI / 2.0 - (PI / 4.0) * (u_offset.x / u_offset.y),
+                u_offset.y,
+            ),
         };
         r * point2(theta.cos(), theta.sin())
     }
@@ -0,0 +1,396 @@
+use std::sync::{Arc, Mutex};
+
+use crate::color::{black, Color3};
+use crate::common::*;
+use crate::vector::{point2, vec2, Point2, Vec2};
+
+/// A pixel reconstruction filter, weighting samples by their offset from a pixel's center.
+/// Nonzero only within `radius` of it in each direction.
+pub trait Filter {
+    fn radius(&self) -> Vec2;
+    fn evaluate(&self, p: &Point2) -> F;
+}
+
+/// Weights every sample within the radius equally. With a radius of half a pixel, each sample
+/// only counts towards the pixel it's in.
+pub struct BoxFilter {
+    pub radius: Vec2,
+}
+
+impl BoxFilter {
+    pub fn new(radius: Vec2) -> Self {
+        Self { radius }
+    }
+}
+
+impl Filter for BoxFilter {
+    fn radius(&self) -> Vec2 {
+        self.radius
+    }
+
+    fn evaluate(&self, p: &Point2) -> F {
+        1.0
+    }
+}
+
+/// Weights fall off linearly from the center to the edge of the radius.
+pub struct TriangleFilter {
+    pub radius: Vec2,
+}
+
+impl TriangleFilter {
+    pub fn new(radius: Vec2) -> Self {
+        Self { radius }
+    }
+}
+
+impl Filter for TriangleFilter {
+    fn radius(&self) -> Vec2 {
+        self.radius
+    }
+
+    fn evaluate(&self, p: &Point2) -> F {
+        F::max(0.0, self.radius.x - p.x.abs()) * F::max(0.0, self.radius.y - p.y.abs())
+    }
+}
+
+/// A Gaussian with falloff rate `alpha`, shifted down so that it reaches zero at the radius.
+pub struct GaussianFilter {
+    radius: Vec2,
+    alpha: F,
+    exp_x: F,
+    exp_y: F,
+}
+
+impl GaussianFilter {
+    pub fn new(radius: Vec2, alpha: F) -> Self {
+        Self {
+            radius,
+            alpha,
+            exp_x: F::exp(-alpha * radius.x * radius.x),
+            exp_y: F::exp(-alpha * radius.y * radius.y),
+        }
+    }
+
+    fn gaussian(&self, d: F, exp_v: F) -> F {
+        F::max(0.0, F::exp(-self.alpha * d * d) - exp_v)
+    }
+}
+
+impl Filter for GaussianFilter {
+    fn radius(&self) -> Vec2 {
+        self.radius
+    }
+
+    fn evaluate(&self, p: &Point2) -> F {
+        self.gaussian(p.x, self.exp_x) * self.gaussian(p.y, self.exp_y)
+    }
+}
+
+/// The Mitchell-Netravali cubic, trading blurring (`b`) against ringing (`c`). Its negative
+/// lobes sharpen edges; `b = c = 1/3` is the usual compromise.
+pub struct MitchellFilter {
+    radius: Vec2,
+    inv_radius: Vec2,
+    b: F,
+    c: F,
+}
+
+impl MitchellFilter {
+    pub fn new(radius: Vec2, b: F, c: F) -> Self {
+        Self {
+            radius,
+            inv_radius: vec2(1.0 / radius.x, 1.0 / radius.y),
+            b,
+            c,
+        }
+    }
+
+    /// The 1D cubic over `[-2, 2]`.
+    fn mitchell_1d(&self, x: F) -> F {
+        let (b, c) = (self.b, self.c);
+        let x = (2.0 * x).abs();
+        if x > 1.0 {
+            ((-b - 6.0 * c) * x * x * x
+                + (6.0 * b + 30.0 * c) * x * x
+                + (-12.0 * b - 48.0 * c) * x
+                + (8.0 * b + 24.0 * c))
+                / 6.0
+        } else {
+            ((12.0 - 9.0 * b - 6.0 * c) * x * x * x
+                + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+                + (6.0 - 2.0 * b))
+                / 6.0
+        }
+    }
+}
+
+impl Filter for MitchellFilter {
+    fn radius(&self) -> Vec2 {
+        self.radius
+    }
+
+    fn evaluate(&self, p: &Point2) -> F {
+        self.mitchell_1d(p.x * self.inv_radius.x) * self.mitchell_1d(p.y * self.inv_radius.y)
+    }
+}
+
+/// A sinc, the ideal low-pass filter, windowed by a wider Lanczos sinc to give it finite
+/// extent. `tau` is the number of sinc lobes within the radius.
+pub struct LanczosSincFilter {
+    radius: Vec2,
+    tau: F,
+}
+
+impl LanczosSincFilter {
+    pub fn new(radius: Vec2, tau: F) -> Self {
+        Self { radius, tau }
+    }
+
+    fn windowed_sinc(&self, x: F, radius: F) -> F {
+        let x = x.abs();
+        if x > radius {
+            return 0.0;
+        }
+        let lanczos = sinc(x / self.tau);
+        sinc(x) * lanczos
+    }
+}
+
+fn sinc(x: F) -> F {
+    let x = x.abs();
+    if x < 1e-5 {
+        return 1.0;
+    }
+    F::sin(PI * x) / (PI * x)
+}
+
+impl Filter for LanczosSincFilter {
+    fn radius(&self) -> Vec2 {
+        self.radius
+    }
+
+    fn evaluate(&self, p: &Point2) -> F {
+        self.windowed_sinc(p.x, self.radius.x) * self.windowed_sinc(p.y, self.radius.y)
+    }
+}
+
+/// A rectangle of pixels, `(x0, y0, x1, y1)` for `[x0, x1) x [y0, y1)`.
+pub type PixelBounds = (S, S, S, S);
+
+/// Resolution of the tabulated filter over one quadrant of its extent.
+const FILTER_TABLE_WIDTH: S = 16;
+
+#[derive(Clone, Copy)]
+struct FilmPixel {
+    contrib_sum: Color3,
+    filter_weight_sum: F,
+}
+
+impl FilmPixel {
+    fn new() -> Self {
+        Self {
+            contrib_sum: black(),
+            filter_weight_sum: 0.0,
+        }
+    }
+}
+
+/// The image being rendered. Samples are reconstructed into pixels with a filter, and each
+/// pixel is the filter weighted average of the samples around it. Render threads add samples
+/// to their own `FilmTile`s and merge them in when done; contributions to arbitrary pixels,
+/// such as light paths connected straight to the camera, can be splatted from any thread.
+pub struct Film {
+    /// The pixels of the full image being rendered; positions are relative to the full image.
+    pub bounds: PixelBounds,
+    pub filter: Arc<dyn Filter + Send + Sync>,
+    /// The filter's values over the quadrant `[0, radius.x] x [0, radius.y]`; it's assumed
+    /// symmetric.
+    filter_table: Vec<F>,
+    filter_integral: F,
+    pixels: Mutex<Vec<FilmPixel>>,
+    splats: Vec<Mutex<Color3>>,
+}
+
+impl Film {
+    pub fn new(bounds: PixelBounds, filter: Arc<dyn Filter + Send + Sync>) -> Self {
+        let radius = filter.radius();
+        let mut filter_table = Vec::with_capacity(FILTER_TABLE_WIDTH * FILTER_TABLE_WIDTH);
+        for y in 0..FILTER_TABLE_WIDTH {
+            for x in 0..FILTER_TABLE_WIDTH {
+                let p = point2(
+                    (x as F + 0.5) * radius.x / FILTER_TABLE_WIDTH as F,
+                    (y as F + 0.5) * radius.y / FILTER_TABLE_WIDTH as F,
+                );
+                filter_table.push(filter.evaluate(&p));
+            }
+        }
+        let cell_area = radius.x * radius.y / (FILTER_TABLE_WIDTH * FILTER_TABLE_WIDTH) as F;
+        let filter_integral = 4.0 * cell_area * filter_table.iter().sum::<F>();
+        let n_pixels = (bounds.2 - bounds.0) * (bounds.3 - bounds.1);
+        Self {
+            bounds,
+            filter,
+            filter_table,
+            filter_integral,
+            pixels: Mutex::new(vec![FilmPixel::new(); n_pixels]),
+            splats: (0..n_pixels).map(|_| Mutex::new(black())).collect(),
+        }
+    }
+
+    pub fn width(&self) -> S {
+        self.bounds.2 - self.bounds.0
+    }
+
+    pub fn height(&self) -> S {
+        self.bounds.3 - self.bounds.1
+    }
+
+    fn pixel_index(&self, x: S, y: S) -> S {
+        (y - self.bounds.1) * self.width() + (x - self.bounds.0)
+    }
+
+    /// Looks up the filter at offset `(dx, dy)` from a pixel center.
+    fn filter_weight(&self, dx: F, dy: F, inv_radius: &Vec2) -> F {
+        let ix =
+            ((dx.abs() * inv_radius.x * FILTER_TABLE_WIDTH as F) as S).min(FILTER_TABLE_WIDTH - 1);
+        let iy =
+            ((dy.abs() * inv_radius.y * FILTER_TABLE_WIDTH as F) as S).min(FILTER_TABLE_WIDTH - 1);
+        self.filter_table[iy * FILTER_TABLE_WIDTH + ix]
+    }
+
+    /// Pixels whose centers lie within the filter radius of `p`, as half-open ranges clipped
+    /// to `[x0, x1) x [y0, y1)`.
+    fn pixels_around(
+        &self,
+        p: &Point2,
+        (x0, y0, x1, y1): PixelBounds,
+    ) -> (std::ops::Range<S>, std::ops::Range<S>) {
+        let radius = self.filter.radius();
+        let p_discrete = p - vec2(0.5, 0.5);
+        let clip = |v: F, lo: S, hi: S| (v.max(lo as F) as S).min(hi);
+        (
+            clip((p_discrete.x - radius.x).ceil(), x0, x1)
+                ..clip((p_discrete.x + radius.x).floor() + 1.0, x0, x1),
+            clip((p_discrete.y - radius.y).ceil(), y0, y1)
+                ..clip((p_discrete.y + radius.y).floor() + 1.0, y0, y1),
+        )
+    }
+
+    /// A tile for rendering pixels `[x0, x1) x [y0, y1)`. Samples in them also count towards
+    /// pixels just outside, within the filter radius, so the tile covers those too.
+    pub fn tile(&self, x0: S, y0: S, x1: S, y1: S) -> FilmTile<'_> {
+        let (xs, ys) = self.pixels_around(&point2(x0 as F, y0 as F), self.bounds);
+        let (xs_end, ys_end) = self.pixels_around(&point2(x1 as F, y1 as F), self.bounds);
+        let bounds = (xs.start, ys.start, xs_end.end, ys_end.end);
+        FilmTile {
+            film: self,
+            x0,
+            y0,
+            x1,
+            y1,
+            bounds,
+            inv_radius: self.filter.radius().map(|r| 1.0 / r),
+            pixels: vec![FilmPixel::new(); (bounds.2 - bounds.0) * (bounds.3 - bounds.1)],
+        }
+    }
+
+    /// Adds a finished tile's samples to the image.
+    pub fn merge_tile(&self, tile: FilmTile) {
+        let mut pixels = self.pixels.lock().unwrap();
+        let (bx0, by0, bx1, by1) = tile.bounds;
+        for y in by0..by1 {
+            for x in bx0..bx1 {
+                let from = &tile.pixels[(y - by0) * (bx1 - bx0) + (x - bx0)];
+                let to = &mut pixels[self.pixel_index(x, y)];
+                to.contrib_sum += from.contrib_sum;
+                to.filter_weight_sum += from.filter_weight_sum;
+            }
+        }
+    }
+
+    /// Adds `col` around the continuous raster position `p_raster`, spread over the pixels
+    /// nearby with the filter. Unlike samples, splats aren't averaged, so the total added to
+    /// the image is `col`.
+    pub fn add_splat(&self, p_raster: &Point2, col: Color3) {
+        if self.filter_integral == 0.0 {
+            return;
+        }
+        let inv_radius = self.filter.radius().map(|r| 1.0 / r);
+        let (xs, ys) = self.pixels_around(p_raster, self.bounds);
+        for y in ys {
+            for x in xs.clone() {
+                let weight = self.filter_weight(
+                    x as F + 0.5 - p_raster.x,
+                    y as F + 0.5 - p_raster.y,
+                    &inv_radius,
+                );
+                if weight != 0.0 {
+                    let mut pixel = self.splats[self.pixel_index(x, y)].lock().unwrap();
+                    *pixel += col * (weight / self.filter_integral);
+                }
+            }
+        }
+    }
+
+    /// The finished image within `bounds`, row by row. Splats are summed over every sample rather than
+    /// averaged, so are multiplied by `splat_scale`, typically one over the samples per pixel.
+    pub fn image(&self, splat_scale: F) -> Vec<Color3> {
+        let pixels = self.pixels.lock().unwrap();
+        pixels
+            .iter()
+            .zip(self.splats.iter())
+            .map(|(pixel, splat)| {
+                let mut col = black();
+                if pixel.filter_weight_sum != 0.0 {
+                    col = pixel.contrib_sum / pixel.filter_weight_sum;
+                }
+                col + *splat.lock().unwrap() * splat_scale
+            })
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        for pixel in self.pixels.lock().unwrap().iter_mut() {
+            *pixel = FilmPixel::new();
+        }
+        for splat in self.splats.iter() {
+            *splat.lock().unwrap() = black();
+        }
+    }
+}
+
+/// Samples for a rectangle of the film, accumulated by a single thread before being merged.
+pub struct FilmTile<'a> {
+    film: &'a Film,
+    /// The pixels being rendered.
+    pub x0: S,
+    pub y0: S,
+    pub x1: S,
+    pub y1: S,
+    /// The pixels their samples reach.
+    bounds: PixelBounds,
+    inv_radius: Vec2,
+    pixels: Vec<FilmPixel>,
+}
+
+impl FilmTile<'_> {
+    /// Adds a radiance sample taken at the continuous raster position `p_film` to every pixel
+    /// of the tile within the filter radius of it.
+    pub fn add_sample(&mut self, p_film: &Point2, l: &Color3, sample_weight: F) {
+        let (bx0, by0, bx1, by1) = self.bounds;
+        let (xs, ys) = self.film.pixels_around(p_film, self.bounds);
+        for y in ys {
+            for x in xs.clone() {
+                let weight = self.film.filter_weight(
+                    x as F + 0.5 - p_film.x,
+                    y as F + 0.5 - p_film.y,
+                    &self.inv_radius,
+                );
+                let pixel = &mut self.pixels[(y - by0) * (bx1 - bx0) + (x - bx0)];
+                pixel.contrib_sum += l * (sample_weight * weight);
+                pixel.filter_weight_sum += weight;
+            }
+        }
+    }
+}
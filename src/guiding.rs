@@ -0,0 +1,399 @@
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    RwLock,
+};
+
+use crate::{
+    aabb::AABB3,
+    color::Color3,
+    common::*,
+    material::{Bsdf, BXDF_ALL, BXDF_SPECULAR},
+    rng::RngGen,
+    vector::*,
+};
+
+/// Leaves of the spatial tree split once they've received this many times the square root of
+/// the number of samples per pixel so far.
+const SPATIAL_SPLIT_FACTOR: F = 12000.0;
+/// Quadtree cells holding more than this fraction of the energy get subdivided.
+const DIRECTIONAL_SPLIT_FRACTION: F = 0.01;
+const MAX_DIRECTIONAL_DEPTH: S = 20;
+
+/// An `f32` that can be added to while render threads read it.
+struct AtomicF32(AtomicU32);
+
+impl AtomicF32 {
+    fn new(val: F) -> Self {
+        Self(AtomicU32::new(val.to_bits()))
+    }
+
+    fn load(&self) -> F {
+        F::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn add(&self, val: F) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((F::from_bits(bits) + val).to_bits())
+            });
+    }
+}
+
+/// Maps a direction to the unit square with an area-preserving cylindrical projection, so
+/// densities over the square and over the sphere differ by a constant `4 * PI`.
+fn dir_to_square(d: &Vec3) -> Point2 {
+    let cos_theta = d.z.clamp(-1.0, 1.0);
+    let phi = F::atan2(d.y, d.x);
+    let phi = if phi < 0.0 { phi + 2.0 * PI } else { phi };
+    point2((cos_theta + 1.0) / 2.0, phi / (2.0 * PI))
+}
+
+fn square_to_dir(p: &Point2) -> Vec3 {
+    let cos_theta = 2.0 * p.x - 1.0;
+    let phi = 2.0 * PI * p.y;
+    let sin_theta = F::sqrt(F::max(0.0, 1.0 - cos_theta * cos_theta));
+    vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+/// Which of the four quadrants `p` lies in, and `p` rescaled to that quadrant.
+fn quadrant(p: &Point2) -> (S, Point2) {
+    let x = (p.x >= 0.5) as S;
+    let y = (p.y >= 0.5) as S;
+    (x + 2 * y, point2(p.x * 2.0 - x as F, p.y * 2.0 - y as F))
+}
+
+struct QuadNode {
+    /// Energy arriving through each quadrant.
+    sums: [AtomicF32; 4],
+    /// Index of the node subdividing each quadrant, or 0 for a leaf (the root can't be a child).
+    children: [S; 4],
+}
+
+impl QuadNode {
+    fn new() -> Self {
+        Self {
+            sums: [0.0; 4].map(AtomicF32::new),
+            children: [0; 4],
+        }
+    }
+
+    fn sum(&self) -> F {
+        self.sums.iter().map(|s| s.load()).sum()
+    }
+}
+
+/// Quadtree over the directional domain, adapted so that cells are roughly equally bright.
+struct DTree {
+    nodes: Vec<QuadNode>,
+    sample_weight: AtomicF32,
+}
+
+impl DTree {
+    fn new() -> Self {
+        Self {
+            nodes: vec![QuadNode::new()],
+            sample_weight: AtomicF32::new(0.0),
+        }
+    }
+
+    fn record(&self, d: &Vec3, irradiance: F) {
+        self.sample_weight.add(1.0);
+        if !irradiance.is_finite() || irradiance <= 0.0 {
+            return;
+        }
+        let mut p = dir_to_square(d);
+        let mut node = &self.nodes[0];
+        loop {
+            let (c, p_child) = quadrant(&p);
+            node.sums[c].add(irradiance);
+            if node.children[c] == 0 {
+                return;
+            }
+            node = &self.nodes[node.children[c]];
+            p = p_child;
+        }
+    }
+
+    /// Density with respect to solid angle.
+    fn pdf(&self, d: &Vec3) -> F {
+        let mut p = dir_to_square(d);
+        let mut pdf = 1.0;
+        let mut node = &self.nodes[0];
+        loop {
+            let total = node.sum();
+            if total <= 0.0 {
+                break;
+            }
+            let (c, p_child) = quadrant(&p);
+            pdf *= 4.0 * node.sums[c].load() / total;
+            if node.children[c] == 0 {
+                break;
+            }
+            node = &self.nodes[node.children[c]];
+            p = p_child;
+        }
+        pdf / (4.0 * PI)
+    }
+
+    fn sample(&self, u: &Point2) -> Vec3 {
+        let mut u = *u;
+        let mut origin = point2(0.0, 0.0);
+        let mut size = 1.0;
+        let mut node = &self.nodes[0];
+        loop {
+            let sums = node.sums.each_ref().map(|s| s.load());
+            let total: F = sums.iter().sum();
+            if total <= 0.0 {
+                break;
+            }
+            // Pick a column in proportion to its energy, then a quadrant within it.
+            let p_left = (sums[0] + sums[2]) / total;
+            let x = if u.x < p_left {
+                u.x /= p_left;
+                0
+            } else {
+                u.x = (u.x - p_left) / (1.0 - p_left);
+                1
+            };
+            let p_bottom = sums[x] / (sums[x] + sums[x + 2]);
+            let y = if u.y < p_bottom {
+                u.y /= p_bottom;
+                0
+            } else {
+                u.y = (u.y - p_bottom) / (1.0 - p_bottom);
+                1
+            };
+            size /= 2.0;
+            origin += point2(x as F, y as F) * size;
+            let child = node.children[x + 2 * y];
+            if child == 0 {
+                break;
+            }
+            node = &self.nodes[child];
+        }
+        let u = point2(u.x.min(ONE_MINUS_EPSILON), u.y.min(ONE_MINUS_EPSILON));
+        square_to_dir(&(origin + u * size))
+    }
+
+    /// A tree with no energy yet, subdivided wherever this one holds more than a small
+    /// fraction of the total.
+    fn refined(&self) -> Self {
+        let mut refined = DTree::new();
+        let total = self.nodes[0].sum();
+        if total > 0.0 {
+            self.refine_into(&mut refined, 0, Some(0), total, total, 1);
+        }
+        refined
+    }
+
+    /// Subdivides node `dst` of `refined` to match the energy under node `src` of `self`. Past
+    /// the leaves of `self` (`src` is `None`), the energy is assumed to be spread evenly.
+    fn refine_into(
+        &self,
+        refined: &mut DTree,
+        dst: S,
+        src: Option<S>,
+        energy: F,
+        total: F,
+        depth: S,
+    ) {
+        for c in 0..4 {
+            let (child_energy, src_child) = match src {
+                Some(src) => {
+                    let node = &self.nodes[src];
+                    let child = node.children[c];
+                    (node.sums[c].load(), (child != 0).then_some(child))
+                }
+                None => (energy / 4.0, None),
+            };
+            if child_energy / total > DIRECTIONAL_SPLIT_FRACTION && depth < MAX_DIRECTIONAL_DEPTH {
+                let child = refined.nodes.len();
+                refined.nodes.push(QuadNode::new());
+                refined.nodes[dst].children[c] = child;
+                self.refine_into(refined, child, src_child, child_energy, total, depth + 1);
+            }
+        }
+    }
+
+    fn copy(&self) -> Self {
+        Self {
+            nodes: self
+                .nodes
+                .iter()
+                .map(|node| QuadNode {
+                    sums: node.sums.each_ref().map(|s| AtomicF32::new(s.load())),
+                    children: node.children,
+                })
+                .collect(),
+            sample_weight: AtomicF32::new(self.sample_weight.load()),
+        }
+    }
+}
+
+/// The directional distribution guiding the current pass, and the one learning from it.
+struct DTreePair {
+    sampling: DTree,
+    building: DTree,
+}
+
+struct SNode {
+    axis: S,
+    /// Both children, or `None` for a leaf.
+    children: Option<[S; 2]>,
+    dtrees: DTreePair,
+}
+
+/// Spatio-directional tree (Müller et al., "Practical Path Guiding"): a binary tree over space
+/// whose leaves each hold a quadtree approximating the light arriving there from every
+/// direction.
+pub struct SdTree {
+    bounds: AABB3,
+    nodes: Vec<SNode>,
+}
+
+impl SdTree {
+    pub fn new(bounds: AABB3) -> Self {
+        // Cube-shaped bounds keep the cells from getting stretched as they split.
+        let extent = bounds.diagonal().max();
+        Self {
+            bounds: AABB3::new(bounds.p_min, bounds.p_min + vec3(extent, extent, extent)),
+            nodes: vec![SNode {
+                axis: 0,
+                children: None,
+                dtrees: DTreePair {
+                    sampling: DTree::new(),
+                    building: DTree::new(),
+                },
+            }],
+        }
+    }
+
+    fn leaf(&self, p: &Point3) -> &DTreePair {
+        let mut p = self.bounds.offset(*p);
+        let mut node = &self.nodes[0];
+        while let Some(children) = node.children {
+            let axis = node.axis;
+            if p[axis] < 0.5 {
+                p[axis] *= 2.0;
+                node = &self.nodes[children[0]];
+            } else {
+                p[axis] = p[axis] * 2.0 - 1.0;
+                node = &self.nodes[children[1]];
+            }
+        }
+        &node.dtrees
+    }
+
+    /// Adds an estimate of the light arriving at `p` from direction `wi`, i.e. radiance
+    /// divided by the pdf of having sampled `wi`. The sums depend on the order of the records,
+    /// so callers add them in a fixed order.
+    pub fn record(&self, p: &Point3, wi: &Vec3, irradiance: F) {
+        self.leaf(p).building.record(wi, irradiance);
+    }
+
+    pub fn pdf(&self, p: &Point3, wi: &Vec3) -> F {
+        self.leaf(p).sampling.pdf(wi)
+    }
+
+    pub fn sample(&self, p: &Point3, u: &Point2) -> Vec3 {
+        self.leaf(p).sampling.sample(u)
+    }
+
+    /// Starts guiding with what was learned in the last pass: splits spatial leaves that
+    /// received enough samples, then refines each quadtree to follow its energy.
+    fn refine(&mut self, total_samples_per_pixel: S) {
+        let threshold = SPATIAL_SPLIT_FACTOR * (total_samples_per_pixel as F).sqrt();
+        let mut stack = vec![0];
+        while let Some(i) = stack.pop() {
+            if let Some(children) = self.nodes[i].children {
+                stack.extend(children);
+                continue;
+            }
+            let weight = self.nodes[i].dtrees.building.sample_weight.load();
+            if weight > threshold {
+                let axis = self.nodes[i].axis;
+                for _ in 0..2 {
+                    let mut building = self.nodes[i].dtrees.building.copy();
+                    building.sample_weight = AtomicF32::new(weight / 2.0);
+                    self.nodes.push(SNode {
+                        axis: (axis + 1) % 3,
+                        children: None,
+                        dtrees: DTreePair {
+                            sampling: self.nodes[i].dtrees.sampling.copy(),
+                            building,
+                        },
+                    });
+                }
+                let n = self.nodes.len();
+                self.nodes[i].children = Some([n - 2, n - 1]);
+                stack.extend([n - 2, n - 1]);
+            }
+        }
+
+        for node in self.nodes.iter_mut().filter(|node| node.children.is_none()) {
+            let refined = node.dtrees.building.refined();
+            node.dtrees.sampling = std::mem::replace(&mut node.dtrees.building, refined);
+        }
+    }
+}
+
+/// Learned directional sampling for `PathIntegrator`. Rendering runs in passes of doubling
+/// sample counts, each guided by what the previous ones recorded, and only the last pass
+/// (which gets at least half of the samples) makes it into the image.
+pub struct PathGuide {
+    /// Probability of sampling the BSDF rather than the learned distribution.
+    pub bsdf_sampling_fraction: F,
+    pub tree: RwLock<SdTree>,
+}
+
+impl PathGuide {
+    pub fn new(bsdf_sampling_fraction: F) -> Self {
+        Self {
+            bsdf_sampling_fraction,
+            tree: RwLock::new(SdTree::new(AABB3::from(point3(0.0, 0.0, 0.0)))),
+        }
+    }
+
+    pub fn reset(&mut self, bounds: AABB3) {
+        self.tree = RwLock::new(SdTree::new(bounds));
+    }
+
+    pub fn refine(&self, total_samples_per_pixel: S) {
+        self.tree.write().unwrap().refine(total_samples_per_pixel);
+    }
+
+    /// Samples a direction at `p` from either the BSDF or the tree, returning the BSDF value,
+    /// the pdf of the combined strategy, the direction and whether a specular lobe was
+    /// sampled. Specular lobes can only come from the BSDF, so their pdf is just scaled by the
+    /// chance of sampling it.
+    pub fn sample_f(
+        &self,
+        tree: &SdTree,
+        bsdf: &Bsdf,
+        p: &Point3,
+        wo: &Vec3,
+        rng: &RngGen,
+    ) -> Option<(Color3, F, Vec3, bool)> {
+        let u = rng.uniform_sample_point2();
+        if bsdf.num_components(BXDF_ALL & !BXDF_SPECULAR) == 0 {
+            let (f, pdf, wi, flags) = bsdf.sample_f(wo, &u, BXDF_ALL)?;
+            return Some((f, pdf, wi, flags & BXDF_SPECULAR != 0));
+        }
+        let alpha = self.bsdf_sampling_fraction;
+        if u.x < alpha {
+            let u = point2((u.x / alpha).min(ONE_MINUS_EPSILON), u.y);
+            let (f, bsdf_pdf, wi, flags) = bsdf.sample_f(wo, &u, BXDF_ALL)?;
+            if flags & BXDF_SPECULAR != 0 {
+                return Some((f, bsdf_pdf * alpha, wi, true));
+            }
+            let pdf = alpha * bsdf_pdf + (1.0 - alpha) * tree.pdf(p, &wi);
+            Some((f, pdf, wi, false))
+        } else {
+            let u = point2(((u.x - alpha) / (1.0 - alpha)).min(ONE_MINUS_EPSILON), u.y);
+            let wi = tree.sample(p, &u);
+            let pdf = alpha * bsdf.pdf(wo, &wi, BXDF_ALL) + (1.0 - alpha) * tree.pdf(p, &wi);
+            Some((bsdf.f(wo, &wi, BXDF_ALL), pdf, wi, false))
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use exr::meta::attribute::Chromaticities;
+use exr::prelude::{
+    f16, AnyChannel, AnyChannels, Encoding, FlatSamples, Image, Vec2, WritableImage,
+};
+
+use crate::color::Color3;
+use crate::colorspace::working_space;
+use crate::common::*;
+use crate::tonemap::ToneMapper;
+use crate::vector::Point2;
+
+/// How OpenEXR files store their samples.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExrPixelType {
+    /// 16-bit floats, the usual choice for color, at half the size.
+    Half,
+    /// 32-bit floats, for data that needs the precision, such as depth.
+    Float,
+}
+
+/// A named image to write alongside the main one, such as an albedo or normal pass. Only
+/// OpenEXR files have room for these.
+pub struct ImageLayer<'a> {
+    pub name: &'a str,
+    pub pixels: &'a [Color3],
+}
+
+/// Writes a linear image, stored row by row from the top, in the format given by the file's
+/// extension: `.png` and `.ppm` are 8-bit sRGB, passed through `tone_map`, while `.pfm` and
+/// `.exr` keep the full floating point values in the working space.
+pub fn write_image(
+    path: &Path,
+    image: &[Color3],
+    width: S,
+    height: S,
+    tone_map: &ToneMapper,
+) -> Result<(), Box<dyn Error>> {
+    write_image_layers(
+        path,
+        image,
+        &[],
+        width,
+        height,
+        tone_map,
+        ExrPixelType::Half,
+    )
+}
+
+/// Like `write_image`, with extra layers and a sample type for OpenEXR files.
+pub fn write_image_layers(
+    path: &Path,
+    image: &[Color3],
+    layers: &[ImageLayer],
+    width: S,
+    height: S,
+    tone_map: &ToneMapper,
+    exr_pixel_type: ExrPixelType,
+) -> Result<(), Box<dyn Error>> {
+    assert_eq!(image.len(), width * height);
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("png") => write_png(path, image, width, height, tone_map),
+        Some("ppm") => write_ppm(path, image, width, height, tone_map),
+        Some("pfm") => write_pfm(path, image, width, height),
+        Some("exr") => write_exr(path, image, layers, width, height, exr_pixel_type),
+        _ => Err(format!("unsupported image format: {}", path.display()).into()),
+    }
+}
+
+fn write_png(
+    path: &Path,
+    image: &[Color3],
+    width: S,
+    height: S,
+    tone_map: &ToneMapper,
+) -> Result<(), Box<dyn Error>> {
+    let writer = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(writer, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_source_srgb(png::SrgbRenderingIntent::Perceptual);
+    let data: Vec<u8> = image
+        .iter()
+        .flat_map(|col| tone_map.to_srgb8(col))
+        .collect();
+    encoder.write_header()?.write_image_data(&data)?;
+    Ok(())
+}
+
+fn write_ppm(
+    path: &Path,
+    image: &[Color3],
+    width: S,
+    height: S,
+    tone_map: &ToneMapper,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write!(writer, "P6\n{} {}\n255\n", width, height)?;
+    for col in image {
+        writer.write_all(&tone_map.to_srgb8(col))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Portable float map: little-endian 32-bit floats (flagged by the negative scale), with the
+/// rows stored from the bottom.
+fn write_pfm(path: &Path, image: &[Color3], width: S, height: S) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write!(writer, "PF\n{} {}\n-1.0\n", width, height)?;
+    for row in image.chunks_exact(width).rev() {
+        for col in row {
+            for v in col.iter() {
+                writer.write_all(&v.to_le_bytes())?;
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn exr_channel(name: String, values: Vec<F>, pixel_type: ExrPixelType) -> AnyChannel<FlatSamples> {
+    let samples = match pixel_type {
+        ExrPixelType::Half => FlatSamples::F16(values.into_iter().map(f16::from_f32).collect()),
+        ExrPixelType::Float => FlatSamples::F32(values),
+    };
+    AnyChannel::new(name.as_str(), samples)
+}
+
+/// Writes the image as the file's `R`, `G` and `B` channels, and each extra layer as
+/// `<name>.R`, `<name>.G` and `<name>.B`, all in a single part. The header records the
+/// working space's chromaticities, so readers know what the RGB values mean.
+fn write_exr(
+    path: &Path,
+    image: &[Color3],
+    layers: &[ImageLayer],
+    width: S,
+    height: S,
+    pixel_type: ExrPixelType,
+) -> Result<(), Box<dyn Error>> {
+    let mut channels = vec![];
+    let main = ImageLayer {
+        name: "",
+        pixels: image,
+    };
+    for layer in std::iter::once(&main).chain(layers.iter()) {
+        assert_eq!(layer.pixels.len(), width * height);
+        let prefix = if layer.name.is_empty() {
+            String::new()
+        } else {
+            format!("{}.", layer.name)
+        };
+        for (c, suffix) in ["R", "G", "B"].iter().enumerate() {
+            let values = layer.pixels.iter().map(|col| col[c]).collect();
+            channels.push(exr_channel(
+                format!("{}{}", prefix, suffix),
+                values,
+                pixel_type,
+            ));
+        }
+    }
+    let mut exr_image = Image::from_encoded_channels(
+        (width, height),
+        Encoding::FAST_LOSSLESS,
+        AnyChannels::sort(channels.into()),
+    );
+    let space = working_space();
+    let xy = |p: Point2| Vec2(p.x, p.y);
+    exr_image.attributes.chromaticities = Some(Chromaticities {
+        red: xy(space.r),
+        green: xy(space.g),
+        blue: xy(space.b),
+        white: xy(space.white),
+    });
+    exr_image.write().to_file(path)?;
+    Ok(())
+}
@@ -1,29 +1,269 @@
 use std::sync::Arc;
 
-use rand::prelude::IteratorRandom;
-
 use crate::{
     camera::SimpleCamera,
-    color::{black, color3, Color3},
+    color::{black, color3, luminance, Color3},
     common::{F, S},
+    film::{Film, FilmTile},
+    guiding::PathGuide,
     interaction::Interaction,
     light::Light,
-    material::{BXDF_ALL, BXDF_SPECULAR},
+    material::{BXDFType, Bsdf, BXDF_ALL, BXDF_REFLECTION, BXDF_SPECULAR, BXDF_TRANSMISSION},
+    progress::ProgressReporter,
     ray::Ray,
     rng::RngGen,
+    sampler::Sampler,
     scene::Scene,
-    vector::Point2,
+    spectrum::{
+        sampled_spectrum, RgbUnboundedSpectrum, SampledSpectrum, SampledWavelengths, Spectrum,
+        N_SPECTRUM_SAMPLES,
+    },
+    tiles::{self, Tile},
+    vector::{point2, Point2, Point3, Vec3},
 };
 
-pub trait Integrator {
-    // fn render(&self, scene: &Scene, cam: &Camera) {
-    //     self.preprocess(scene, cam);
-
-    // }
+pub trait Integrator: Send + Sync {
     fn preprocess(&mut self, scene: &Scene, cam: &SimpleCamera) {}
     fn li(&self, ray: &mut Ray, scene: &Scene, depth: S, rng: &RngGen) -> Color3;
-    // fn specular_reflect(&self, ray: &Ray, inter: &SurfaceInteraction, scene: &Scene, depth: S) -> Color3;
-    // fn specular_transmit(&self, ray: &Ray, inter: &SurfaceInteraction, scene: &Scene, depth: S) -> Color3;
+    /// Contributions made to arbitrary pixels while computing `li`, e.g. light paths connected
+    /// directly to the camera. These are summed over all samples, so they need dividing by the
+    /// number of samples per pixel before being added to the image.
+    fn splats(&self) -> Option<&Film> {
+        None
+    }
+    /// Like `li`, but hands back the contributions to other pixels, with their raster
+    /// positions, instead of adding them to `splats`. Used by callers that weight every
+    /// contribution of a sample themselves, such as Metropolis light transport.
+    fn li_with_splats(
+        &self,
+        ray: &mut Ray,
+        scene: &Scene,
+        rng: &RngGen,
+    ) -> (Color3, Vec<(Point2, Color3)>) {
+        (self.li(ray, scene, 0, rng), vec![])
+    }
+    /// Renders the pixels in the camera's crop window. By default the window is split into
+    /// tiles, rendered in parallel in the order set by `tiles::set_tile_order`, with every
+    /// pixel taking `samples_per_pixel` camera rays through `li`; each finished tile is merged
+    /// into a film reconstructed with the camera's filter, then any splats are added in.
+    /// Integrators that don't estimate pixels independently, such as photon mapping, override
+    /// this.
+    fn render(
+        &self,
+        scene: &Scene,
+        cam: &SimpleCamera,
+        samples_per_pixel: S,
+        rng: &RngGen,
+    ) -> Vec<Color3> {
+        render_image(self, scene, cam, samples_per_pixel, 0, rng)
+    }
+    /// Whether the image can be built up by repeated `render_pass` calls into the same film,
+    /// for a progressive preview. Integrators whose `render` needs all the samples of a pixel
+    /// at once, or works on whole images, say no and are only rendered in one go.
+    fn progressive(&self) -> bool {
+        true
+    }
+    /// Radiance arriving at `inter` along the mirror direction of its specular reflection
+    /// lobes, weighted by the BSDF. Lobes that combine reflection and transmission, such as
+    /// `FresnelSpecular`, aren't followed; see `Material::calculate_bsdf_split_specular`.
+    fn specular_reflect(
+        &self,
+        ray: &Ray,
+        inter: &Interaction,
+        scene: &Scene,
+        depth: S,
+        rng: &RngGen,
+    ) -> Color3 {
+        self.specular_scatter(
+            ray,
+            inter,
+            scene,
+            depth,
+            rng,
+            BXDF_REFLECTION | BXDF_SPECULAR,
+        )
+    }
+    /// Radiance arriving at `inter` through its specular transmission lobes, weighted by the
+    /// BSDF.
+    fn specular_transmit(
+        &self,
+        ray: &Ray,
+        inter: &Interaction,
+        scene: &Scene,
+        depth: S,
+        rng: &RngGen,
+    ) -> Color3 {
+        self.specular_scatter(
+            ray,
+            inter,
+            scene,
+            depth,
+            rng,
+            BXDF_TRANSMISSION | BXDF_SPECULAR,
+        )
+    }
+    fn specular_scatter(
+        &self,
+        ray: &Ray,
+        inter: &Interaction,
+        scene: &Scene,
+        depth: S,
+        rng: &RngGen,
+        flags: BXDFType,
+    ) -> Color3 {
+        let bsdf = match inter.bsdf {
+            Some(ref bsdf) => bsdf,
+            None => return black(),
+        };
+        let ns = inter.shading.as_ref().unwrap().n;
+        let wo = -ray.direction.normalize();
+        match bsdf.sample_f(&wo, &rng.uniform_sample_point2(), flags) {
+            Some((f, pdf, wi, _)) if pdf > 0.0 && f != black() && wi.dot(&ns) != 0.0 => {
+                let mut scattered = inter.spawn_ray(wi);
+                f.component_mul(&self.li(&mut scattered, scene, depth + 1, rng)) * wi.dot(&ns).abs()
+                    / pdf
+            }
+            _ => black(),
+        }
+    }
+}
+
+/// The default `Integrator::render`, for overrides that still want to render a pass pixel by
+/// pixel. Samples are numbered from `first_sample`, so that renders adding to earlier ones
+/// draw new samples.
+pub fn render_image<T: Integrator + ?Sized>(
+    integrator: &T,
+    scene: &Scene,
+    cam: &SimpleCamera,
+    samples_per_pixel: S,
+    first_sample: S,
+    rng: &RngGen,
+) -> Vec<Color3> {
+    let film = Film::new(cam.pixel_bounds(), cam.filter.clone());
+    let progress = ProgressReporter::new(tiles::tiles(film.bounds).len(), "Rendering");
+    render_pass(
+        integrator,
+        scene,
+        cam,
+        &film,
+        samples_per_pixel,
+        first_sample,
+        rng,
+        &|_| progress.update(1),
+    );
+    progress.done();
+    film_image(integrator, &film, samples_per_pixel)
+}
+
+/// Adds `samples_per_pixel` camera rays through every pixel of `film` to it, a tile at a time
+/// in parallel, calling `on_tile` as each tile is finished. Every sample starts its own
+/// sequence of `rng`, numbered per pixel from `first_sample`, so later passes over the same
+/// film draw new samples and a seeded render doesn't depend on the tiles or threads. Tiles,
+/// and the splats made while rendering them, are merged in the order the tiles were handed
+/// out, so the sums don't depend on the threads either. Returns false if the pass was
+/// cancelled before every tile was rendered; see `tiles::cancel`.
+pub fn render_pass<T: Integrator + ?Sized>(
+    integrator: &T,
+    scene: &Scene,
+    cam: &SimpleCamera,
+    film: &Film,
+    samples_per_pixel: S,
+    first_sample: S,
+    rng: &RngGen,
+    on_tile: &(dyn Fn(&Tile) + Sync),
+) -> bool {
+    tiles::for_each_tile(
+        film.bounds,
+        |t| {
+            let (x0, y0, x1, y1) = t.bounds;
+            let mut tile = film.tile(x0, y0, x1, y1);
+            let mut splats = vec![];
+            render_tile(
+                &mut tile,
+                cam,
+                samples_per_pixel,
+                first_sample,
+                rng,
+                |ray| {
+                    let (l, sample_splats) = integrator.li_with_splats(ray, scene, rng);
+                    splats.extend(sample_splats);
+                    l
+                },
+            );
+            (tile, splats)
+        },
+        |t, (tile, splats)| {
+            film.merge_tile(tile);
+            if let Some(splat_film) = integrator.splats() {
+                for (p_raster, l) in splats {
+                    splat_film.add_splat(&p_raster, l);
+                }
+            }
+            on_tile(t);
+        },
+    )
+}
+
+/// Adds `samples_per_pixel` camera rays through every pixel of `tile`, numbered from
+/// `first_sample`, with the radiance along each given by `li`.
+pub fn render_tile(
+    tile: &mut FilmTile,
+    cam: &SimpleCamera,
+    samples_per_pixel: S,
+    first_sample: S,
+    rng: &RngGen,
+    mut li: impl FnMut(&mut Ray) -> Color3,
+) {
+    for y in tile.y0..tile.y1 {
+        for x in tile.x0..tile.x1 {
+            for index in first_sample..first_sample + samples_per_pixel {
+                rng.start_pixel_sample((x, y), index);
+                let p_film = point2(x as F, y as F) + rng.get_pixel_2d();
+                // `get_ray` aims through the pixel center, so offset back to the sample.
+                let mut ray = cam.get_ray(p_film - point2(0.5, 0.5));
+                let l = li(&mut ray);
+                tile.add_sample(&p_film, &l, 1.0);
+            }
+        }
+    }
+}
+
+/// The image in `film` after `samples_per_pixel` samples, plus the integrator's splats.
+pub fn film_image<T: Integrator + ?Sized>(
+    integrator: &T,
+    film: &Film,
+    samples_per_pixel: S,
+) -> Vec<Color3> {
+    let splat_scale = 1.0 / samples_per_pixel as F;
+    let mut image = film.image(splat_scale);
+    if let Some(splats) = integrator.splats() {
+        for (col, splat) in image.iter_mut().zip(splats.image(splat_scale)) {
+            *col += splat;
+        }
+    }
+    image
+}
+
+/// Averages `samples_per_pixel` radiance estimates for camera rays through pixel `(x, y)`,
+/// i.e. with a box filter, ignoring the camera's.
+pub fn render_pixel<T: Integrator + ?Sized>(
+    integrator: &T,
+    scene: &Scene,
+    cam: &SimpleCamera,
+    x: S,
+    y: S,
+    samples_per_pixel: S,
+    rng: &RngGen,
+) -> Color3 {
+    let mut out_col = black();
+    for index in 0..samples_per_pixel {
+        rng.start_pixel_sample((x, y), index);
+        let u = rng.get_pixel_2d();
+        let mut ray = cam.get_ray(point2(x as F + u.x - 0.5, y as F + u.y - 0.5));
+        let col = integrator.li(&mut ray, scene, 0, rng);
+        out_col += col / samples_per_pixel as F;
+    }
+    out_col
 }
 
 pub fn power_heuristic(nf: S, f_pdf: F, ng: S, g_pdf: F) -> F {
@@ -32,6 +272,20 @@ pub fn power_heuristic(nf: S, f_pdf: F, ng: S, g_pdf: F) -> F {
     (f * f) / (f * f + g * g)
 }
 
+/// Evaluates the BSDF (or phase function, for medium interactions) for light arriving from
+/// `wi`, returning the cosine-weighted value and the pdf of sampling that direction.
+fn scattering_f_pdf(inter: &Interaction, wi: &Vec3, flags: BXDFType) -> (Color3, F) {
+    let wo = inter.wo.unwrap();
+    if let Some(ref phase) = inter.phase {
+        let p = phase.p(&wo, wi);
+        (color3(p, p, p), p)
+    } else {
+        let bsdf = inter.bsdf.as_ref().unwrap();
+        let f = bsdf.f(&wo, wi, flags) * wi.dot(&inter.shading.as_ref().unwrap().n).abs();
+        (f, bsdf.pdf(&wo, wi, flags))
+    }
+}
+
 #[allow(clippy::borrowed_box)]
 pub fn estimate_direct(
     inter: &Interaction,
@@ -45,37 +299,44 @@ pub fn estimate_direct(
     let mut ld = black();
     if let Some(mut li) = light.sample_li(Arc::new(inter.clone()), u_light) {
         if li.pdf > 0.0 && li.col != black() {
-            let f = inter
-                .bsdf
-                .as_ref()
-                .unwrap()
-                .f(&inter.wo.unwrap(), &li.wi, flags)
-                * li.wi.dot(&inter.shading.as_ref().unwrap().n);
-            let scattering_pdf =
-                inter
-                    .bsdf
-                    .as_ref()
-                    .unwrap()
-                    .pdf(&inter.wo.unwrap(), &li.wi, flags);
+            let (f, scattering_pdf) = scattering_f_pdf(inter, &li.wi, flags);
             if f != black() {
-                if !li.vis.unoccluded(scene) {
-                    li.col = black();
-                } else {
-                    let weight = power_heuristic(1, li.pdf, 1, scattering_pdf);
+                li.col = li.col.component_mul(&li.vis.transmittance(scene, rng));
+                if li.col != black() {
+                    let weight = if light.is_delta_position() {
+                        1.0
+                    } else {
+                        power_heuristic(1, li.pdf, 1, scattering_pdf)
+                    };
                     ld += f.component_mul(&li.col) * weight / li.pdf;
                 }
             }
         }
     }
-    if let Some((mut f, scattering_pdf, wi, sampled_type)) =
+    if light.is_delta_position() {
+        return ld;
+    }
+
+    let sampled = if let Some(ref phase) = inter.phase {
+        let (wi, p) = phase.sample_p(&inter.wo.unwrap(), &u_scattering);
+        Some((color3(p, p, p), p, wi, false))
+    } else {
+        let shading_n = inter.shading.as_ref().unwrap().n;
         inter
             .bsdf
             .as_ref()
             .unwrap()
             .sample_f(&inter.wo.unwrap(), &u_scattering, flags)
-    {
-        f *= wi.dot(&inter.shading.as_ref().unwrap().n).abs();
-        let sampled_specular = sampled_type & BXDF_SPECULAR != 0;
+            .map(|(f, pdf, wi, sampled_type)| {
+                (
+                    f * wi.dot(&shading_n).abs(),
+                    pdf,
+                    wi,
+                    sampled_type & BXDF_SPECULAR != 0,
+                )
+            })
+    };
+    if let Some((f, scattering_pdf, wi, sampled_specular)) = sampled {
         if f != black() && scattering_pdf > 0.0 {
             let mut weight = 1.0;
             if !sampled_specular {
@@ -85,16 +346,14 @@ pub fn estimate_direct(
                 }
                 weight = power_heuristic(1, scattering_pdf, 1, li_pdf);
             }
-            let ray = inter.spawn_ray_to_point(&wi);
-            // let tr = 1.0;
-            // let mut li = black();
-            // if let Some(light_isect) = scene.intersect(&mut ray) {
-            // li = light_isect.le(-wi);
-            // } else {
-            let li = light.le(&ray);
-            // }
-            if li != black() {
-                ld += f.component_mul(&li) * weight / scattering_pdf;
+            let mut ray = inter.spawn_ray(wi);
+            let (light_inter, tr) = scene.intersect_tr(&mut ray, rng);
+            // TODO: add emission from area lights once primitives can be emitters.
+            if light_inter.is_none() {
+                let li = light.le(&ray);
+                if li != black() {
+                    ld += f.component_mul(&li).component_mul(&tr) * weight / scattering_pdf;
+                }
             }
         }
     }
@@ -138,7 +397,8 @@ pub fn uniform_sample_one_light(inter: &Interaction, scene: &Scene, rng: &RngGen
     if scene.lights.is_empty() {
         return black();
     }
-    let light = scene.lights.iter().choose(&mut rand::thread_rng()).unwrap();
+    let light_idx = ((rng.sample_0_1() * scene.lights.len() as F) as S).min(scene.lights.len() - 1);
+    let light = &scene.lights[light_idx];
     let u_light = rng.uniform_sample_point2();
     let u_scattering = rng.uniform_sample_point2();
     scene.lights.len() as F * estimate_direct(inter, u_scattering, light, u_light, scene, rng)
@@ -204,21 +464,43 @@ impl Integrator for DirectLightingIntegrator {
 
 pub struct PathIntegrator {
     max_depth: S,
+    /// When set, directions are partly sampled from a distribution learned over the render.
+    guide: Option<PathGuide>,
 }
 
 impl PathIntegrator {
     pub fn new(max_depth: S) -> Self {
-        Self { max_depth }
+        Self {
+            max_depth,
+            guide: None,
+        }
     }
-}
 
-impl Integrator for PathIntegrator {
-    fn li(&self, original_ray: &mut Ray, scene: &Scene, depth: S, rng: &RngGen) -> Color3 {
+    /// A path tracer with path guiding, sampling the BSDF with probability
+    /// `bsdf_sampling_fraction` and the learned distribution otherwise.
+    pub fn new_guided(max_depth: S, bsdf_sampling_fraction: F) -> Self {
+        Self {
+            max_depth,
+            guide: Some(PathGuide::new(bsdf_sampling_fraction)),
+        }
+    }
+
+    /// Traces a path, adding its guided vertices to `guide_records` rather than recording them
+    /// in the guiding tree straight away, so the caller can record them in a fixed order.
+    fn li_path(
+        &self,
+        original_ray: &mut Ray,
+        scene: &Scene,
+        rng: &RngGen,
+        guide_records: &mut Vec<GuideVertex>,
+    ) -> Color3 {
         let mut out_color = black();
         let mut ray = original_ray.to_owned();
         let mut beta = color3(1.0, 1.0, 1.0);
         let mut specular_bounce = false;
         let mut bounces = 0;
+        let tree = self.guide.as_ref().map(|guide| guide.tree.read().unwrap());
+        let mut guide_vertices = vec![];
         loop {
             let inter_opt = scene.intersect(&mut ray);
             if bounces == 0 || specular_bounce {
@@ -226,7 +508,9 @@ impl Integrator for PathIntegrator {
                     // out_color += beta.component_mul(&inter.le(&-ray.direction));
                 } else {
                     for light in scene.lights.iter() {
-                        out_color += beta.component_mul(&light.le(&ray));
+                        let le = beta.component_mul(&light.le(&ray));
+                        out_color += le;
+                        add_guide_radiance(&mut guide_vertices, &le);
                     }
                 }
             }
@@ -238,28 +522,74 @@ impl Integrator for PathIntegrator {
 
             inter.scatter(&mut ray, rng);
             if inter.bsdf.is_none() {
+                // Medium boundaries don't count as a bounce.
                 ray = inter.spawn_ray(ray.direction);
-                bounces -= 1;
                 continue;
             }
 
             let bsdf = inter.bsdf.as_ref().unwrap();
-            out_color += beta.component_mul(&uniform_sample_one_light(&inter, scene, rng));
+            let ld = beta.component_mul(&uniform_sample_one_light(&inter, scene, rng));
+            out_color += ld;
+            add_guide_radiance(&mut guide_vertices, &ld);
             let wo = -ray.direction;
-            if let Some((f, pdf, wi, flags)) =
-                bsdf.sample_f(&wo, &rng.uniform_sample_point2(), BXDF_ALL)
-            {
+            let sampled = match (self.guide.as_ref(), tree.as_deref()) {
+                (Some(guide), Some(tree)) => guide.sample_f(tree, bsdf, &inter.p, &wo, rng),
+                _ => bsdf
+                    .sample_f(&wo, &rng.uniform_sample_point2(), BXDF_ALL)
+                    .map(|(f, pdf, wi, flags)| (f, pdf, wi, flags & BXDF_SPECULAR != 0)),
+            };
+            if let Some((f, pdf, wi, specular)) = sampled {
                 if f == black() || pdf == 0.0 {
                     break;
                 }
                 beta.component_mul_assign(
                     &(f * wi.dot(&inter.shading.as_ref().unwrap().n).abs() / pdf),
                 );
-                specular_bounce = flags & BXDF_SPECULAR != 0;
+                specular_bounce = specular;
+                if tree.is_some() && !specular {
+                    guide_vertices.push(GuideVertex {
+                        p: inter.p,
+                        wi: wi.normalize(),
+                        pdf,
+                        beta,
+                        radiance: black(),
+                    });
+                }
                 ray = inter.spawn_ray(wi);
+            } else {
+                break;
             }
 
-            // TODO: Subsurface scattering here!
+            // Light refracted into a translucent object comes back out somewhere nearby.
+            let n = inter.n.unwrap();
+            if let Some(ref bssrdf) = inter.bssrdf {
+                if ray.direction.dot(&n) * wo.dot(&n) < 0.0 {
+                    let u = rng.sample_0_1();
+                    let (pi, s, pdf) =
+                        match bssrdf.sample_s(&inter, scene, u, &rng.uniform_sample_point2()) {
+                            Some((pi, s, pdf)) if s != black() && pdf > 0.0 => (pi, s, pdf),
+                            _ => break,
+                        };
+                    beta.component_mul_assign(&(s / pdf));
+
+                    let ld = beta.component_mul(&uniform_sample_one_light(&pi, scene, rng));
+                    out_color += ld;
+                    add_guide_radiance(&mut guide_vertices, &ld);
+
+                    let pi_bsdf = pi.bsdf.as_ref().unwrap();
+                    match pi_bsdf.sample_f(&pi.wo.unwrap(), &rng.uniform_sample_point2(), BXDF_ALL)
+                    {
+                        Some((f, pdf, wi, flags)) if f != black() && pdf > 0.0 => {
+                            beta.component_mul_assign(
+                                &(f * wi.dot(&pi.shading.as_ref().unwrap().n).abs() / pdf),
+                            );
+                            specular_bounce = flags & BXDF_SPECULAR != 0;
+                            ray = pi.spawn_ray(wi);
+                        }
+                        _ => break,
+                    }
+                }
+            }
 
             if bounces > 3 {
                 let q = F::max(0.05, 1.0 - beta.y);
@@ -271,6 +601,504 @@ impl Integrator for PathIntegrator {
 
             bounces += 1;
         }
+        guide_records.extend(guide_vertices);
+        out_color
+    }
+
+    /// Renders a pass of `samples_per_pixel` guided samples, training `guide` with what its
+    /// paths find. The records are added to the tree a tile at a time in the order the tiles
+    /// were handed out, so the learned distribution doesn't depend on the threads.
+    fn render_guided_pass(
+        &self,
+        guide: &PathGuide,
+        scene: &Scene,
+        cam: &SimpleCamera,
+        samples_per_pixel: S,
+        first_sample: S,
+        rng: &RngGen,
+    ) -> Vec<Color3> {
+        let film = Film::new(cam.pixel_bounds(), cam.filter.clone());
+        let progress = ProgressReporter::new(tiles::tiles(film.bounds).len(), "Rendering");
+        tiles::for_each_tile(
+            film.bounds,
+            |t| {
+                let (x0, y0, x1, y1) = t.bounds;
+                let mut tile = film.tile(x0, y0, x1, y1);
+                let mut records = vec![];
+                render_tile(
+                    &mut tile,
+                    cam,
+                    samples_per_pixel,
+                    first_sample,
+                    rng,
+                    |ray| self.li_path(ray, scene, rng, &mut records),
+                );
+                (tile, records)
+            },
+            |_, (tile, records)| {
+                film.merge_tile(tile);
+                let tree = guide.tree.read().unwrap();
+                for v in records.iter() {
+                    tree.record(&v.p, &v.wi, luminance(&v.radiance) / v.pdf);
+                }
+                progress.update(1);
+            },
+        );
+        progress.done();
+        film_image(self, &film, samples_per_pixel)
+    }
+}
+
+/// A non-specular scattering event on a guided path, collecting the light that later arrives
+/// through it to train the guiding distribution.
+struct GuideVertex {
+    p: Point3,
+    wi: Vec3,
+    pdf: F,
+    /// Path throughput just after scattering here.
+    beta: Color3,
+    radiance: Color3,
+}
+
+/// Adds `contribution`, already weighted by the current path throughput, to the light arriving
+/// at every earlier guided vertex.
+fn add_guide_radiance(vertices: &mut [GuideVertex], contribution: &Color3) {
+    for v in vertices.iter_mut() {
+        for c in 0..3 {
+            if v.beta[c] > 0.0 {
+                v.radiance[c] += contribution[c] / v.beta[c];
+            }
+        }
+    }
+}
+
+impl Integrator for PathIntegrator {
+    fn preprocess(&mut self, scene: &Scene, cam: &SimpleCamera) {
+        if let Some(ref mut guide) = self.guide {
+            guide.reset(scene.world_bounds());
+        }
+    }
+
+    /// Path tracing, without training the guiding distribution; `render` does that.
+    fn li(&self, ray: &mut Ray, scene: &Scene, depth: S, rng: &RngGen) -> Color3 {
+        self.li_path(ray, scene, rng, &mut vec![])
+    }
+
+    fn progressive(&self) -> bool {
+        self.guide.is_none()
+    }
+
+    /// With guiding, renders in passes of 1, 2, 4, ... samples per pixel, refining the guiding
+    /// distribution between them. Only the final pass, which takes the remaining samples, ends
+    /// up in the image; the earlier ones are noisier for lack of good guiding.
+    fn render(
+        &self,
+        scene: &Scene,
+        cam: &SimpleCamera,
+        samples_per_pixel: S,
+        rng: &RngGen,
+    ) -> Vec<Color3> {
+        let guide = match self.guide {
+            Some(ref guide) => guide,
+            None => return render_image(self, scene, cam, samples_per_pixel, 0, rng),
+        };
+        let mut remaining = samples_per_pixel;
+        let mut pass_samples = 1;
+        let mut total_samples = 0;
+        loop {
+            // Fold the remainder into the last pass if the next one couldn't be bigger.
+            if remaining < pass_samples * 3 {
+                pass_samples = remaining;
+            }
+            let image =
+                self.render_guided_pass(guide, scene, cam, pass_samples, total_samples, rng);
+            remaining -= pass_samples;
+            total_samples += pass_samples;
+            if remaining == 0 {
+                return image;
+            }
+            guide.refine(total_samples);
+            pass_samples *= 2;
+        }
+    }
+}
+
+/// Upsamples an RGB factor such as a BSDF value or a transmittance.
+fn spectral_factor(col: &Color3, wavelengths: &SampledWavelengths) -> SampledSpectrum {
+    RgbUnboundedSpectrum::new(col).sample(wavelengths)
+}
+
+/// A wavelength dependent material's BSDF, built once for each wavelength of a path.
+type WavelengthBsdfs = [Bsdf; N_SPECTRUM_SAMPLES];
+
+/// Spectral value of the BSDF value `f`, or, given per-wavelength BSDFs, of `eval` applied to
+/// each of them at its own wavelength.
+fn spectral_bsdf_value(
+    f: &Color3,
+    bsdfs: Option<&WavelengthBsdfs>,
+    wavelengths: &SampledWavelengths,
+    eval: impl Fn(&Bsdf) -> Color3,
+) -> SampledSpectrum {
+    match bsdfs {
+        Some(bsdfs) => {
+            SampledSpectrum::from_fn(|i, _| spectral_factor(&eval(&bsdfs[i]), wavelengths)[i])
+        }
+        None => spectral_factor(f, wavelengths),
+    }
+}
+
+/// `estimate_direct` for spectral rendering, keeping the light's emission spectrum separate
+/// from the upsampled reflectance it's multiplied with.
+#[allow(clippy::borrowed_box)]
+fn estimate_direct_spectral(
+    inter: &Interaction,
+    bsdfs: Option<&WavelengthBsdfs>,
+    light: &Box<dyn Light + Send + Sync>,
+    wavelengths: &SampledWavelengths,
+    scene: &Scene,
+    rng: &RngGen,
+) -> SampledSpectrum {
+    let flags = BXDF_ALL & !BXDF_SPECULAR;
+    let u_light = rng.uniform_sample_point2();
+    let u_scattering = rng.uniform_sample_point2();
+    let wo = inter.wo.unwrap();
+    let shading_n = inter.shading.as_ref().unwrap().n;
+    let mut ld = SampledSpectrum::zeros();
+    if let Some(li) = light.sample_li(Arc::new(inter.clone()), u_light) {
+        if li.pdf > 0.0 && li.col != black() {
+            let (f, scattering_pdf) = scattering_f_pdf(inter, &li.wi, flags);
+            if f != black() {
+                let tr = li.vis.transmittance(scene, rng);
+                if tr != black() {
+                    let weight = if light.is_delta_position() {
+                        1.0
+                    } else {
+                        power_heuristic(1, li.pdf, 1, scattering_pdf)
+                    };
+                    let cos_theta = li.wi.dot(&shading_n).abs();
+                    let f = spectral_bsdf_value(&f, bsdfs, wavelengths, |bsdf| {
+                        bsdf.f(&wo, &li.wi, flags) * cos_theta
+                    });
+                    ld += f
+                        .component_mul(&spectral_factor(&tr, wavelengths))
+                        .component_mul(&light.spectral_radiance(&li.col, wavelengths))
+                        * (weight / li.pdf);
+                }
+            }
+        }
+    }
+    if light.is_delta_position() {
+        return ld;
+    }
+
+    let bsdf = inter.bsdf.as_ref().unwrap();
+    if let Some((f, scattering_pdf, wi, _)) = bsdf.sample_f(&wo, &u_scattering, flags) {
+        let f = f * wi.dot(&shading_n).abs();
+        if f != black() && scattering_pdf > 0.0 {
+            let li_pdf = light.pdf_li(inter, &wi);
+            if li_pdf == 0.0 {
+                return ld;
+            }
+            let weight = power_heuristic(1, scattering_pdf, 1, li_pdf);
+            let mut ray = inter.spawn_ray(wi);
+            let (light_inter, tr) = scene.intersect_tr(&mut ray, rng);
+            if light_inter.is_none() {
+                let li = light.le(&ray);
+                if li != black() {
+                    let cos_theta = wi.dot(&shading_n).abs();
+                    // The same sample picks the same direction from every wavelength's BSDF.
+                    let f = spectral_bsdf_value(&f, bsdfs, wavelengths, |bsdf| {
+                        bsdf.sample_f(&wo, &u_scattering, flags)
+                            .map_or(black(), |(f, _, _, _)| f)
+                            * cos_theta
+                    });
+                    ld += f
+                        .component_mul(&spectral_factor(&tr, wavelengths))
+                        .component_mul(&light.spectral_radiance(&li, wavelengths))
+                        * (weight / scattering_pdf);
+                }
+            }
+        }
+    }
+    ld
+}
+
+/// Path tracer that transports a handful of wavelengths instead of RGB. Each camera ray
+/// samples a hero wavelength plus evenly spaced companions that follow the same path;
+/// material colors are upsampled to smooth spectra, lights emit either their own spectrum
+/// or upsampled RGB, and the result is converted through CIE XYZ back to the working space.
+/// Dispersive dielectrics split the path, leaving only the hero wavelength.
+pub struct SpectralPathIntegrator {
+    max_depth: S,
+}
+
+impl SpectralPathIntegrator {
+    pub fn new(max_depth: S) -> Self {
+        Self { max_depth }
+    }
+
+    /// Radiance along `ray` at the given wavelengths.
+    pub fn li_spectral(
+        &self,
+        original_ray: &mut Ray,
+        scene: &Scene,
+        wavelengths: &mut SampledWavelengths,
+        rng: &RngGen,
+    ) -> SampledSpectrum {
+        let mut out = SampledSpectrum::zeros();
+        let mut ray = original_ray.to_owned();
+        let mut beta = sampled_spectrum(1.0);
+        let mut specular_bounce = false;
+        let mut bounces = 0;
+        loop {
+            let inter_opt = scene.intersect(&mut ray);
+            if inter_opt.is_none() {
+                if bounces == 0 || specular_bounce {
+                    for light in scene.lights.iter() {
+                        let le = light.le(&ray);
+                        if le != black() {
+                            out += beta.component_mul(&light.spectral_radiance(&le, wavelengths));
+                        }
+                    }
+                }
+                break;
+            }
+            if bounces >= self.max_depth {
+                break;
+            }
+
+            let mut inter = inter_opt.unwrap();
+            inter.scatter(&mut ray, rng);
+            if inter.bsdf.is_none() {
+                ray = inter.spawn_ray(ray.direction);
+                continue;
+            }
+            // A dispersive material sends each wavelength its own way, so only the hero
+            // wavelength can carry on, with the BSDF rebuilt for it. Other wavelength
+            // dependent materials get a BSDF for each wavelength.
+            let material = inter.primitive.as_ref().and_then(|p| p.material.clone());
+            let mut bsdfs = None;
+            if let Some(material) = material.filter(|m| m.is_wavelength_dependent()) {
+                if material.is_dispersive() {
+                    wavelengths.terminate_secondary();
+                    inter.create_bsdf();
+                    material.calculate_bsdf_at_wavelength(&mut inter, wavelengths.lambda[0]);
+                } else {
+                    bsdfs = Some(wavelengths.lambda.map(|lambda| {
+                        let mut at_wavelength = inter.clone();
+                        at_wavelength.create_bsdf();
+                        material.calculate_bsdf_at_wavelength(&mut at_wavelength, lambda);
+                        at_wavelength.bsdf.unwrap()
+                    }));
+                }
+            }
+
+            if !scene.lights.is_empty() {
+                let light_idx =
+                    ((rng.sample_0_1() * scene.lights.len() as F) as S).min(scene.lights.len() - 1);
+                let ld = estimate_direct_spectral(
+                    &inter,
+                    bsdfs.as_ref(),
+                    &scene.lights[light_idx],
+                    wavelengths,
+                    scene,
+                    rng,
+                );
+                out += beta.component_mul(&ld) * scene.lights.len() as F;
+            }
+
+            let bsdf = inter.bsdf.as_ref().unwrap();
+            let wo = -ray.direction;
+            let u = rng.uniform_sample_point2();
+            match bsdf.sample_f(&wo, &u, BXDF_ALL) {
+                Some((f, pdf, wi, flags)) if f != black() && pdf > 0.0 => {
+                    let scale = wi.dot(&inter.shading.as_ref().unwrap().n).abs() / pdf;
+                    let f = spectral_bsdf_value(&f, bsdfs.as_ref(), wavelengths, |bsdf| {
+                        bsdf.sample_f(&wo, &u, BXDF_ALL)
+                            .map_or(black(), |(f, _, _, _)| f)
+                    });
+                    beta.component_mul_assign(&(f * scale));
+                    specular_bounce = flags & BXDF_SPECULAR != 0;
+                    ray = inter.spawn_ray(wi);
+                }
+                _ => break,
+            }
+
+            if bounces > 3 {
+                let q = F::max(0.05, 1.0 - beta.max());
+                if rng.sample_0_1() < q {
+                    break;
+                }
+                beta /= 1.0 - q;
+            }
+            bounces += 1;
+        }
+        out
+    }
+}
+
+impl Integrator for SpectralPathIntegrator {
+    fn li(&self, ray: &mut Ray, scene: &Scene, depth: S, rng: &RngGen) -> Color3 {
+        let mut wavelengths = SampledWavelengths::sample_visible(rng.sample_0_1());
+        let l = self.li_spectral(ray, scene, &mut wavelengths, rng);
+        wavelengths.to_rgb(&l)
+    }
+}
+
+/// Path tracer that also accounts for participating media, sampling scattering events along
+/// rays that travel through a medium and doing next-event estimation from those points.
+pub struct VolPathIntegrator {
+    max_depth: S,
+}
+
+impl VolPathIntegrator {
+    pub fn new(max_depth: S) -> Self {
+        Self { max_depth }
+    }
+}
+
+impl Integrator for VolPathIntegrator {
+    fn li(&self, original_ray: &mut Ray, scene: &Scene, depth: S, rng: &RngGen) -> Color3 {
+        let mut out_color = black();
+        let mut ray = original_ray.to_owned();
+        let mut beta = color3(1.0, 1.0, 1.0);
+        let mut specular_bounce = false;
+        let mut bounces = 0;
+        loop {
+            let inter_opt = scene.intersect(&mut ray);
+
+            let mut medium_inter = None;
+            if let Some(ref medium) = ray.medium {
+                let (weight, mi) = medium.sample(&ray, rng);
+                beta.component_mul_assign(&weight);
+                medium_inter = mi;
+            }
+            if beta == black() {
+                break;
+            }
+
+            if let Some(mi) = medium_inter {
+                if bounces >= self.max_depth {
+                    break;
+                }
+                out_color += beta.component_mul(&uniform_sample_one_light(&mi, scene, rng));
+                let wo = -ray.direction;
+                let (wi, _) = mi
+                    .phase
+                    .as_ref()
+                    .unwrap()
+                    .sample_p(&wo, &rng.uniform_sample_point2());
+                ray = mi.spawn_ray(wi);
+                specular_bounce = false;
+            } else {
+                if (bounces == 0 || specular_bounce) && inter_opt.is_none() {
+                    for light in scene.lights.iter() {
+                        out_color += beta.component_mul(&light.le(&ray));
+                    }
+                }
+                if inter_opt.is_none() || bounces >= self.max_depth {
+                    break;
+                }
+
+                let inter = inter_opt.unwrap();
+                let bsdf = match inter.bsdf {
+                    Some(ref bsdf) => bsdf,
+                    None => {
+                        // Medium boundaries don't count as a bounce.
+                        ray = inter.spawn_ray(ray.direction);
+                        continue;
+                    }
+                };
+
+                out_color += beta.component_mul(&uniform_sample_one_light(&inter, scene, rng));
+                let wo = -ray.direction;
+                match bsdf.sample_f(&wo, &rng.uniform_sample_point2(), BXDF_ALL) {
+                    Some((f, pdf, wi, flags)) if f != black() && pdf > 0.0 => {
+                        beta.component_mul_assign(
+                            &(f * wi.dot(&inter.shading.as_ref().unwrap().n).abs() / pdf),
+                        );
+                        specular_bounce = flags & BXDF_SPECULAR != 0;
+                        ray = inter.spawn_ray(wi);
+                    }
+                    _ => break,
+                }
+            }
+
+            if bounces > 3 {
+                let q = F::max(0.05, 1.0 - beta.max());
+                if rng.sample_0_1() < q {
+                    break;
+                }
+                beta /= 1.0 - q;
+            }
+
+            bounces += 1;
+        }
+        out_color
+    }
+}
+
+/// Classic recursive ray tracing: direct lighting from every light at each hit, plus perfect
+/// specular reflection and refraction followed recursively up to `max_depth`. Glossy and
+/// diffuse interreflection is ignored, which makes it a fast preview for glass-heavy scenes.
+pub struct WhittedIntegrator {
+    max_depth: S,
+}
+
+impl WhittedIntegrator {
+    pub fn new(max_depth: S) -> Self {
+        Self { max_depth }
+    }
+}
+
+impl Integrator for WhittedIntegrator {
+    fn li(&self, ray: &mut Ray, scene: &Scene, depth: S, rng: &RngGen) -> Color3 {
+        let mut inter = match scene.intersect(ray) {
+            Some(inter) => inter,
+            None => {
+                return scene
+                    .lights
+                    .iter()
+                    .fold(black(), |col, light| col + light.le(ray))
+            }
+        };
+        let material = match inter.primitive.as_ref().and_then(|p| p.material.clone()) {
+            Some(material) => material,
+            None => {
+                // Medium boundaries don't count as a bounce.
+                let mut continued = inter.spawn_ray(ray.direction);
+                return self.li(&mut continued, scene, depth, rng);
+            }
+        };
+        // Rebuild the BSDF so that glass reflects and refracts rather than choosing between them.
+        inter.create_bsdf();
+        material.calculate_bsdf_split_specular(&mut inter);
+
+        let mut out_color = black();
+        let bsdf = inter.bsdf.as_ref().unwrap();
+        let ns = inter.shading.as_ref().unwrap().n;
+        let wo = -ray.direction.normalize();
+        let inter_arc = Arc::new(inter.clone());
+        for light in scene.lights.iter() {
+            if let Some(mut li) = light.sample_li(inter_arc.clone(), rng.uniform_sample_point2()) {
+                if li.pdf == 0.0 || li.col == black() {
+                    continue;
+                }
+                let f = bsdf.f(&wo, &li.wi, BXDF_ALL);
+                if f == black() {
+                    continue;
+                }
+                // Shadow rays pass through medium boundaries, picking up the media's transmittance.
+                li.col = li.col.component_mul(&li.vis.transmittance(scene, rng));
+                if li.col != black() {
+                    out_color += f.component_mul(&li.col) * li.wi.dot(&ns).abs() / li.pdf;
+                }
+            }
+        }
+        if depth + 1 < self.max_depth {
+            out_color += self.specular_reflect(ray, &inter, scene, depth, rng);
+            out_color += self.specular_transmit(ray, &inter, scene, depth, rng);
+        }
         out_color
     }
 }
@@ -2,8 +2,10 @@ extern crate nalgebra_glm as glm;
 
 use std::sync::Arc;
 
+use crate::bssrdf::TabulatedBssrdf;
 use crate::common::*;
 use crate::material::{Bsdf, Bxdf};
+use crate::media::{Medium, MediumInterface, PhaseFunction};
 use crate::primitive::Primitive;
 use crate::ray::Ray;
 use crate::rng::RngGen;
@@ -36,7 +38,11 @@ pub struct Interaction {
 
     pub primitive: Option<Arc<Primitive>>,
     pub bsdf: Option<Bsdf>,
-    // pub medium_interface: MediumInterface,
+    /// Set on translucent surfaces, where light can leave at another point than it entered.
+    pub bssrdf: Option<Arc<TabulatedBssrdf>>,
+    pub medium_interface: Option<MediumInterface>,
+    /// Only set for interactions inside a participating medium.
+    pub phase: Option<Arc<dyn PhaseFunction + Send + Sync>>,
 }
 
 impl Interaction {
@@ -67,8 +73,9 @@ impl Interaction {
             shading: Some(Shading { n, dpdu, dpdv }),
             primitive,
             bsdf,
-            // medium_interface,
-            // shape,
+            medium_interface: None,
+            phase: None,
+            bssrdf: None,
         };
         out.create_bsdf();
 
@@ -105,7 +112,9 @@ impl Interaction {
                 dpdv: vec3(0.0, 0.0, 0.0),
             }),
             bsdf: None,
-            // medium_interface,
+            medium_interface: None,
+            phase: None,
+            bssrdf: None,
         };
         out.create_bsdf();
         out
@@ -127,7 +136,43 @@ impl Interaction {
             shading: None,
             primitive: None,
             bsdf: None,
-            // medium_interface: MediumInterface::new_empty(),
+            medium_interface: None,
+            phase: None,
+            bssrdf: None,
+        }
+    }
+
+    /// Creates an interaction at a scattering event inside `medium`.
+    pub fn new_medium(
+        p: Point3,
+        wo: Vec3,
+        time: F,
+        medium: Option<Arc<dyn Medium + Send + Sync>>,
+        phase: Arc<dyn PhaseFunction + Send + Sync>,
+    ) -> Self {
+        Self {
+            wo: Some(wo),
+            medium_interface: Some(MediumInterface::new_non_transition(medium)),
+            phase: Some(phase),
+            bssrdf: None,
+            ..Self::new_general(p, time)
+        }
+    }
+
+    pub fn is_surface_interaction(&self) -> bool {
+        self.n.is_some()
+    }
+
+    pub fn is_medium_interaction(&self) -> bool {
+        self.phase.is_some()
+    }
+
+    /// Returns the medium on the side of the surface that `w` points into.
+    pub fn get_medium(&self, w: &Vec3) -> Option<Arc<dyn Medium + Send + Sync>> {
+        let mi = self.medium_interface.as_ref()?;
+        match self.n {
+            Some(n) if w.dot(&n) <= 0.0 => mi.inside.clone(),
+            _ => mi.outside.clone(),
         }
     }
 
@@ -159,7 +204,12 @@ impl Interaction {
     // }
 
     pub fn spawn_ray_to_point(&self, p: &Point3) -> Ray {
-        Ray::new_non_differential(self.p, p - self.p, 0.0001, 0.9999, 0.0)
+        let d = p - self.p;
+        // Keep the same absolute offset from both ends as `spawn_ray`, so short segments still
+        // step past the surfaces they start and end on. Segments too short for that, down to
+        // none at all, shrink to their midpoint rather than turning inside out.
+        let eps = F::min(0.0001 / d.magnitude(), 0.5);
+        Ray::new_non_differential(self.p, d, eps, 1.0 - eps, self.time, self.get_medium(&d))
     }
 
     pub fn spawn_ray_to(&self, other: &Interaction) -> Ray {
@@ -168,6 +218,13 @@ impl Interaction {
     }
 
     pub fn spawn_ray(&self, direction: Vec3) -> Ray {
-        Ray::new_non_differential(self.p, direction, 0.0001, F::INFINITY, self.time)
+        Ray::new_non_differential(
+            self.p,
+            direction,
+            0.0001,
+            F::INFINITY,
+            self.time,
+            self.get_medium(&direction),
+        )
     }
 }
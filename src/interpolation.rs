@@ -0,0 +1,201 @@
+use crate::common::*;
+
+/// Index `i` of the interval `[i, i + 1]` at which `pred` switches from true to false, for a
+/// predicate that's true for a prefix of `0..size`. Clamped to a valid interval.
+fn find_interval(size: S, pred: impl Fn(S) -> bool) -> S {
+    let (mut first, mut len) = (0, size);
+    while len > 0 {
+        let half = len >> 1;
+        let middle = first + half;
+        if pred(middle) {
+            first = middle + 1;
+            len -= half + 1;
+        } else {
+            len = half;
+        }
+    }
+    first.saturating_sub(1).min(size.saturating_sub(2))
+}
+
+/// Derivatives at the ends of interval `i`, scaled to its width, estimated from the
+/// neighbouring nodes or, at the ends, from the interval itself.
+fn endpoint_derivatives(x: &[F], values: &[F], i: S) -> (F, F) {
+    let n = x.len();
+    let (x0, x1) = (x[i], x[i + 1]);
+    let (f0, f1) = (values[i], values[i + 1]);
+    let width = x1 - x0;
+    let d0 = if i > 0 {
+        width * (f1 - values[i - 1]) / (x1 - x[i - 1])
+    } else {
+        f1 - f0
+    };
+    let d1 = if i + 2 < n {
+        width * (values[i + 2] - f0) / (x[i + 2] - x0)
+    } else {
+        f1 - f0
+    };
+    (d0, d1)
+}
+
+/// Weights of the four nodes around `x` for Catmull-Rom spline interpolation, and the index
+/// of the first of them (which may be -1, with a zero weight). `None` outside the nodes.
+pub fn catmull_rom_weights(nodes: &[F], x: F) -> Option<(I, [F; 4])> {
+    let size = nodes.len();
+    if !(x >= nodes[0] && x <= nodes[size - 1]) {
+        return None;
+    }
+    let idx = find_interval(size, |i| nodes[i] <= x);
+    let (x0, x1) = (nodes[idx], nodes[idx + 1]);
+    let t = (x - x0) / (x1 - x0);
+    let (t2, t3) = (t * t, t * t * t);
+    let mut weights = [0.0, 2.0 * t3 - 3.0 * t2 + 1.0, -2.0 * t3 + 3.0 * t2, 0.0];
+    if idx > 0 {
+        let w0 = (t3 - 2.0 * t2 + t) * (x1 - x0) / (x1 - nodes[idx - 1]);
+        weights[0] = -w0;
+        weights[2] += w0;
+    } else {
+        let w0 = t3 - 2.0 * t2 + t;
+        weights[1] -= w0;
+        weights[2] += w0;
+    }
+    if idx + 2 < size {
+        let w3 = (t3 - t2) * (x1 - x0) / (nodes[idx + 2] - x0);
+        weights[1] -= w3;
+        weights[3] = w3;
+    } else {
+        let w3 = t3 - t2;
+        weights[1] -= w3;
+        weights[2] += w3;
+    }
+    Some((idx as I - 1, weights))
+}
+
+/// Integrates the spline through `values` at nodes `x`, returning the integral and filling
+/// `cdf` with the running integral at each node.
+pub fn integrate_catmull_rom(x: &[F], values: &[F], cdf: &mut [F]) -> F {
+    let mut sum = 0.0;
+    cdf[0] = 0.0;
+    for i in 0..x.len() - 1 {
+        let (d0, d1) = endpoint_derivatives(x, values, i);
+        let width = x[i + 1] - x[i];
+        sum += ((d0 - d1) * (1.0 / 12.0) + (values[i] + values[i + 1]) * 0.5) * width;
+        cdf[i + 1] = sum;
+    }
+    sum
+}
+
+/// Finds where the spline through increasing `values` at nodes `x` takes the value `u`.
+pub fn invert_catmull_rom(x: &[F], values: &[F], u: F) -> F {
+    let n = values.len();
+    if u <= values[0] {
+        return x[0];
+    } else if u >= values[n - 1] {
+        return x[n - 1];
+    }
+    let i = find_interval(n, |i| values[i] <= u);
+    let (x0, x1) = (x[i], x[i + 1]);
+    let (f0, f1) = (values[i], values[i + 1]);
+    let (d0, d1) = endpoint_derivatives(x, values, i);
+
+    // Newton-bisection on the cubic over the interval.
+    let (mut a, mut b, mut t): (F, F, F) = (0.0, 1.0, 0.5);
+    loop {
+        if !(t > a && t < b) {
+            t = 0.5 * (a + b);
+        }
+        let (t2, t3) = (t * t, t * t * t);
+        let f_hat = (2.0 * t3 - 3.0 * t2 + 1.0) * f0
+            + (-2.0 * t3 + 3.0 * t2) * f1
+            + (t3 - 2.0 * t2 + t) * d0
+            + (t3 - t2) * d1;
+        let df_hat = (6.0 * t2 - 6.0 * t) * f0
+            + (-6.0 * t2 + 6.0 * t) * f1
+            + (3.0 * t2 - 4.0 * t + 1.0) * d0
+            + (3.0 * t2 - 2.0 * t) * d1;
+        if (f_hat - u).abs() < 1e-6 || b - a < 1e-6 {
+            break;
+        }
+        if f_hat - u < 0.0 {
+            a = t;
+        } else {
+            b = t;
+        }
+        t -= (f_hat - u) / df_hat;
+    }
+    x0 + t * (x1 - x0)
+}
+
+/// Samples the second dimension of a 2D spline, `values[i * nodes2.len() + j]` at
+/// `(nodes1[i], nodes2[j])`, with the first fixed at `alpha`. `cdf` holds each row's running
+/// integrals, as from `integrate_catmull_rom`. Returns the sample, the function value there
+/// and its density.
+pub fn sample_catmull_rom_2d(
+    nodes1: &[F],
+    nodes2: &[F],
+    values: &[F],
+    cdf: &[F],
+    alpha: F,
+    u: F,
+) -> Option<(F, F, F)> {
+    let size2 = nodes2.len();
+    let (offset, weights) = catmull_rom_weights(nodes1, alpha)?;
+    let interpolate = |array: &[F], idx: S| -> F {
+        (0..4)
+            .filter(|&i| weights[i] != 0.0)
+            .map(|i| array[(offset + i as I) as S * size2 + idx] * weights[i])
+            .sum()
+    };
+
+    let maximum = interpolate(cdf, size2 - 1);
+    if maximum <= 0.0 {
+        return None;
+    }
+    let u = u * maximum;
+    let idx = find_interval(size2, |i| interpolate(cdf, i) <= u);
+
+    let (f0, f1) = (interpolate(values, idx), interpolate(values, idx + 1));
+    let (x0, x1) = (nodes2[idx], nodes2[idx + 1]);
+    let width = x1 - x0;
+    let d0 = if idx > 0 {
+        width * (f1 - interpolate(values, idx - 1)) / (x1 - nodes2[idx - 1])
+    } else {
+        f1 - f0
+    };
+    let d1 = if idx + 2 < size2 {
+        width * (interpolate(values, idx + 2) - f0) / (nodes2[idx + 2] - x0)
+    } else {
+        f1 - f0
+    };
+    let u = (u - interpolate(cdf, idx)) / width;
+
+    // Invert the integral of the cubic, starting from the linear interpolant's inverse.
+    let mut t = if f0 != f1 {
+        (f0 - F::sqrt(F::max(0.0, f0 * f0 + 2.0 * u * (f1 - f0)))) / (f0 - f1)
+    } else {
+        u / f0
+    };
+    let (mut a, mut b) = (0.0, 1.0);
+    let mut f_hat;
+    loop {
+        if !(t >= a && t <= b) {
+            t = 0.5 * (a + b);
+        }
+        let big_f_hat = t
+            * (f0
+                + t * (0.5 * d0
+                    + t * ((1.0 / 3.0) * (-2.0 * d0 - d1) + f1 - f0
+                        + t * (0.25 * (d0 + d1) + 0.5 * (f0 - f1)))));
+        f_hat = f0
+            + t * (d0 + t * (-2.0 * d0 - d1 + 3.0 * (f1 - f0) + t * (d0 + d1 + 2.0 * (f0 - f1))));
+        if (big_f_hat - u).abs() < 1e-6 || b - a < 1e-6 {
+            break;
+        }
+        if big_f_hat - u < 0.0 {
+            a = t;
+        } else {
+            b = t;
+        }
+        t -= (big_f_hat - u) / f_hat;
+    }
+    Some((x0 + width * t, f_hat, f_hat / maximum))
+}
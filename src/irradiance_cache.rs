@@ -0,0 +1,469 @@
+use std::{collections::HashMap, sync::RwLock};
+
+use rayon::prelude::*;
+
+use crate::{
+    camera::SimpleCamera,
+    color::{black, color3, Color3},
+    common::*,
+    film::Film,
+    integrator::{film_image, render_tile, uniform_sample_one_light, Integrator, PathIntegrator},
+    interaction::Interaction,
+    material::{Bsdf, BXDF_ALL, BXDF_DIFFUSE, BXDF_REFLECTION, BXDF_SPECULAR},
+    progress::ProgressReporter,
+    ray::Ray,
+    rng::RngGen,
+    scene::Scene,
+    tiles,
+    vector::*,
+};
+
+/// Before the cache is used to render, records are computed for grids of pixels this far
+/// apart, from coarse to fine, with finer grids only filling in where coarser ones don't reach.
+const PREPASS_STRIDES: [S; 3] = [32, 16, 8];
+
+/// Indirect irradiance at a point, and how it changes as the point moves or its normal
+/// rotates (Ward & Heckbert, "Irradiance Gradients"). Gradients are stored per color channel.
+struct IrradianceRecord {
+    p: Point3,
+    n: Normal3,
+    e: Color3,
+    /// Harmonic mean distance to the surfaces seen from `p`, which bounds how far the record
+    /// can be reused.
+    r: F,
+    rotational: [Vec3; 3],
+    translational: [Vec3; 3],
+}
+
+fn dot_gradient(gradient: &[Vec3; 3], v: &Vec3) -> Color3 {
+    color3(gradient[0].dot(v), gradient[1].dot(v), gradient[2].dot(v))
+}
+
+fn add_gradient(gradient: &mut [Vec3; 3], v: &Vec3, weight: &Color3) {
+    for c in 0..3 {
+        gradient[c] += v * weight[c];
+    }
+}
+
+/// Records, and a uniform grid of the cells each record is valid in.
+struct RecordStore {
+    records: Vec<IrradianceRecord>,
+    cells: HashMap<[I; 3], Vec<S>>,
+}
+
+impl RecordStore {
+    fn new() -> Self {
+        Self {
+            records: vec![],
+            cells: HashMap::new(),
+        }
+    }
+}
+
+/// Sparse cache of indirect irradiance at diffuse surfaces. Irradiance varies slowly over
+/// diffuse surfaces away from corners, so a few records computed with many hemisphere rays
+/// can be interpolated over most of the image.
+///
+/// Records are only added to the cache between parallel passes, in a fixed order, and records
+/// computed meanwhile are kept in a separate store by whoever computed them, so a seeded
+/// render comes out the same however many threads build the cache.
+pub struct IrradianceCache {
+    /// How much error interpolation may introduce; smaller values place records closer.
+    max_error: F,
+    /// Hemisphere rays are stratified into `n_theta * n_phi` cells.
+    n_theta: S,
+    n_phi: S,
+    min_spacing: F,
+    max_spacing: F,
+    store: RwLock<RecordStore>,
+}
+
+impl IrradianceCache {
+    pub fn new(max_error: F, n_theta: S, n_phi: S) -> Self {
+        Self {
+            max_error,
+            n_theta,
+            n_phi,
+            min_spacing: 0.0,
+            max_spacing: F::INFINITY,
+            store: RwLock::new(RecordStore::new()),
+        }
+    }
+
+    /// Empties the cache and scales record spacing to a scene with the given radius.
+    pub fn reset(&mut self, world_radius: F) {
+        self.min_spacing = world_radius * 0.001;
+        self.max_spacing = world_radius * 0.1;
+        self.clear();
+    }
+
+    pub fn clear(&self) {
+        let mut store = self.store.write().unwrap();
+        store.records.clear();
+        store.cells.clear();
+    }
+
+    fn cell(&self, p: &Point3) -> [I; 3] {
+        let cell_size = self.max_spacing * self.max_error;
+        [0, 1, 2].map(|i| (p[i] / cell_size).floor() as I)
+    }
+
+    /// Interpolates the irradiance at `p` with normal `n` from nearby records in the cache or
+    /// in `local`, or returns `None` if none of them is close enough.
+    fn lookup(&self, p: &Point3, n: &Normal3, local: &RecordStore) -> Option<Color3> {
+        let shared = self.store.read().unwrap();
+        let cell = self.cell(p);
+        let mut sum = black();
+        let mut sum_weights = 0.0;
+        for store in [&*shared, local] {
+            let Some(candidates) = store.cells.get(&cell) else {
+                continue;
+            };
+            for &i in candidates {
+                let record = &store.records[i];
+                // Skip records in front of `p`, which see surfaces that `p` can't.
+                let d = (p - record.p).dot(&((n + record.n) / 2.0));
+                if d < -0.01 * record.r {
+                    continue;
+                }
+                let error = (p - record.p).magnitude() / record.r
+                    + F::sqrt(F::max(0.0, 1.0 - n.dot(&record.n)));
+                if error >= self.max_error {
+                    continue;
+                }
+                let weight = 1.0 / F::max(error, 1e-6);
+                let e = record.e
+                    + dot_gradient(&record.rotational, &record.n.cross(n))
+                    + dot_gradient(&record.translational, &(p - record.p));
+                sum += e.map(|c| c.max(0.0)) * weight;
+                sum_weights += weight;
+            }
+        }
+        if sum_weights == 0.0 {
+            return None;
+        }
+        Some(sum / sum_weights)
+    }
+
+    /// Adds the records of `local` to the cache.
+    fn merge(&self, local: RecordStore) {
+        let mut store = self.store.write().unwrap();
+        for record in local.records {
+            self.insert(&mut store, record);
+        }
+    }
+
+    fn insert(&self, store: &mut RecordStore, record: IrradianceRecord) {
+        let i = store.records.len();
+        // The record is only used within `max_error * r` of its position.
+        let reach = vec3(1.0, 1.0, 1.0) * record.r * self.max_error;
+        let lo = self.cell(&(record.p - reach));
+        let hi = self.cell(&(record.p + reach));
+        for z in lo[2]..=hi[2] {
+            for y in lo[1]..=hi[1] {
+                for x in lo[0]..=hi[0] {
+                    store.cells.entry([x, y, z]).or_default().push(i);
+                }
+            }
+        }
+        store.records.push(record);
+    }
+
+    /// Indirect irradiance at `inter`, interpolated from the cache or `local` if possible and
+    /// otherwise computed and added to `local`. `n` is the shading normal facing the incoming
+    /// ray, and `sign` says whether that's flipped relative to the BSDF's frame.
+    fn irradiance(
+        &self,
+        inter: &Interaction,
+        bsdf: &Bsdf,
+        n: &Normal3,
+        sign: F,
+        scene: &Scene,
+        path: &PathIntegrator,
+        rng: &RngGen,
+        local: &mut RecordStore,
+    ) -> Color3 {
+        if let Some(e) = self.lookup(&inter.p, n, local) {
+            return e;
+        }
+        let record = self.compute_record(inter, bsdf, n, sign, scene, path, rng);
+        let e = record.e;
+        self.insert(local, record);
+        e
+    }
+
+    /// Gathers incoming radiance over the hemisphere with stratified cosine-weighted rays,
+    /// estimating the gradients from how it changes between neighbouring strata.
+    fn compute_record(
+        &self,
+        inter: &Interaction,
+        bsdf: &Bsdf,
+        n: &Normal3,
+        sign: F,
+        scene: &Scene,
+        path: &PathIntegrator,
+        rng: &RngGen,
+    ) -> IrradianceRecord {
+        let (m, n_phi) = (self.n_theta, self.n_phi);
+        let mut radiance = vec![black(); m * n_phi];
+        let mut dist = vec![F::INFINITY; m * n_phi];
+        let mut e = black();
+        let mut inv_dist_sum = 0.0;
+        let mut rotational = [Vec3::zeros(); 3];
+        for k in 0..n_phi {
+            let mut rot_sum = black();
+            for j in 0..m {
+                let u = rng.uniform_sample_point2();
+                let sin_theta = F::sqrt((j as F + u.x) / m as F);
+                let cos_theta = F::sqrt(F::max(0.0, 1.0 - sin_theta * sin_theta));
+                let phi = 2.0 * PI * (k as F + u.y) / n_phi as F;
+                let wi = bsdf.local_to_world(&vec3(
+                    sin_theta * phi.cos(),
+                    sin_theta * phi.sin(),
+                    sign * cos_theta,
+                ));
+
+                // Only light reflected off other surfaces is indirect; rays escaping to the
+                // lights are accounted for by direct lighting.
+                let mut probe = inter.spawn_ray(wi);
+                if let (Some(hit), _) = scene.intersect_tr(&mut probe, rng) {
+                    let d = distance3d(&inter.p, &hit.p);
+                    let l = path.li(&mut inter.spawn_ray(wi), scene, 0, rng);
+                    radiance[j * n_phi + k] = l;
+                    dist[j * n_phi + k] = d;
+                    inv_dist_sum += 1.0 / d;
+                    e += l;
+                    rot_sum -= l * (sin_theta / cos_theta.max(1e-3));
+                }
+            }
+            let phi = 2.0 * PI * (k as F + 0.5) / n_phi as F;
+            let v_k = bsdf.local_to_world(&vec3(-phi.sin(), phi.cos(), 0.0));
+            add_gradient(&mut rotational, &v_k, &rot_sum);
+        }
+        let scale = PI / (m * n_phi) as F;
+        e *= scale;
+        for g in rotational.iter_mut() {
+            *g *= scale;
+        }
+
+        let mut translational = [Vec3::zeros(); 3];
+        for k in 0..n_phi {
+            let phi = 2.0 * PI * (k as F + 0.5) / n_phi as F;
+            let u_k = bsdf.local_to_world(&vec3(phi.cos(), phi.sin(), 0.0));
+            let phi_minus = 2.0 * PI * k as F / n_phi as F;
+            let v_k_minus = bsdf.local_to_world(&vec3(-phi_minus.sin(), phi_minus.cos(), 0.0));
+            let k_prev = (k + n_phi - 1) % n_phi;
+
+            // Change across the boundaries between rings of constant theta.
+            let mut theta_sum = black();
+            for j in 1..m {
+                let sin_theta_minus = F::sqrt(j as F / m as F);
+                let cos2_theta_minus = 1.0 - sin_theta_minus * sin_theta_minus;
+                let r = F::min(dist[j * n_phi + k], dist[(j - 1) * n_phi + k]);
+                theta_sum += (radiance[j * n_phi + k] - radiance[(j - 1) * n_phi + k])
+                    * (sin_theta_minus * cos2_theta_minus / r);
+            }
+            add_gradient(
+                &mut translational,
+                &u_k,
+                &(theta_sum * (2.0 * PI / n_phi as F)),
+            );
+
+            // Change across the boundaries between wedges of constant phi.
+            let mut phi_sum = black();
+            for j in 0..m {
+                let cos_theta_minus = F::sqrt(1.0 - j as F / m as F);
+                let cos_theta_plus = F::sqrt(1.0 - (j + 1) as F / m as F);
+                let sin_theta_center = F::sqrt((j as F + 0.5) / m as F);
+                let r = F::min(dist[j * n_phi + k], dist[j * n_phi + k_prev]);
+                phi_sum += (radiance[j * n_phi + k] - radiance[j * n_phi + k_prev])
+                    * ((cos_theta_minus - cos_theta_plus) / (sin_theta_center * r));
+            }
+            add_gradient(&mut translational, &v_k_minus, &phi_sum);
+        }
+
+        let mut r = if inv_dist_sum > 0.0 {
+            (m * n_phi) as F / inv_dist_sum
+        } else {
+            F::INFINITY
+        };
+        // Don't extrapolate the gradient past where the irradiance would hit zero.
+        let gradient_length = translational
+            .iter()
+            .map(|g| g.magnitude())
+            .fold(0.0, F::max);
+        if gradient_length > 0.0 {
+            r = r.min(e.max() / gradient_length);
+        }
+        IrradianceRecord {
+            p: inter.p,
+            n: *n,
+            e,
+            r: r.clamp(self.min_spacing, self.max_spacing),
+            rotational,
+            translational,
+        }
+    }
+}
+
+/// Path tracer that can use an irradiance cache for the indirect light at diffuse surfaces,
+/// as a fast preview of global illumination. Camera rays are followed through specular
+/// bounces; at the first diffuse surface direct lighting is sampled as usual and indirect
+/// lighting comes from the cache. The same scene rendered with `PathIntegrator` shows what
+/// the cache approximates.
+pub struct IrradianceCacheIntegrator {
+    max_depth: S,
+    /// Computes the radiance arriving along hemisphere rays when building records.
+    path: PathIntegrator,
+    cache: IrradianceCache,
+}
+
+impl IrradianceCacheIntegrator {
+    pub fn new(max_depth: S, max_error: F, n_theta: S, n_phi: S) -> Self {
+        Self {
+            max_depth,
+            path: PathIntegrator::new(max_depth.saturating_sub(1)),
+            cache: IrradianceCache::new(max_error, n_theta, n_phi),
+        }
+    }
+
+    /// Radiance along `original_ray`, with records that the cache is missing computed into
+    /// `records`.
+    fn li_cached(
+        &self,
+        original_ray: &mut Ray,
+        scene: &Scene,
+        rng: &RngGen,
+        records: &mut RecordStore,
+    ) -> Color3 {
+        let mut out_color = black();
+        let mut ray = original_ray.to_owned();
+        let mut beta = color3(1.0, 1.0, 1.0);
+        let mut bounces = 0;
+        loop {
+            let inter = match scene.intersect(&mut ray) {
+                Some(inter) => inter,
+                None => {
+                    // Only reached directly or through specular bounces.
+                    for light in scene.lights.iter() {
+                        out_color += beta.component_mul(&light.le(&ray));
+                    }
+                    break;
+                }
+            };
+            let bsdf = match inter.bsdf {
+                Some(ref bsdf) => bsdf,
+                None => {
+                    // Medium boundaries don't count as a bounce.
+                    ray = inter.spawn_ray(ray.direction);
+                    continue;
+                }
+            };
+            if bounces >= self.max_depth {
+                break;
+            }
+
+            out_color += beta.component_mul(&uniform_sample_one_light(&inter, scene, rng));
+            let wo = -ray.direction.normalize();
+            if bsdf.num_components(BXDF_DIFFUSE | BXDF_REFLECTION) > 0 {
+                let ns = inter.shading.as_ref().unwrap().n.normalize();
+                let sign = if ns.dot(&wo) < 0.0 { -1.0 } else { 1.0 };
+                let n = ns * sign;
+                let e = self
+                    .cache
+                    .irradiance(&inter, bsdf, &n, sign, scene, &self.path, rng, records);
+                let f = bsdf.f(&wo, &n, BXDF_DIFFUSE | BXDF_REFLECTION);
+                out_color += beta.component_mul(&f.component_mul(&e));
+                break;
+            }
+
+            match bsdf.sample_f(&wo, &rng.uniform_sample_point2(), BXDF_ALL) {
+                Some((f, pdf, wi, flags)) if f != black() && pdf > 0.0 => {
+                    if flags & BXDF_SPECULAR == 0 {
+                        break;
+                    }
+                    beta.component_mul_assign(
+                        &(f * wi.dot(&inter.shading.as_ref().unwrap().n).abs() / pdf),
+                    );
+                    ray = inter.spawn_ray(wi);
+                }
+                _ => break,
+            }
+            bounces += 1;
+        }
+        out_color
+    }
+}
+
+impl Integrator for IrradianceCacheIntegrator {
+    fn preprocess(&mut self, scene: &Scene, cam: &SimpleCamera) {
+        self.cache.reset(scene.world_bounds().bounding_sphere().1);
+    }
+
+    /// Records computed for a lone sample are only used by it.
+    fn li(&self, ray: &mut Ray, scene: &Scene, depth: S, rng: &RngGen) -> Color3 {
+        self.li_cached(ray, scene, rng, &mut RecordStore::new())
+    }
+
+    /// The cache is built before rendering, so the image is only rendered in one go.
+    fn progressive(&self) -> bool {
+        false
+    }
+
+    /// Rebuilds the cache for every render: records are first computed in parallel for sparse
+    /// grids of pixels, then the image is rendered, interpolating between them. Each tile adds
+    /// records of its own wherever they don't reach, which are only used within the tile.
+    fn render(
+        &self,
+        scene: &Scene,
+        cam: &SimpleCamera,
+        samples_per_pixel: S,
+        rng: &RngGen,
+    ) -> Vec<Color3> {
+        self.cache.clear();
+        let (x0, y0, x1, y1) = cam.pixel_bounds();
+        let mut first_sequence = 0;
+        for stride in PREPASS_STRIDES {
+            // Points of the same grid don't see each other's records, which are only added to
+            // the cache once the whole grid is done.
+            let (nx, ny) = ((x1 - x0) / stride, (y1 - y0) / stride);
+            let grid_records: Vec<RecordStore> = (0..nx * ny)
+                .into_par_iter()
+                .map(|i| {
+                    let x = x0 + (i % nx) * stride + stride / 2;
+                    let y = y0 + (i / nx) * stride + stride / 2;
+                    rng.start_sequence(first_sequence + i);
+                    let mut ray = cam.get_ray(point2(x as F, y as F));
+                    let mut records = RecordStore::new();
+                    self.li_cached(&mut ray, scene, rng, &mut records);
+                    records
+                })
+                .collect();
+            for records in grid_records {
+                self.cache.merge(records);
+            }
+            first_sequence += nx * ny;
+        }
+
+        let film = Film::new(cam.pixel_bounds(), cam.filter.clone());
+        let progress = ProgressReporter::new(tiles::tiles(film.bounds).len(), "Rendering");
+        tiles::for_each_tile(
+            film.bounds,
+            |t| {
+                let (x0, y0, x1, y1) = t.bounds;
+                let mut tile = film.tile(x0, y0, x1, y1);
+                let mut records = RecordStore::new();
+                render_tile(&mut tile, cam, samples_per_pixel, 0, rng, |ray| {
+                    self.li_cached(ray, scene, rng, &mut records)
+                });
+                tile
+            },
+            |_, tile| {
+                film.merge_tile(tile);
+                progress.update(1);
+            },
+        );
+        progress.done();
+        film_image(self, &film, samples_per_pixel)
+    }
+}
@@ -2,14 +2,22 @@ use std::sync::Arc;
 
 use crate::{
     aabb::AABB3,
-    color::{black, color3, Color3},
+    color::{black, color3, luminance, Color3},
     common::{F, PI, S},
-    distributions::Distribution2D,
+    distributions::{Distribution1D, Distribution2D},
     interaction::Interaction,
     ray::Ray,
+    rng::RngGen,
     scene::Scene,
+    spectrum::{
+        spectrum_luminance, spectrum_to_rgb, RgbIlluminantSpectrum, SampledSpectrum,
+        SampledWavelengths, Spectrum,
+    },
     transform::Transform,
-    vector::{point2, point3, spherical_phi, spherical_theta, vec3, Point2, Point3, Vec3},
+    vector::{
+        coordinate_system, point2, point3, spherical_phi, spherical_theta, vec3, Normal3, Point2,
+        Point3, Vec3,
+    },
 };
 
 pub struct VisibilityTester {
@@ -22,23 +30,26 @@ impl VisibilityTester {
         !scene.intersect_p(&self.p0.spawn_ray_to(&self.p1))
     }
 
-    /// TODO: implement for translucent materials
-    pub fn transmittance(&self, scene: &Scene) -> Color3 {
-        // let mut ray = self.p0.spawn_ray_to(&self.p1);
-        // let mut tr = color3(1.0,1.0,1.0);
-        // loop { // TODO: this might need fixing
-        //     if let Some(inter) = scene.intersect(&mut ray) {
-        //         if let Some(prim) = inter.primitive {
-        //             if let Some(_) = prim.material {
-        //                 return black();
-        //             }
-        //         }
-        //         ray = inter.spawn_ray_to(&&self.p1);
-        //     } else {
-        //         return tr;
-        //     }
-        // }
-        black()
+    /// Computes the fraction of light that makes it from `p1` to `p0`, passing through
+    /// surfaces without a BSDF (medium boundaries) and attenuating by any media in between.
+    pub fn transmittance(&self, scene: &Scene, rng: &RngGen) -> Color3 {
+        let mut ray = self.p0.spawn_ray_to(&self.p1);
+        let mut tr = color3(1.0, 1.0, 1.0);
+        loop {
+            let inter = scene.intersect(&mut ray);
+            if let Some(ref inter) = inter {
+                if inter.bsdf.is_some() {
+                    return black();
+                }
+            }
+            if let Some(ref medium) = ray.medium {
+                tr.component_mul_assign(&medium.transmittance(&ray, rng));
+            }
+            match inter {
+                Some(inter) => ray = inter.spawn_ray_to(&self.p1),
+                None => return tr,
+            }
+        }
     }
 }
 
@@ -49,21 +60,75 @@ pub struct LiResult {
     pub pdf: F,
 }
 
+/// A ray leaving a light, as sampled for tracing paths that start at the light.
+pub struct LeResult {
+    pub col: Color3,
+    pub ray: Ray,
+    pub n_light: Normal3,
+    pub pdf_pos: F,
+    pub pdf_dir: F,
+}
+
 pub trait Light {
     fn num_samples(&self) -> S {
         1
     }
     fn light_to_world(&self) -> Transform;
     fn maybe_set_bounds(&mut self, world_bounds: &AABB3) {}
+    /// Whether the light is described by a delta distribution in position, meaning it can
+    /// only be reached by sampling it directly.
+    fn is_delta_position(&self) -> bool {
+        false
+    }
+    /// Whether the light surrounds the whole scene and is reached by rays that escape it.
+    fn is_infinite(&self) -> bool {
+        false
+    }
     fn sample_li(&self, inter: Arc<Interaction>, u: Point2) -> Option<LiResult>;
     fn pdf_li(&self, inter: &Interaction, w: &Vec3) -> F {
         0.0
     }
+    /// Samples a ray leaving the light, returning the densities of its origin (per unit area)
+    /// and direction (per unit solid angle) separately.
+    fn sample_le(&self, u1: Point2, u2: Point2, time: F) -> Option<LeResult>;
+    /// Returns the positional and directional densities `sample_le` would produce `ray` with.
+    fn pdf_le(&self, ray: &Ray, n_light: &Normal3) -> (F, F);
     fn power(&self) -> Color3;
     fn brightness(&self) -> F;
     fn le(&self, ray: &Ray) -> Color3 {
         black()
     }
+    /// Spectral radiance at the given wavelengths for light this light returned as `col`.
+    /// By default the RGB value is upsampled as an illuminant.
+    fn spectral_radiance(&self, col: &Color3, wavelengths: &SampledWavelengths) -> SampledSpectrum {
+        RgbIlluminantSpectrum::new(col).sample(wavelengths)
+    }
+}
+
+/// An emission spectrum in place of an RGB color, and the RGB the light uses for it.
+pub struct SpectralEmission {
+    spectrum: Arc<dyn Spectrum + Send + Sync>,
+    rgb: Color3,
+    luminance: F,
+}
+
+impl SpectralEmission {
+    fn new(spectrum: Arc<dyn Spectrum + Send + Sync>) -> Self {
+        Self {
+            rgb: spectrum_to_rgb(spectrum.as_ref()),
+            luminance: spectrum_luminance(spectrum.as_ref()),
+            spectrum,
+        }
+    }
+
+    /// The spectrum scaled to the brightness of `col`, which is the emission's RGB after
+    /// any scaling by the light.
+    fn radiance(&self, col: &Color3, wavelengths: &SampledWavelengths) -> SampledSpectrum {
+        if self.luminance <= 0.0 {
+            return SampledSpectrum::zeros();
+        }
+        self.spectrum.sample(wavelengths) * (luminance(col) / self.luminance)
+    }
 }
 
 pub struct PointLight {
@@ -71,6 +136,7 @@ pub struct PointLight {
     position: Point3,
     intensity: Color3,
     brightness: F,
+    emission: Option<SpectralEmission>,
 }
 
 impl PointLight {
@@ -80,6 +146,22 @@ impl PointLight {
             intensity,
             position: light_to_world.fpt(point3(0.0, 0.0, 0.0)),
             brightness,
+            emission: None,
+        }
+    }
+
+    /// A point light emitting `spectrum`, e.g. a `BlackbodySpectrum`. RGB renderers see its
+    /// color converted to the working space.
+    pub fn new_spectral(
+        light_to_world: Transform,
+        spectrum: Arc<dyn Spectrum + Send + Sync>,
+        brightness: F,
+    ) -> Self {
+        let emission = SpectralEmission::new(spectrum);
+        Self {
+            intensity: emission.rgb,
+            emission: Some(emission),
+            ..Self::new(light_to_world, black(), brightness)
         }
     }
 }
@@ -89,6 +171,10 @@ impl Light for PointLight {
         self.light_to_world
     }
 
+    fn is_delta_position(&self) -> bool {
+        true
+    }
+
     fn sample_li(&self, inter: Arc<Interaction>, u: Point2) -> Option<LiResult> {
         let wi = (self.position - inter.p).normalize();
         let pdf = 1.0;
@@ -100,6 +186,23 @@ impl Light for PointLight {
         Some(LiResult { col, vis, wi, pdf })
     }
 
+    fn sample_le(&self, u1: Point2, u2: Point2, time: F) -> Option<LeResult> {
+        let direction = Distribution1D::uniform_sample_sphere(&u1);
+        let ray =
+            Ray::new_non_differential(self.position, direction, 0.0001, F::INFINITY, time, None);
+        Some(LeResult {
+            col: self.intensity * self.brightness,
+            ray,
+            n_light: direction,
+            pdf_pos: 1.0,
+            pdf_dir: Distribution1D::uniform_sphere_pdf(),
+        })
+    }
+
+    fn pdf_le(&self, ray: &Ray, n_light: &Normal3) -> (F, F) {
+        (0.0, Distribution1D::uniform_sphere_pdf())
+    }
+
     fn power(&self) -> Color3 {
         4.0 * PI * self.intensity
     }
@@ -107,6 +210,13 @@ impl Light for PointLight {
     fn brightness(&self) -> F {
         self.brightness
     }
+
+    fn spectral_radiance(&self, col: &Color3, wavelengths: &SampledWavelengths) -> SampledSpectrum {
+        match self.emission {
+            Some(ref emission) => emission.radiance(col, wavelengths),
+            None => RgbIlluminantSpectrum::new(col).sample(wavelengths),
+        }
+    }
 }
 
 pub struct ConstantInfiniteLight {
@@ -116,6 +226,7 @@ pub struct ConstantInfiniteLight {
     world_radius: Option<F>,
     distr: Distribution2D,
     brightness: F,
+    emission: Option<SpectralEmission>,
 }
 
 impl ConstantInfiniteLight {
@@ -127,6 +238,22 @@ impl ConstantInfiniteLight {
             world_radius: None,
             distr: Distribution2D::new(&[&[1.0]]),
             brightness,
+            emission: None,
+        }
+    }
+
+    /// An environment emitting `spectrum` from every direction. RGB renderers see its color
+    /// converted to the working space.
+    pub fn new_spectral(
+        light_to_world: Transform,
+        spectrum: Arc<dyn Spectrum + Send + Sync>,
+        brightness: F,
+    ) -> Self {
+        let emission = SpectralEmission::new(spectrum);
+        Self {
+            intensity: emission.rgb,
+            emission: Some(emission),
+            ..Self::new(light_to_world, black(), brightness)
         }
     }
 }
@@ -136,9 +263,13 @@ impl Light for ConstantInfiniteLight {
         self.light_to_world
     }
 
+    fn is_infinite(&self) -> bool {
+        true
+    }
+
     fn le(&self, ray: &Ray) -> Color3 {
         // let w = self.light_to_world.ivec(ray.d).normalize();
-        self.intensity
+        self.intensity * self.brightness
     }
 
     fn maybe_set_bounds(&mut self, world_bounds: &AABB3) {
@@ -182,12 +313,11 @@ impl Light for ConstantInfiniteLight {
                     sin_theta * sin_phi,
                     cos_theta,
                 ));
-                let pdf;
-                if sin_theta == 0.0 {
-                    pdf = 0.0
+                let pdf = if sin_theta == 0.0 {
+                    0.0
                 } else {
-                    pdf = map_pdf / (2.0 * PI * PI * sin_theta);
-                }
+                    map_pdf / (2.0 * PI * PI * sin_theta)
+                };
                 let vis = VisibilityTester {
                     p0: inter.clone(),
                     p1: Arc::new(Interaction::new_general(
@@ -217,12 +347,69 @@ impl Light for ConstantInfiniteLight {
         if sin_theta == 0.0 {
             0.0
         } else {
-            self.distr
-                .pdf(point2(phi / (2.0 * PI), theta / (2.0 * PI)) / (2.0 * PI * PI * sin_theta))
+            self.distr.pdf(point2(phi / (2.0 * PI), theta / PI)) / (2.0 * PI * PI * sin_theta)
+        }
+    }
+
+    fn sample_le(&self, u1: Point2, u2: Point2, time: F) -> Option<LeResult> {
+        let (center, radius) = match (self.world_center, self.world_radius) {
+            (Some(center), Some(radius)) => (center, radius),
+            _ => panic!("Uninitialized ConstantInfiniteLight is trying to be used! Did you call light.preprocess()?"),
+        };
+        let (uv, map_pdf) = self.distr.sample_continuous(&u1)?;
+        if map_pdf == 0.0 {
+            return None;
+        }
+        let theta = uv[1] * PI;
+        let phi = uv[0] * 2.0 * PI;
+        let sin_theta = theta.sin();
+        if sin_theta == 0.0 {
+            return None;
         }
+        let d = -self.light_to_world.fvec(&vec3(
+            sin_theta * phi.cos(),
+            sin_theta * phi.sin(),
+            theta.cos(),
+        ));
+        // Start the ray on a disk facing the sampled direction, just outside the scene's
+        // bounding sphere.
+        let (v1, v2) = coordinate_system(&-d);
+        let cd = Distribution1D::concentric_sample_disk(&u2);
+        let p_disk = center + radius * (cd.x * v1 + cd.y * v2);
+        let ray =
+            Ray::new_non_differential(p_disk + radius * -d, d, 0.0001, F::INFINITY, time, None);
+        Some(LeResult {
+            col: self.intensity * self.brightness,
+            ray,
+            n_light: d,
+            pdf_pos: 1.0 / (PI * radius * radius),
+            pdf_dir: map_pdf / (2.0 * PI * PI * sin_theta),
+        })
+    }
+
+    fn pdf_le(&self, ray: &Ray, n_light: &Normal3) -> (F, F) {
+        let radius = self.world_radius.expect(
+            "Uninitialized ConstantInfiniteLight is trying to be used! Did you call light.preprocess()?",
+        );
+        let wi = self.light_to_world.ivec(&-ray.direction);
+        let theta = spherical_theta(&wi);
+        let phi = spherical_phi(&wi);
+        let sin_theta = theta.sin();
+        let pdf_dir = if sin_theta == 0.0 {
+            0.0
+        } else {
+            self.distr.pdf(point2(phi / (2.0 * PI), theta / PI)) / (2.0 * PI * PI * sin_theta)
+        };
+        (1.0 / (PI * radius * radius), pdf_dir)
     }
 
     fn brightness(&self) -> F {
         self.brightness
     }
+    fn spectral_radiance(&self, col: &Color3, wavelengths: &SampledWavelengths) -> SampledSpectrum {
+        match self.emission {
+            Some(ref emission) => emission.radiance(col, wavelengths),
+            None => RgbIlluminantSpectrum::new(col).sample(wavelengths),
+        }
+    }
 }
@@ -2,83 +2,122 @@
 #![allow(unused_variables)]
 #![allow(clippy::too_many_arguments)]
 mod aabb;
+mod bdpt;
+mod bssrdf;
 mod camera;
 mod color;
+mod colorspace;
 mod common;
+mod conductors;
+mod debug;
 mod integrator;
 mod interaction;
+mod interpolation;
+mod irradiance_cache;
 mod light;
 mod material;
 mod matrix;
 // mod mesh;
 mod distributions;
+mod film;
+mod guiding;
+mod imageio;
 mod media;
+mod mlt;
+mod navigation;
 mod onb;
 mod primitive;
+mod progress;
+mod progressive;
 mod quaternion;
 mod ray;
 mod rng;
+mod sampler;
 mod scene;
 mod shape;
+mod spectrum;
 mod sphere;
+mod sppm;
 mod texture;
+mod tiles;
+mod tonemap;
 mod transform;
 mod vector;
 
+use clap::{Parser, ValueEnum};
 use rayon::prelude::*;
 use winit::{
     dpi::LogicalSize,
-    event::VirtualKeyCode,
+    event::{Event, VirtualKeyCode},
     event_loop::{ControlFlow, EventLoop},
     window::WindowBuilder,
 };
 use winit_input_helper::WinitInputHelper;
 
-use std::{sync::Arc, time::Instant};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
+use bdpt::BdptIntegrator;
 use camera::SimpleCamera;
-use integrator::{Integrator, PathIntegrator};
+use colorspace::ColorSpace;
+use debug::{AoIntegrator, DebugIntegrator, DebugMode};
+use imageio::{ExrPixelType, ImageLayer};
+use integrator::{
+    Integrator, PathIntegrator, SpectralPathIntegrator, VolPathIntegrator, WhittedIntegrator,
+};
+use irradiance_cache::IrradianceCacheIntegrator;
 use light::ConstantInfiniteLight;
 use material::Matte;
 use media::MediumInterface;
+use mlt::MltIntegrator;
+use navigation::CameraController;
 use pixels::{Pixels, SurfaceTexture};
 use primitive::Primitive;
+use progress::ProgressReporter;
+use progressive::ProgressiveRender;
 use rayon::iter::{IndexedParallelIterator, ParallelIterator};
 use rng::RngGen;
 use scene::Scene;
 use sphere::Sphere;
+use sppm::SppmIntegrator;
 use texture::{ConstantValue, SolidColor};
+use tiles::TileOrder;
+use tonemap::{ToneMapOperator, ToneMapper};
 use transform::Transform;
 
 use color::*;
 use common::*;
 use vector::*;
 
-const WIDTH: S = 640;
-const HEIGHT: S = 400;
-const ASPECT_RATIO: F = (WIDTH as F) / (HEIGHT as F);
-
 struct World
 where
     Self: Send + Sync,
 {
     pub scene: Scene,
     pub cam: SimpleCamera,
-    pub integrator: PathIntegrator,
+    pub integrator: Box<dyn Integrator + Send + Sync>,
     // max_depth: S,
     samples_per_pixel: S,
     rng: RngGen,
 }
 
 impl World {
-    pub fn new(scene: Scene, cam: SimpleCamera, max_depth: S, samples_per_pixel: S) -> Self {
+    pub fn new(
+        scene: Scene,
+        cam: SimpleCamera,
+        integrator: Box<dyn Integrator + Send + Sync>,
+        samples_per_pixel: S,
+    ) -> Self {
         Self {
             scene,
             cam,
             // max_depth,
             samples_per_pixel,
             rng: RngGen::new(),
-            integrator: PathIntegrator::new(max_depth),
+            integrator,
         }
     }
 
@@ -88,41 +127,261 @@ impl World {
     }
 
     pub fn render_pixel(&self, x: S, y: S) -> Color3 {
-        let mut out_col = black();
-        for _ in 0..self.samples_per_pixel {
-            let mut ray = self.cam.get_ray(point2(
-                x as F + self.rng.sample_neg1_1(),
-                y as F + self.rng.sample_neg1_1(),
-            ));
-            let col = self.integrator.li(&mut ray, &self.scene, 0, &self.rng);
-            out_col += col / self.samples_per_pixel as F;
+        integrator::render_pixel(
+            self.integrator.as_ref(),
+            &self.scene,
+            &self.cam,
+            x,
+            y,
+            self.samples_per_pixel,
+            &self.rng,
+        )
+    }
+
+    pub fn render(&self) -> Vec<Color3> {
+        self.integrator
+            .render(&self.scene, &self.cam, self.samples_per_pixel, &self.rng)
+    }
+
+    /// Renders a debug view of the scene in place of the integrator, such as to save
+    /// alongside the image.
+    pub fn render_debug(&self, mode: DebugMode) -> Vec<Color3> {
+        let mut integrator = DebugIntegrator::new(mode);
+        integrator.preprocess(&self.scene, &self.cam);
+        integrator.render(&self.scene, &self.cam, self.samples_per_pixel, &self.rng)
+    }
+}
+
+/// Keys selecting each `IntegratorKind` in the window, in order.
+const INTEGRATOR_KEYS: [VirtualKeyCode; 10] = [
+    VirtualKeyCode::Key1,
+    VirtualKeyCode::Key2,
+    VirtualKeyCode::Key3,
+    VirtualKeyCode::Key4,
+    VirtualKeyCode::Key5,
+    VirtualKeyCode::Key6,
+    VirtualKeyCode::Key7,
+    VirtualKeyCode::Key8,
+    VirtualKeyCode::Key9,
+    VirtualKeyCode::Key0,
+];
+
+/// The views the window cycles through in place of the integrator.
+const DEBUG_VIEWS: [DebugMode; 7] = [
+    DebugMode::ShadingNormal,
+    DebugMode::GeometricNormal,
+    DebugMode::Uv,
+    DebugMode::Depth,
+    DebugMode::PrimitiveId,
+    DebugMode::MaterialId,
+    DebugMode::BsdfCount,
+];
+
+/// Saves the image shown in the window, reporting rather than failing on errors.
+fn save_frame(
+    path: &Path,
+    image: &[Color3],
+    width: S,
+    height: S,
+    tone_map: &ToneMapper,
+    exr_pixel_type: ExrPixelType,
+) {
+    match imageio::write_image_layers(path, image, &[], width, height, tone_map, exr_pixel_type) {
+        Ok(()) => eprintln!("Saved {}", path.display()),
+        Err(err) => log::error!("saving {} failed: {}", path.display(), err),
+    }
+}
+
+/// Shows a rendered image in the window.
+fn draw(pixels: &mut Pixels, image: &[Color3], tone_map: &ToneMapper) {
+    pixels
+        .frame_mut()
+        .par_chunks_exact_mut(4)
+        .zip(image.par_iter())
+        .for_each(|(pixel, col)| {
+            pixel.copy_from_slice(&tone_map.to_pixel(col));
+        });
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum IntegratorKind {
+    Path,
+    /// Path tracing with path guiding.
+    Guided,
+    /// Path tracing through participating media.
+    Volpath,
+    /// Spectral path tracing.
+    Spectral,
+    Bdpt,
+    /// Metropolis light transport over bidirectional paths.
+    Mlt,
+    /// Stochastic progressive photon mapping.
+    Sppm,
+    Whitted,
+    IrradianceCache,
+    /// Ambient occlusion.
+    Ao,
+}
+
+impl IntegratorKind {
+    fn build(self, max_depth: S) -> Box<dyn Integrator + Send + Sync> {
+        match self {
+            IntegratorKind::Path => Box::new(PathIntegrator::new(max_depth)),
+            IntegratorKind::Guided => Box::new(PathIntegrator::new_guided(max_depth, 0.5)),
+            IntegratorKind::Volpath => Box::new(VolPathIntegrator::new(max_depth)),
+            IntegratorKind::Spectral => Box::new(SpectralPathIntegrator::new(max_depth)),
+            IntegratorKind::Bdpt => Box::new(BdptIntegrator::new(max_depth)),
+            IntegratorKind::Mlt => Box::new(MltIntegrator::new(
+                Box::new(BdptIntegrator::new(max_depth)),
+                100000,
+                1000,
+                0.01,
+                0.3,
+            )),
+            IntegratorKind::Sppm => Box::new(SppmIntegrator::new(max_depth, 200000, 0.3)),
+            IntegratorKind::Whitted => Box::new(WhittedIntegrator::new(max_depth)),
+            IntegratorKind::IrradianceCache => {
+                Box::new(IrradianceCacheIntegrator::new(max_depth, 0.3, 8, 24))
+            }
+            IntegratorKind::Ao => Box::new(AoIntegrator::new(16, 5.0)),
         }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ColorSpaceKind {
+    /// sRGB or Rec.709.
+    Srgb,
+    Rec2020,
+    Acescg,
+    DisplayP3,
+}
 
-        out_col
+impl ColorSpaceKind {
+    fn space(self) -> &'static ColorSpace {
+        match self {
+            ColorSpaceKind::Srgb => ColorSpace::srgb(),
+            ColorSpaceKind::Rec2020 => ColorSpace::rec2020(),
+            ColorSpaceKind::Acescg => ColorSpace::aces_cg(),
+            ColorSpaceKind::DisplayP3 => ColorSpace::display_p3(),
+        }
     }
 }
 
+/// Renders the scene in a preview window, or with `--headless`, straight to an image file.
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Args {
+    /// Render without opening a window, saving the image to `--output`.
+    #[arg(long)]
+    headless: bool,
+    /// Where to save the render. The format follows the extension: png, ppm, pfm or exr.
+    /// Defaults to render.png when headless.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Save exr files with 32-bit rather than 16-bit floats.
+    #[arg(long)]
+    exr_float: bool,
+    /// A debug view to save as an extra layer of the headless render's exr file, e.g. normals
+    /// or depth for compositing. Repeat for more layers.
+    #[arg(long = "layer", value_enum)]
+    layers: Vec<DebugMode>,
+    /// Image width in pixels.
+    #[arg(long, default_value_t = 640)]
+    width: S,
+    /// Image height in pixels.
+    #[arg(long, default_value_t = 400)]
+    height: S,
+    /// Width over height of each pixel.
+    #[arg(long, default_value_t = 1.0)]
+    pixel_aspect: F,
+    /// Render only part of the image, given as fractions of its width and height from the
+    /// top left: x_min,x_max,y_min,y_max.
+    #[arg(long, value_delimiter = ',', num_args = 4)]
+    crop: Option<Vec<F>>,
+    /// Samples per pixel.
+    #[arg(long, default_value_t = 100)]
+    spp: S,
+    /// Samples per pixel added by each pass of the window's progressive preview. Zero renders
+    /// the whole image before showing it.
+    #[arg(long, default_value_t = 1)]
+    pass_spp: S,
+    /// Stop refining the image after this many seconds, even if short of `--spp`. Headless
+    /// renders then refine it progressively too. Integrators that only render in one go, and
+    /// `--pass-spp 0`, can't be stopped early.
+    #[arg(long)]
+    time_limit: Option<F>,
+    /// Maximum number of bounces.
+    #[arg(long, default_value_t = 8)]
+    max_depth: S,
+    #[arg(long, value_enum, default_value_t = IntegratorKind::Path)]
+    integrator: IntegratorKind,
+    /// The RGB space to render in, which pfm and exr files are saved in too.
+    #[arg(long, value_enum, default_value_t = ColorSpaceKind::Srgb)]
+    color_space: ColorSpaceKind,
+    /// How to fit the render's brightness range to the display and to png or ppm files.
+    #[arg(long, value_enum, default_value_t = ToneMapOperator::Linear)]
+    tonemap: ToneMapOperator,
+    /// Exposure compensation in stops.
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    exposure: F,
+    /// Color temperature in Kelvin to show as white, e.g. 3200 for tungsten lighting.
+    #[arg(long)]
+    white_balance: Option<F>,
+    /// Luminance shown as white by the extended Reinhard operator.
+    #[arg(long, default_value_t = 4.0)]
+    white_point: F,
+    /// Width and height in pixels of the tiles handed to each render thread.
+    #[arg(long, default_value_t = 16)]
+    tile_size: S,
+    /// The order tiles are rendered in.
+    #[arg(long, value_enum, default_value_t = TileOrder::Hilbert)]
+    tile_order: TileOrder,
+    /// Number of render threads. Defaults to one per core.
+    #[arg(long)]
+    threads: Option<S>,
+    /// Seed for a repeatable render. Random by default.
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Don't report progress.
+    #[arg(short, long)]
+    quiet: bool,
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
 
-    let event_loop = EventLoop::new();
-    let mut input = WinitInputHelper::new();
-    let window = {
-        let size = LogicalSize::new(WIDTH as f64, HEIGHT as f64);
-        WindowBuilder::new()
-            .with_title("RustyRays")
-            .with_inner_size(size)
-            .with_min_inner_size(size)
-            .with_max_inner_size(size)
-            .build(&event_loop)?
-    };
-
-    let mut pixels = {
-        let surface_texture = SurfaceTexture::new(WIDTH as u32, HEIGHT as u32, &window);
-        Pixels::new(WIDTH as u32, HEIGHT as u32, surface_texture)?
+    let args = Args::parse();
+    if args.width == 0 || args.height == 0 || args.pixel_aspect <= 0.0 {
+        return Err("the image must have a positive size and pixel aspect".into());
+    }
+    if !args.layers.is_empty() {
+        let exr = args
+            .output
+            .as_ref()
+            .and_then(|output| output.extension())
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("exr"));
+        if !args.headless || !exr {
+            return Err("--layer needs --headless and an exr --output".into());
+        }
+    }
+    let exr_pixel_type = match args.exr_float {
+        true => ExrPixelType::Float,
+        false => ExrPixelType::Half,
     };
+    progress::set_quiet(args.quiet);
+    colorspace::set_working_space(args.color_space.space())?;
+    tiles::set_tile_size(args.tile_size);
+    tiles::set_tile_order(args.tile_order);
+    if let Some(threads) = args.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()?;
+    }
 
-    let sky = color3(0.7, 0.8, 1.0);
+    // The scene's colors are picked in sRGB.
+    let srgb = ColorSpace::srgb();
+    let sky = srgb.to_working(&color3(0.7, 0.8, 1.0));
 
     let objs: Scene = Scene {
         objs: vec![
@@ -135,13 +394,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     Transform::new_translate(vec3(0.0, -100.01, 0.0)),
                     MediumInterface::new_empty(),
                 )),
-                Arc::new(Matte {
-                    kd: Arc::new(SolidColor {
-                        color: color3(0.1, 0.1, 1.0),
-                    }),
+                Some(Arc::new(Matte {
+                    kd: Arc::new(SolidColor::new_in(color3(0.1, 0.1, 1.0), srgb)),
                     bump_map: None,
                     sigma: Some(Arc::new(ConstantValue { val: 0.0 })),
-                }),
+                })),
                 None,
             ),
             Primitive::new(
@@ -151,13 +408,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     Transform::new_translate(vec3(0.0, 3.0, 0.0)),
                     MediumInterface::new_empty(),
                 )),
-                Arc::new(Matte {
-                    kd: Arc::new(SolidColor {
-                        color: color3(1.0, 0.1, 0.1),
-                    }),
+                Some(Arc::new(Matte {
+                    kd: Arc::new(SolidColor::new_in(color3(1.0, 0.1, 0.1), srgb)),
                     bump_map: None,
                     sigma: Some(Arc::new(ConstantValue { val: 0.0 })),
-                }),
+                })),
                 None,
             ),
         ],
@@ -170,7 +425,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             Box::new(ConstantInfiniteLight::new(
                 Transform::new_identity(),
                 sky,
-                100000.0,
+                1.0,
             )),
         ],
     };
@@ -184,57 +439,237 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     //         // * Transform::new_translate(vec3(10.0,10.0,10.0)),
     //     90.0
     // );
-    let cam = SimpleCamera::new(point3(10.0, 10.0, 10.0), point3(0.0, 0.0, 0.0), 40.0);
+    let mut cam = SimpleCamera::new(
+        point3(10.0, 10.0, 10.0),
+        point3(0.0, 0.0, 0.0),
+        40.0,
+        args.width,
+        args.height,
+    );
+    cam.pixel_aspect = args.pixel_aspect;
+    if let Some(ref crop) = args.crop {
+        cam.crop_window = (crop[0], crop[1], crop[2], crop[3]);
+    }
+    let (width, height) = cam.image_size();
+    if width == 0 || height == 0 {
+        return Err("the crop window doesn't cover any pixels".into());
+    }
 
-    let mut world = World::new(objs, cam, 8, 100);
+    let mut world = World::new(objs, cam, args.integrator.build(args.max_depth), args.spp);
+    if let Some(seed) = args.seed {
+        world.rng = RngGen::new_seeded(seed);
+    }
+
+    let mut tone_map = ToneMapper::new(args.tonemap, args.exposure);
+    tone_map.white_point = args.white_point;
+    tone_map.set_white_balance(args.white_balance);
+
+    let progressive = world.integrator.progressive() && args.pass_spp > 0;
+    let time_limit = args.time_limit.map(Duration::from_secs_f32);
+    if time_limit.is_some() && !progressive {
+        let one_go = match args.pass_spp {
+            0 => "--pass-spp 0".to_string(),
+            _ => {
+                let name = args.integrator.to_possible_value().unwrap();
+                format!("--integrator {}", name.get_name())
+            }
+        };
+        return Err(format!("--time-limit can't stop a render in one go, as with {one_go}").into());
+    }
 
     world.preprocess();
 
-    // let mut current_frame = 0;
-
-    // Draw the current frame
-    
-    // world.draw_next_pixel(i, x, y, frame);
-    let chunk_size = HEIGHT / 5;
-    assert_eq!(HEIGHT % chunk_size, 0);
-    let start = Instant::now();
-    println!("Rendering...");
-    
-    // let mut color_buf =vec![color3(0.0, 0.0, 0.0); WIDTH * HEIGHT];
-    {
-        let frame = pixels.frame_mut();
-        frame.fill(0);
-        frame
-        .par_chunks_exact_mut(4 * chunk_size)
-        .enumerate()
-        .for_each(|(chunk_idx, chunk)| {
-            let chunk_offset = chunk_size * chunk_idx;
-            chunk
-                .par_chunks_exact_mut(4)
-                .enumerate()
-                .for_each(|(pixel_idx, pixel)| {
-                    let i = chunk_offset + pixel_idx;
-                    let x = i % WIDTH as S;
-                    let y = i / WIDTH as S;
-                    let rendered_pixel = world.render_pixel(x, y);
-                    let rendered_pixel = color_to_pixel(rendered_pixel, 0.40);
-                    pixel[0] = rendered_pixel[0];
-                    pixel[1] = rendered_pixel[1];
-                    pixel[2] = rendered_pixel[2];
-                    pixel[3] = rendered_pixel[3];
+    // Whether a progressive render should stop refining.
+    let finished = move |render: &ProgressiveRender| {
+        render.samples_per_pixel() >= args.spp
+            || time_limit.is_some_and(|limit| render.elapsed() >= limit)
+    };
+
+    if args.headless {
+        let image = match time_limit {
+            Some(limit) if progressive => {
+                // Stop in the middle of a pass rather than run over by up to a whole one.
+                std::thread::spawn(move || {
+                    std::thread::sleep(limit);
+                    tiles::cancel();
                 });
-        });
-        pixels.render()?;
+                let mut render = ProgressiveRender::new(&world.cam, args.pass_spp);
+                // Counts tiles over every pass, which the deadline may cut short.
+                let n_tiles = tiles::tiles(world.cam.pixel_bounds()).len();
+                let progress =
+                    ProgressReporter::new(args.spp.div_ceil(args.pass_spp) * n_tiles, "Rendering");
+                while !finished(&render) {
+                    let complete = render.render_pass(
+                        world.integrator.as_ref(),
+                        &world.scene,
+                        &world.cam,
+                        &world.rng,
+                        &|_| progress.update(1),
+                    );
+                    if !complete {
+                        break;
+                    }
+                }
+                progress.done();
+                if !args.quiet {
+                    eprintln!(
+                        "Rendered {} samples per pixel in {:.1}s",
+                        render.samples_per_pixel(),
+                        render.elapsed().as_secs_f32()
+                    );
+                }
+                render.image(world.integrator.as_ref())
+            }
+            _ => world.render(),
+        };
+        let output = args.output.unwrap_or_else(|| PathBuf::from("render.png"));
+        let layers: Vec<(String, Vec<Color3>)> = args
+            .layers
+            .iter()
+            .map(|&mode| {
+                let name = mode.to_possible_value().unwrap().get_name().to_string();
+                (name, world.render_debug(mode))
+            })
+            .collect();
+        let layers: Vec<ImageLayer> = layers
+            .iter()
+            .map(|(name, pixels)| ImageLayer { name, pixels })
+            .collect();
+        imageio::write_image_layers(
+            &output,
+            &image,
+            &layers,
+            width,
+            height,
+            &tone_map,
+            exr_pixel_type,
+        )?;
+        if !args.quiet {
+            eprintln!("Saved {}", output.display());
+        }
+        return Ok(());
     }
-    
-    let end = Instant::now();
-    println!("Done in {} seconds!", (end - start).as_secs_f32());
+
+    let event_loop = EventLoop::new();
+    let mut input = WinitInputHelper::new();
+    let window = {
+        let size = LogicalSize::new(width as f64, height as f64);
+        WindowBuilder::new()
+            .with_title("RustyRays")
+            .with_inner_size(size)
+            .with_min_inner_size(size)
+            .with_max_inner_size(size)
+            .build(&event_loop)?
+    };
+
+    let mut pixels = {
+        let surface_texture = SurfaceTexture::new(width as u32, height as u32, &window);
+        Pixels::new(width as u32, height as u32, surface_texture)?
+    };
+
+    eprintln!(
+        "Drag to orbit, right drag to pan, scroll to dolly and fly with WASD, Q and E. Keys 1-9 \
+         and 0 switch integrator, V cycles debug views and P saves the frame."
+    );
+    let mut controller = CameraController::new();
+    let mut kind = args.integrator;
+    // Index into `DEBUG_VIEWS` of the view replacing the integrator, if any.
+    let mut debug_view: Option<S> = None;
+    let mut render = ProgressiveRender::new(&world.cam, args.pass_spp.max(1));
+    let mut image = vec![black(); width * height];
+    // Whether the current view still needs rendering, or more passes.
+    let mut refining = true;
+    // With `--output`, every finished view is saved; P saves whatever is showing.
+    let save_when_finished = args.output.is_some();
+    let save_path = args.output.unwrap_or_else(|| PathBuf::from("render.png"));
+
     event_loop.run(move |event, _, control_flow| {
+        if let Event::RedrawRequested(_) = event {
+            if let Err(err) = pixels.render() {
+                log::error!("pixels.render() failed: {}", err);
+                *control_flow = ControlFlow::Exit;
+                return;
+            }
+        }
+
         if input.update(&event) {
             if input.key_pressed(VirtualKeyCode::Escape) || input.close_requested() {
                 *control_flow = ControlFlow::Exit;
                 return;
             }
+
+            let mut changed = controller.update(&input, &mut world.cam);
+            let mut switched = false;
+            for (key, new_kind) in INTEGRATOR_KEYS.iter().zip(IntegratorKind::value_variants()) {
+                if input.key_pressed(*key) {
+                    kind = *new_kind;
+                    debug_view = None;
+                    switched = true;
+                }
+            }
+            if input.key_pressed(VirtualKeyCode::V) {
+                debug_view = match debug_view {
+                    None => Some(0),
+                    Some(i) if i + 1 < DEBUG_VIEWS.len() => Some(i + 1),
+                    Some(_) => None,
+                };
+                switched = true;
+            }
+            if switched {
+                world.integrator = match debug_view {
+                    Some(i) => Box::new(DebugIntegrator::new(DEBUG_VIEWS[i])),
+                    None => kind.build(args.max_depth),
+                };
+                changed = true;
+            }
+            if changed {
+                // Integrators may keep a copy of the camera, or depend on where it is.
+                world.integrator.preprocess(&world.scene, &world.cam);
+                render.restart(world.integrator.as_ref(), &world.cam);
+                refining = true;
+            }
+
+            if input.key_pressed(VirtualKeyCode::P) {
+                save_frame(&save_path, &image, width, height, &tone_map, exr_pixel_type);
+            }
+
+            *control_flow = ControlFlow::Wait;
+            if refining {
+                if world.integrator.progressive() && args.pass_spp > 0 {
+                    render.render_pass(
+                        world.integrator.as_ref(),
+                        &world.scene,
+                        &world.cam,
+                        &world.rng,
+                        &|_| {},
+                    );
+                    image = render.image(world.integrator.as_ref());
+                    refining = !finished(&render);
+                } else {
+                    // Integrators that can't refine an image progressively render it in one go.
+                    render.restart(world.integrator.as_ref(), &world.cam);
+                    image = world.render();
+                    refining = false;
+                }
+                draw(&mut pixels, &image, &tone_map);
+                let view = match debug_view {
+                    Some(i) => format!("{:?}", DEBUG_VIEWS[i]),
+                    None => kind.to_possible_value().unwrap().get_name().to_string(),
+                };
+                window.set_title(&format!(
+                    "RustyRays - {} - pass {} ({} spp) - {:.1}s",
+                    view,
+                    render.passes,
+                    render.samples_per_pixel(),
+                    render.elapsed().as_secs_f32()
+                ));
+                window.request_redraw();
+                if refining {
+                    *control_flow = ControlFlow::Poll;
+                } else if save_when_finished {
+                    save_frame(&save_path, &image, width, height, &tone_map, exr_pixel_type);
+                }
+            }
         }
     });
     // Ok(())
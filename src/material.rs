@@ -1,11 +1,13 @@
 use std::sync::Arc;
 
-use crate::color::{black, Color3};
+use crate::bssrdf::{measured_medium, BssrdfTable, TabulatedBssrdf};
+use crate::color::{black, color3, Color3};
 use crate::common::*;
+use crate::conductors::ConductorIor;
 use crate::distributions::Distribution1D;
 use crate::interaction::Interaction;
 use crate::ray::Ray;
-use crate::texture::{ColorTexture, ScalarTexture};
+use crate::texture::{ColorTexture, ScalarTexture, SolidColor};
 use crate::vector::*;
 
 pub const BXDF_REFLECTION: u8 = 0b00000001;
@@ -17,12 +19,89 @@ pub const BXDF_ALL: u8 =
     BXDF_DIFFUSE | BXDF_GLOSSY | BXDF_REFLECTION | BXDF_SPECULAR | BXDF_TRANSMISSION;
 pub type BXDFType = u8;
 
+/// Whether a path carries radiance from the lights (traced from the camera) or importance from
+/// the camera (traced from the lights). Scattering isn't symmetric under refraction or shading
+/// normals, so integrators that trace from the lights need to know the difference.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TransportMode {
+    Radiance,
+    Importance,
+}
+
+/// Whether every property of a BxDF is among `flags`, e.g. a specular BxDF doesn't match a
+/// request for just reflection and transmission.
+fn matches_flags(bxdf_type: BXDFType, flags: BXDFType) -> bool {
+    bxdf_type & flags == bxdf_type
+}
+
 fn same_hemisphere(w: &Vec3, wp: &Vec3) -> bool {
     w.z * wp.z > 0.0
 }
+fn cos_theta(w: &Vec3) -> F {
+    w.z
+}
 fn abs_cos_theta(w: &Vec3) -> F {
     w.z.abs()
 }
+
+/// Fresnel reflectance of a dielectric interface for unpolarized light.
+pub fn fr_dielectric(cos_theta_i: F, eta_i: F, eta_t: F) -> F {
+    let mut cos_theta_i = cos_theta_i.clamp(-1.0, 1.0);
+    let (mut eta_i, mut eta_t) = (eta_i, eta_t);
+    if cos_theta_i <= 0.0 {
+        std::mem::swap(&mut eta_i, &mut eta_t);
+        cos_theta_i = cos_theta_i.abs();
+    }
+    let sin_theta_i = F::sqrt(F::max(0.0, 1.0 - cos_theta_i * cos_theta_i));
+    let sin_theta_t = eta_i / eta_t * sin_theta_i;
+    if sin_theta_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_theta_t = F::sqrt(F::max(0.0, 1.0 - sin_theta_t * sin_theta_t));
+    let r_parl = ((eta_t * cos_theta_i) - (eta_i * cos_theta_t))
+        / ((eta_t * cos_theta_i) + (eta_i * cos_theta_t));
+    let r_perp = ((eta_i * cos_theta_i) - (eta_t * cos_theta_t))
+        / ((eta_i * cos_theta_i) + (eta_t * cos_theta_t));
+    (r_parl * r_parl + r_perp * r_perp) / 2.0
+}
+
+/// Fresnel reflectance of a conductor with complex index of refraction `eta_t + i k`, seen
+/// from a dielectric with index `eta_i`.
+pub fn fr_conductor(cos_theta_i: F, eta_i: F, eta_t: F, k: F) -> F {
+    let cos_theta_i = cos_theta_i.clamp(-1.0, 1.0);
+    let eta = eta_t / eta_i;
+    let eta_k = k / eta_i;
+    let cos2_theta_i = cos_theta_i * cos_theta_i;
+    let sin2_theta_i = 1.0 - cos2_theta_i;
+    let eta2 = eta * eta;
+    let eta_k2 = eta_k * eta_k;
+
+    let t0 = eta2 - eta_k2 - sin2_theta_i;
+    let a2_plus_b2 = F::sqrt(t0 * t0 + 4.0 * eta2 * eta_k2);
+    let t1 = a2_plus_b2 + cos2_theta_i;
+    let a = F::sqrt(F::max(0.0, 0.5 * (a2_plus_b2 + t0)));
+    let t2 = 2.0 * cos_theta_i * a;
+    let rs = (t1 - t2) / (t1 + t2);
+
+    let t3 = cos2_theta_i * a2_plus_b2 + sin2_theta_i * sin2_theta_i;
+    let t4 = t2 * sin2_theta_i;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+    0.5 * (rp + rs)
+}
+
+/// Refracts `wi` through a surface with normal `n` (on the same side as `wi`), where `eta` is
+/// the ratio of the incident to the transmitted index of refraction. Returns `None` on total
+/// internal reflection.
+pub fn refract(wi: &Vec3, n: &Normal3, eta: F) -> Option<Vec3> {
+    let cos_theta_i = n.dot(wi);
+    let sin2_theta_i = F::max(0.0, 1.0 - cos_theta_i * cos_theta_i);
+    let sin2_theta_t = eta * eta * sin2_theta_i;
+    if sin2_theta_t >= 1.0 {
+        return None;
+    }
+    let cos_theta_t = F::sqrt(1.0 - sin2_theta_t);
+    Some(eta * -wi + (eta * cos_theta_i - cos_theta_t) * n)
+}
 pub trait Bxdf {
     fn bxdf_type(&self) -> BXDFType;
     fn scale(&self) -> F {
@@ -84,6 +163,207 @@ impl Bxdf for LambertianReflection {
     }
 }
 
+/// How much light a smooth interface reflects, given the cosine of the angle between the
+/// incident direction and the normal.
+pub trait Fresnel {
+    fn evaluate(&self, cos_theta_i: F) -> Color3;
+}
+
+pub struct FresnelDielectric {
+    pub eta_i: F,
+    pub eta_t: F,
+}
+
+impl Fresnel for FresnelDielectric {
+    fn evaluate(&self, cos_theta_i: F) -> Color3 {
+        let f = fr_dielectric(cos_theta_i, self.eta_i, self.eta_t);
+        color3(f, f, f)
+    }
+}
+
+/// Reflects all incident light.
+pub struct FresnelNoOp;
+
+impl Fresnel for FresnelNoOp {
+    fn evaluate(&self, cos_theta_i: F) -> Color3 {
+        color3(1.0, 1.0, 1.0)
+    }
+}
+
+/// Fresnel reflectance of a metal, per color channel.
+pub struct FresnelConductor {
+    pub eta_i: Color3,
+    pub eta_t: Color3,
+    pub k: Color3,
+}
+
+impl Fresnel for FresnelConductor {
+    fn evaluate(&self, cos_theta_i: F) -> Color3 {
+        Color3::from_fn(|c, _| {
+            fr_conductor(cos_theta_i.abs(), self.eta_i[c], self.eta_t[c], self.k[c])
+        })
+    }
+}
+
+/// Perfect mirror reflection, scaled by a Fresnel term.
+pub struct SpecularReflection {
+    r: Color3,
+    fresnel: Arc<dyn Fresnel + Send + Sync>,
+}
+
+impl SpecularReflection {
+    pub fn new(r: Color3, fresnel: Arc<dyn Fresnel + Send + Sync>) -> Self {
+        Self { r, fresnel }
+    }
+}
+
+impl Bxdf for SpecularReflection {
+    fn bxdf_type(&self) -> BXDFType {
+        BXDF_REFLECTION | BXDF_SPECULAR
+    }
+    fn rho_2samples(
+        &self,
+        n_samples: S,
+        samples1: &[Point2],
+        samples2: &[Point2],
+    ) -> Option<Color3> {
+        None
+    }
+    fn rho(&self, n_samples: S, wo: &Vec3, samples: &[Point2]) -> Option<Color3> {
+        None
+    }
+    fn f(&self, wo: &Vec3, wi: &Vec3) -> Option<Color3> {
+        Some(black())
+    }
+    fn pdf(&self, wo: &Vec3, wi: &Vec3) -> F {
+        0.0
+    }
+    fn sample_f(&self, wo: &Vec3, u: &Point2) -> Option<(Color3, F, Vec3, BXDFType)> {
+        let wi = vec3(-wo.x, -wo.y, wo.z);
+        let col = self.fresnel.evaluate(cos_theta(&wi)).component_mul(&self.r) / abs_cos_theta(&wi);
+        Some((col, 1.0, wi, self.bxdf_type()))
+    }
+}
+
+/// Perfect refraction through a dielectric interface, with `eta_a` the index of refraction
+/// on the side the normal points away from and `eta_b` on the other.
+pub struct SpecularTransmission {
+    t: Color3,
+    eta_a: F,
+    eta_b: F,
+    fresnel: FresnelDielectric,
+}
+
+impl SpecularTransmission {
+    pub fn new(t: Color3, eta_a: F, eta_b: F) -> Self {
+        Self {
+            t,
+            eta_a,
+            eta_b,
+            fresnel: FresnelDielectric {
+                eta_i: eta_a,
+                eta_t: eta_b,
+            },
+        }
+    }
+}
+
+impl Bxdf for SpecularTransmission {
+    fn bxdf_type(&self) -> BXDFType {
+        BXDF_TRANSMISSION | BXDF_SPECULAR
+    }
+    fn rho_2samples(
+        &self,
+        n_samples: S,
+        samples1: &[Point2],
+        samples2: &[Point2],
+    ) -> Option<Color3> {
+        None
+    }
+    fn rho(&self, n_samples: S, wo: &Vec3, samples: &[Point2]) -> Option<Color3> {
+        None
+    }
+    fn f(&self, wo: &Vec3, wi: &Vec3) -> Option<Color3> {
+        Some(black())
+    }
+    fn pdf(&self, wo: &Vec3, wi: &Vec3) -> F {
+        0.0
+    }
+    fn sample_f(&self, wo: &Vec3, u: &Point2) -> Option<(Color3, F, Vec3, BXDFType)> {
+        let entering = cos_theta(wo) > 0.0;
+        let (eta_i, eta_t, n) = if entering {
+            (self.eta_a, self.eta_b, normal3(0.0, 0.0, 1.0))
+        } else {
+            (self.eta_b, self.eta_a, normal3(0.0, 0.0, -1.0))
+        };
+        let wi = refract(wo, &n, eta_i / eta_t)?;
+        let ft = self
+            .t
+            .component_mul(&(color3(1.0, 1.0, 1.0) - self.fresnel.evaluate(cos_theta(&wi))))
+            * (eta_i * eta_i)
+            / (eta_t * eta_t);
+        Some((ft / abs_cos_theta(&wi), 1.0, wi, self.bxdf_type()))
+    }
+}
+
+/// Perfectly smooth dielectric that picks between specular reflection and transmission in
+/// proportion to the Fresnel term.
+pub struct FresnelSpecular {
+    r: Color3,
+    t: Color3,
+    eta_a: F,
+    eta_b: F,
+}
+
+impl FresnelSpecular {
+    pub fn new(r: Color3, t: Color3, eta_a: F, eta_b: F) -> Self {
+        Self { r, t, eta_a, eta_b }
+    }
+}
+
+impl Bxdf for FresnelSpecular {
+    fn bxdf_type(&self) -> BXDFType {
+        BXDF_REFLECTION | BXDF_TRANSMISSION | BXDF_SPECULAR
+    }
+    fn rho_2samples(
+        &self,
+        n_samples: S,
+        samples1: &[Point2],
+        samples2: &[Point2],
+    ) -> Option<Color3> {
+        None
+    }
+    fn rho(&self, n_samples: S, wo: &Vec3, samples: &[Point2]) -> Option<Color3> {
+        None
+    }
+    fn f(&self, wo: &Vec3, wi: &Vec3) -> Option<Color3> {
+        Some(black())
+    }
+    fn pdf(&self, wo: &Vec3, wi: &Vec3) -> F {
+        0.0
+    }
+    fn sample_f(&self, wo: &Vec3, u: &Point2) -> Option<(Color3, F, Vec3, BXDFType)> {
+        let f = fr_dielectric(cos_theta(wo), self.eta_a, self.eta_b);
+        if u.x < f {
+            let wi = vec3(-wo.x, -wo.y, wo.z);
+            let col = f * self.r / abs_cos_theta(&wi);
+            Some((col, f, wi, BXDF_SPECULAR | BXDF_REFLECTION))
+        } else {
+            let entering = cos_theta(wo) > 0.0;
+            let (eta_i, eta_t, n) = if entering {
+                (self.eta_a, self.eta_b, normal3(0.0, 0.0, 1.0))
+            } else {
+                (self.eta_b, self.eta_a, normal3(0.0, 0.0, -1.0))
+            };
+            let wi = refract(wo, &n, eta_i / eta_t)?;
+            // Radiance is compressed into a smaller solid angle when entering a denser medium.
+            let ft = self.t * (1.0 - f) * (eta_i * eta_i) / (eta_t * eta_t);
+            let col = ft / abs_cos_theta(&wi);
+            Some((col, 1.0 - f, wi, BXDF_SPECULAR | BXDF_TRANSMISSION))
+        }
+    }
+}
+
 // impl BitAnd for dyn BXDF {
 //     type Output = BXDFType;
 
@@ -92,6 +372,178 @@ impl Bxdf for LambertianReflection {
 //     }
 // }
 
+/// Converts a user-facing roughness in `[0, 1]` to the microfacet distribution's alpha, so
+/// that equal steps in roughness look roughly evenly spaced.
+pub fn roughness_to_alpha(roughness: F) -> F {
+    let x = roughness.max(1e-3).ln();
+    1.62142
+        + 0.819_955 * x
+        + 0.1734 * x * x
+        + 0.017_120_1 * x * x * x
+        + 0.000_640_711 * x * x * x * x
+}
+
+/// Trowbridge-Reitz (GGX) distribution of microfacet normals, possibly anisotropic with
+/// different roughnesses along the shading frame's tangents.
+#[derive(Clone, Copy, Debug)]
+pub struct TrowbridgeReitzDistribution {
+    pub alpha_x: F,
+    pub alpha_y: F,
+}
+
+impl TrowbridgeReitzDistribution {
+    fn tan2_theta(w: &Vec3) -> F {
+        F::max(0.0, 1.0 - w.z * w.z) / (w.z * w.z)
+    }
+
+    /// `(cos² φ, sin² φ)` of a direction.
+    fn cos2_sin2_phi(w: &Vec3) -> (F, F) {
+        let sin2_theta = F::max(0.0, 1.0 - w.z * w.z);
+        if sin2_theta == 0.0 {
+            return (1.0, 0.0);
+        }
+        let cos2_phi = (w.x * w.x / sin2_theta).clamp(0.0, 1.0);
+        (cos2_phi, 1.0 - cos2_phi)
+    }
+
+    /// Density of microfacets with normal `wh`, per unit area of the macro surface.
+    pub fn d(&self, wh: &Vec3) -> F {
+        let tan2_theta = Self::tan2_theta(wh);
+        if tan2_theta.is_infinite() {
+            return 0.0;
+        }
+        let cos4_theta = wh.z * wh.z * wh.z * wh.z;
+        let (cos2_phi, sin2_phi) = Self::cos2_sin2_phi(wh);
+        let e = tan2_theta
+            * (cos2_phi / (self.alpha_x * self.alpha_x) + sin2_phi / (self.alpha_y * self.alpha_y));
+        1.0 / (PI * self.alpha_x * self.alpha_y * cos4_theta * (1.0 + e) * (1.0 + e))
+    }
+
+    /// Ratio of hidden to visible microfacet area seen from `w`.
+    fn lambda(&self, w: &Vec3) -> F {
+        let tan2_theta = Self::tan2_theta(w);
+        if tan2_theta.is_infinite() {
+            return 0.0;
+        }
+        let (cos2_phi, sin2_phi) = Self::cos2_sin2_phi(w);
+        let alpha2 =
+            cos2_phi * self.alpha_x * self.alpha_x + sin2_phi * self.alpha_y * self.alpha_y;
+        (-1.0 + F::sqrt(1.0 + alpha2 * tan2_theta)) / 2.0
+    }
+
+    /// Fraction of microfacets visible from both `wo` and `wi`.
+    pub fn g(&self, wo: &Vec3, wi: &Vec3) -> F {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    /// Samples a microfacet normal in the same hemisphere as `wo`, proportionally to `d`
+    /// times its cosine.
+    pub fn sample_wh(&self, wo: &Vec3, u: &Point2) -> Vec3 {
+        let (tan2_theta, phi) = if self.alpha_x == self.alpha_y {
+            (
+                self.alpha_x * self.alpha_x * u.x / (1.0 - u.x),
+                2.0 * PI * u.y,
+            )
+        } else {
+            let mut phi = F::atan(self.alpha_y / self.alpha_x * F::tan(2.0 * PI * u.y + 0.5 * PI));
+            if u.y > 0.5 {
+                phi += PI;
+            }
+            let (sin_phi, cos_phi) = phi.sin_cos();
+            let alpha2 = 1.0
+                / (cos_phi * cos_phi / (self.alpha_x * self.alpha_x)
+                    + sin_phi * sin_phi / (self.alpha_y * self.alpha_y));
+            (alpha2 * u.x / (1.0 - u.x), phi)
+        };
+        let cos_theta = 1.0 / F::sqrt(1.0 + tan2_theta);
+        let sin_theta = F::sqrt(F::max(0.0, 1.0 - cos_theta * cos_theta));
+        let wh = vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+        if same_hemisphere(wo, &wh) {
+            wh
+        } else {
+            -wh
+        }
+    }
+
+    pub fn pdf(&self, wh: &Vec3) -> F {
+        self.d(wh) * abs_cos_theta(wh)
+    }
+}
+
+/// Glossy reflection off a rough surface made of mirror-like microfacets (Torrance-Sparrow).
+pub struct MicrofacetReflection {
+    r: Color3,
+    distribution: TrowbridgeReitzDistribution,
+    fresnel: Arc<dyn Fresnel + Send + Sync>,
+}
+
+impl MicrofacetReflection {
+    pub fn new(
+        r: Color3,
+        distribution: TrowbridgeReitzDistribution,
+        fresnel: Arc<dyn Fresnel + Send + Sync>,
+    ) -> Self {
+        Self {
+            r,
+            distribution,
+            fresnel,
+        }
+    }
+}
+
+impl Bxdf for MicrofacetReflection {
+    fn bxdf_type(&self) -> BXDFType {
+        BXDF_REFLECTION | BXDF_GLOSSY
+    }
+    fn rho_2samples(
+        &self,
+        n_samples: S,
+        samples1: &[Point2],
+        samples2: &[Point2],
+    ) -> Option<Color3> {
+        None
+    }
+    fn rho(&self, n_samples: S, wo: &Vec3, samples: &[Point2]) -> Option<Color3> {
+        None
+    }
+    fn f(&self, wo: &Vec3, wi: &Vec3) -> Option<Color3> {
+        let cos_theta_o = abs_cos_theta(wo);
+        let cos_theta_i = abs_cos_theta(wi);
+        let wh = wi + wo;
+        if cos_theta_i == 0.0 || cos_theta_o == 0.0 || wh == Vec3::zeros() {
+            return Some(black());
+        }
+        let wh = wh.normalize();
+        let f = self.fresnel.evaluate(wi.dot(&wh));
+        Some(
+            self.r.component_mul(&f) * self.distribution.d(&wh) * self.distribution.g(wo, wi)
+                / (4.0 * cos_theta_i * cos_theta_o),
+        )
+    }
+    fn pdf(&self, wo: &Vec3, wi: &Vec3) -> F {
+        if !same_hemisphere(wo, wi) {
+            return 0.0;
+        }
+        let wh = (wo + wi).normalize();
+        self.distribution.pdf(&wh) / (4.0 * wo.dot(&wh))
+    }
+    fn sample_f(&self, wo: &Vec3, u: &Point2) -> Option<(Color3, F, Vec3, BXDFType)> {
+        if wo.z == 0.0 {
+            return None;
+        }
+        let wh = self.distribution.sample_wh(wo, u);
+        if wo.dot(&wh) < 0.0 {
+            return None;
+        }
+        let wi = -wo + 2.0 * wo.dot(&wh) * wh;
+        if !same_hemisphere(wo, &wi) {
+            return None;
+        }
+        let pdf = self.distribution.pdf(&wh) / (4.0 * wo.dot(&wh));
+        self.f(wo, &wi).map(|f| (f, pdf, wi, self.bxdf_type()))
+    }
+}
+
 #[derive(Clone)]
 pub struct Bsdf {
     // shape: Arc<dyn Shape>,
@@ -103,6 +555,8 @@ pub struct Bsdf {
     // pub wi: Vec3,
     // pub attenuation: Color3,
     // pub pdf_value: F,
+    /// Relative index of refraction across the surface, or 1 for opaque surfaces.
+    pub eta: F,
     ss: Vec3,
     ns: Vec3,
     ng: Vec3,
@@ -111,18 +565,24 @@ pub struct Bsdf {
 
 impl Bsdf {
     pub fn new(inter: &Interaction) -> Self {
+        // TODO: remove all these unwraps
+        let shading = inter.shading.as_ref().unwrap();
+        let ns = shading.n.normalize();
+        // Gram-Schmidt dpdu against the normal so the frame stays orthonormal, falling back to
+        // an arbitrary tangent where the parameterization is degenerate.
+        let dpdu = shading.dpdu - ns * ns.dot(&shading.dpdu);
+        let ss = if dpdu.magnitude_squared() > 0.0 {
+            dpdu.normalize()
+        } else {
+            coordinate_system(&ns).0
+        };
         Self {
-            // TODO: remove all these unwraps
             bxdfs: vec![],
-            ns: inter.shading.as_ref().unwrap().n,
+            eta: 1.0,
+            ns,
             ng: inter.n.unwrap(),
-            ss: inter.shading.as_ref().unwrap().dpdu.normalize(),
-            ts: inter
-                .shading
-                .as_ref()
-                .unwrap()
-                .n
-                .cross(&inter.shading.as_ref().unwrap().dpdu.normalize()),
+            ss,
+            ts: ns.cross(&ss),
         }
     }
 
@@ -130,14 +590,30 @@ impl Bsdf {
         self.bxdfs.push(bxdf);
     }
 
-    pub fn pdf(&self, wo: &Vec3, wi: &Vec3, flags: BXDFType) -> F {
+    pub fn num_components(&self, flags: BXDFType) -> S {
         self.bxdfs
             .iter()
-            .filter_map(|item| match item {
-                bxdf if bxdf.bxdf_type() & flags != 0 => Some(bxdf.pdf(wo, wi)),
-                _ => None,
-            })
-            .product()
+            .filter(|bxdf| matches_flags(bxdf.bxdf_type(), flags))
+            .count()
+    }
+
+    pub fn pdf(&self, wo_world: &Vec3, wi_world: &Vec3, flags: BXDFType) -> F {
+        let wo = self.world_to_local(wo_world);
+        let wi = self.world_to_local(wi_world);
+        if wo.z == 0.0 {
+            return 0.0;
+        }
+        let matching_comps = self.num_components(flags);
+        if matching_comps == 0 {
+            return 0.0;
+        }
+        let pdf: F = self
+            .bxdfs
+            .iter()
+            .filter(|bxdf| matches_flags(bxdf.bxdf_type(), flags))
+            .map(|bxdf| bxdf.pdf(&wo, &wi))
+            .sum();
+        pdf / matching_comps as F
     }
 
     pub fn f(&self, wo_world: &Vec3, wi_world: &Vec3, flags: BXDFType) -> Color3 {
@@ -146,7 +622,7 @@ impl Bsdf {
         let reflect = wi_world.dot(&self.ng) * wo_world.dot(&self.ng) > 0.0;
         let mut f = black();
         for bxdf in self.bxdfs.iter() {
-            if bxdf.bxdf_type() & flags != 0
+            if matches_flags(bxdf.bxdf_type(), flags)
                 && ((reflect && (bxdf.bxdf_type() & BXDF_REFLECTION != 0))
                     || (!reflect && (bxdf.bxdf_type() & BXDF_TRANSMISSION != 0)))
             {
@@ -157,31 +633,66 @@ impl Bsdf {
         }
         f
     }
+
+    /// Samples one of the matching BxDFs. The returned direction is in world space.
     pub fn sample_f(
         &self,
-        wo: &Vec3,
+        wo_world: &Vec3,
         u: &Point2,
         flags: BXDFType,
     ) -> Option<(Color3, F, Vec3, BXDFType)> {
-        let mut matching_comps = 0;
-        if self.bxdfs.is_empty() {
+        let matching_comps = self.num_components(flags);
+        if matching_comps == 0 {
             return None;
         }
-        for bxdf in self.bxdfs.iter() {
-            if bxdf.bxdf_type() & flags != 0 {
-                matching_comps += 1;
-            }
+        let comp = ((u.x * matching_comps as F).floor() as S).min(matching_comps - 1);
+        let bxdf = self
+            .bxdfs
+            .iter()
+            .filter(|bxdf| matches_flags(bxdf.bxdf_type(), flags))
+            .nth(comp)
+            .unwrap();
+        let u_remapped = point2(
+            (u.x * matching_comps as F - comp as F).min(ONE_MINUS_EPSILON),
+            u.y,
+        );
+
+        let wo = self.world_to_local(wo_world);
+        if wo.z == 0.0 {
+            return None;
         }
-        let comp;
-        if matching_comps == 0 {
-            comp = 0;
-        } else {
-            comp = ((u.x * matching_comps as F).floor() as I).min(matching_comps - 1);
+        let (mut f, mut pdf, wi, sampled_type) = bxdf.sample_f(&wo, &u_remapped)?;
+        if pdf == 0.0 {
+            return None;
+        }
+        let wi_world = self.local_to_world(&wi);
+
+        // Account for the other matching lobes unless a delta lobe was sampled.
+        if sampled_type & BXDF_SPECULAR == 0 && matching_comps > 1 {
+            pdf = self.pdf(wo_world, &wi_world, flags);
+            f = self.f(wo_world, &wi_world, flags);
+        } else if matching_comps > 1 {
+            pdf /= matching_comps as F;
         }
-        let bxdf = &self.bxdfs[comp as S];
-        // let u_remapped = point2(u[0] * matching_comps as F - comp as F, u[1]);
-        // let
-        bxdf.sample_f(wo, u)
+        Some((f, pdf, wi_world, sampled_type))
+    }
+
+    /// Factor converting a throughput computed from `sample_f` to the given transport mode.
+    /// Specular transmission scales radiance by the squared ratio of IORs, which importance
+    /// isn't subject to.
+    pub fn transport_scale(&self, wo: &Vec3, sampled_type: BXDFType, mode: TransportMode) -> F {
+        let specular_transmission = BXDF_SPECULAR | BXDF_TRANSMISSION;
+        if mode == TransportMode::Radiance
+            || sampled_type & specular_transmission != specular_transmission
+        {
+            return 1.0;
+        }
+        let eta = if wo.dot(&self.ns) > 0.0 {
+            self.eta
+        } else {
+            1.0 / self.eta
+        };
+        eta * eta
     }
 
     pub fn world_to_local(&self, v: &Vec3) -> Vec3 {
@@ -202,6 +713,27 @@ pub trait Material {
         inter: &mut Interaction,
         // rng: &RngGen,
     );
+    /// Like `calculate_bsdf`, but with specular reflection and transmission as separate lobes
+    /// rather than one that picks between them, so integrators can follow both.
+    fn calculate_bsdf_split_specular(&self, inter: &mut Interaction) {
+        self.calculate_bsdf(inter);
+    }
+    /// Like `calculate_bsdf`, for light of a single wavelength in nanometers. Only dispersive
+    /// materials, whose index of refraction depends on wavelength, need to override this.
+    fn calculate_bsdf_at_wavelength(&self, inter: &mut Interaction, lambda: F) {
+        self.calculate_bsdf(inter);
+    }
+    /// Whether the BSDF depends on wavelength, so that spectral renderers can't share a path
+    /// between several wavelengths once it reaches this material.
+    fn is_dispersive(&self) -> bool {
+        false
+    }
+    /// Whether `calculate_bsdf_at_wavelength` differs from `calculate_bsdf`, e.g. for measured
+    /// conductors. Spectral renderers evaluate such BSDFs once per wavelength; dispersive
+    /// materials are always wavelength dependent.
+    fn is_wavelength_dependent(&self) -> bool {
+        self.is_dispersive()
+    }
     fn scattering_pdf(&self, _ray: &Ray, _inter: &Interaction) -> F;
 }
 
@@ -253,3 +785,370 @@ impl Material for Matte {
         }
     }
 }
+
+/// Index of refraction of a dielectric, possibly varying with wavelength (dispersion).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ior {
+    Constant(F),
+    /// Cauchy's equation `n = a + b / λ²`, with `λ` in micrometers.
+    Cauchy {
+        a: F,
+        b: F,
+    },
+    /// Sellmeier's equation `n² = 1 + Σ bᵢ λ² / (λ² - cᵢ)`, with `λ` in micrometers and `cᵢ`
+    /// in square micrometers.
+    Sellmeier {
+        b: [F; 3],
+        c: [F; 3],
+    },
+}
+
+impl Ior {
+    /// Wavelength of the Fraunhofer d line, at which glasses' nominal index is quoted and
+    /// which renderers without wavelengths use.
+    pub const D_LINE: F = 587.56;
+
+    /// Schott N-BK7, the common borosilicate crown glass.
+    pub const BK7: Ior = Ior::Sellmeier {
+        b: [1.039_612, 0.231_792_34, 1.010_469_5],
+        c: [0.006_000_699, 0.020_017_914, 103.560_65],
+    };
+    pub const FUSED_SILICA: Ior = Ior::Sellmeier {
+        b: [0.696_166_3, 0.407_942_6, 0.897_479_4],
+        c: [0.004_679_148, 0.013_512_063, 97.934],
+    };
+    /// Schott SF11, a dense flint glass with strong dispersion, as used in prisms.
+    pub const SF11: Ior = Ior::Sellmeier {
+        b: [1.737_597, 0.313_747_35, 1.898_781],
+        c: [0.013_188_707, 0.062_306_814, 155.236_3],
+    };
+    pub const DIAMOND: Ior = Ior::Sellmeier {
+        b: [0.3306, 4.3356, 0.0],
+        c: [0.030_625, 0.011_236, 0.0],
+    };
+    pub const WATER: Ior = Ior::Cauchy {
+        a: 1.3199,
+        b: 0.006_878,
+    };
+
+    /// A named preset: `bk7`, `fused-silica`, `sf11`, `diamond` or `water`.
+    pub fn named(name: &str) -> Option<Ior> {
+        match name.to_lowercase().as_str() {
+            "bk7" => Some(Ior::BK7),
+            "fused-silica" => Some(Ior::FUSED_SILICA),
+            "sf11" => Some(Ior::SF11),
+            "diamond" => Some(Ior::DIAMOND),
+            "water" => Some(Ior::WATER),
+            _ => None,
+        }
+    }
+
+    /// Index of refraction at `lambda`, in nanometers.
+    pub fn at(&self, lambda: F) -> F {
+        let l = lambda / 1000.0;
+        let l2 = l * l;
+        match *self {
+            Ior::Constant(eta) => eta,
+            Ior::Cauchy { a, b } => a + b / l2,
+            Ior::Sellmeier { b, c } => {
+                let n2: F = 1.0 + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<F>();
+                n2.max(1.0).sqrt()
+            }
+        }
+    }
+
+    pub fn is_dispersive(&self) -> bool {
+        !matches!(self, Ior::Constant(_))
+    }
+}
+
+/// A smooth dielectric such as glass or water. Give the primitive a transitional
+/// `MediumInterface` to get smoky or tinted glass.
+#[derive(Clone)]
+pub struct Glass {
+    pub kr: Arc<dyn ColorTexture + Send + Sync>,
+    pub kt: Arc<dyn ColorTexture + Send + Sync>,
+    pub eta: Ior,
+}
+
+impl Glass {
+    fn add_bxdfs(&self, inter: &mut Interaction, eta: F, split_specular: bool) {
+        let r = self.kr.eval(inter);
+        let t = self.kt.eval(inter);
+        if let Some(ref mut bsdf) = inter.bsdf {
+            bsdf.eta = eta;
+        }
+        if !split_specular {
+            if r != black() || t != black() {
+                inter.add_bxdf(Arc::new(FresnelSpecular::new(r, t, 1.0, eta)));
+            }
+            return;
+        }
+        if r != black() {
+            let fresnel = FresnelDielectric {
+                eta_i: 1.0,
+                eta_t: eta,
+            };
+            inter.add_bxdf(Arc::new(SpecularReflection::new(r, Arc::new(fresnel))));
+        }
+        if t != black() {
+            inter.add_bxdf(Arc::new(SpecularTransmission::new(t, 1.0, eta)));
+        }
+    }
+}
+
+impl Material for Glass {
+    fn calculate_bsdf(&self, inter: &mut Interaction) {
+        self.add_bxdfs(inter, self.eta.at(Ior::D_LINE), false);
+    }
+
+    fn calculate_bsdf_split_specular(&self, inter: &mut Interaction) {
+        self.add_bxdfs(inter, self.eta.at(Ior::D_LINE), true);
+    }
+
+    fn calculate_bsdf_at_wavelength(&self, inter: &mut Interaction, lambda: F) {
+        self.add_bxdfs(inter, self.eta.at(lambda), false);
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.eta.is_dispersive()
+    }
+
+    fn scattering_pdf(&self, ray: &Ray, inter: &Interaction) -> F {
+        0.0
+    }
+}
+
+/// How a `Subsurface` material specifies its scattering medium.
+enum SubsurfaceCoefficients {
+    /// Absorption and scattering coefficients directly.
+    Scattering {
+        sigma_a: Arc<dyn ColorTexture + Send + Sync>,
+        sigma_s: Arc<dyn ColorTexture + Send + Sync>,
+    },
+    /// The overall diffuse reflectance and mean free path, which are easier to pick by eye.
+    Reflectance {
+        kd: Arc<dyn ColorTexture + Send + Sync>,
+        mfp: Arc<dyn ColorTexture + Send + Sync>,
+    },
+}
+
+/// A translucent dielectric such as skin, wax or marble, where light scatters beneath the
+/// surface and leaves some distance from where it entered. The surface itself is smooth, with
+/// specular reflection and refraction; the light refracted into it is handed to a BSSRDF.
+pub struct Subsurface {
+    pub kr: Arc<dyn ColorTexture + Send + Sync>,
+    pub kt: Arc<dyn ColorTexture + Send + Sync>,
+    pub eta: F,
+    /// Multiplies the coefficients, e.g. to convert measured ones in inverse millimeters to
+    /// scene units.
+    pub scale: F,
+    coefficients: SubsurfaceCoefficients,
+    table: Arc<BssrdfTable>,
+}
+
+impl Subsurface {
+    /// A medium with the given absorption and scattering coefficients, and Henyey-Greenstein
+    /// asymmetry `g`.
+    pub fn new(
+        sigma_a: Arc<dyn ColorTexture + Send + Sync>,
+        sigma_s: Arc<dyn ColorTexture + Send + Sync>,
+        scale: F,
+        g: F,
+        eta: F,
+    ) -> Self {
+        Self {
+            kr: Arc::new(SolidColor {
+                color: color3(1.0, 1.0, 1.0),
+            }),
+            kt: Arc::new(SolidColor {
+                color: color3(1.0, 1.0, 1.0),
+            }),
+            eta,
+            scale,
+            coefficients: SubsurfaceCoefficients::Scattering { sigma_a, sigma_s },
+            table: Arc::new(BssrdfTable::new_beam_diffusion(g, eta)),
+        }
+    }
+
+    /// A medium found from its diffuse reflectance `kd` and mean free path `mfp`, the
+    /// average distance light travels inside before scattering or being absorbed.
+    pub fn from_reflectance(
+        kd: Arc<dyn ColorTexture + Send + Sync>,
+        mfp: Arc<dyn ColorTexture + Send + Sync>,
+        g: F,
+        eta: F,
+    ) -> Self {
+        Self {
+            coefficients: SubsurfaceCoefficients::Reflectance { kd, mfp },
+            ..Self::new(
+                Arc::new(SolidColor { color: black() }),
+                Arc::new(SolidColor { color: black() }),
+                1.0,
+                g,
+                eta,
+            )
+        }
+    }
+
+    /// A measured medium, e.g. `skin1`, `marble` or `ketchup`; see `measured_medium`. Its
+    /// coefficients are per millimeter, so `scale` is the number of millimeters per scene
+    /// unit.
+    pub fn named(name: &str, scale: F, eta: F) -> Option<Self> {
+        let (sigma_s, sigma_a) = measured_medium(name)?;
+        Some(Self::new(
+            Arc::new(SolidColor { color: sigma_a }),
+            Arc::new(SolidColor { color: sigma_s }),
+            scale,
+            0.0,
+            eta,
+        ))
+    }
+
+    fn add_bxdfs(&self, inter: &mut Interaction, split_specular: bool) {
+        let r = self.kr.eval(inter);
+        let t = self.kt.eval(inter);
+        if let Some(ref mut bsdf) = inter.bsdf {
+            bsdf.eta = self.eta;
+        }
+        if split_specular {
+            if r != black() {
+                let fresnel = FresnelDielectric {
+                    eta_i: 1.0,
+                    eta_t: self.eta,
+                };
+                inter.add_bxdf(Arc::new(SpecularReflection::new(r, Arc::new(fresnel))));
+            }
+            if t != black() {
+                inter.add_bxdf(Arc::new(SpecularTransmission::new(t, 1.0, self.eta)));
+            }
+        } else if r != black() || t != black() {
+            inter.add_bxdf(Arc::new(FresnelSpecular::new(r, t, 1.0, self.eta)));
+        }
+
+        let (sigma_a, sigma_s) = match self.coefficients {
+            SubsurfaceCoefficients::Scattering {
+                ref sigma_a,
+                ref sigma_s,
+            } => (
+                sigma_a.eval(inter) * self.scale,
+                sigma_s.eval(inter) * self.scale,
+            ),
+            SubsurfaceCoefficients::Reflectance { ref kd, ref mfp } => {
+                let kd = kd.eval(inter).map(|c| c.clamp(0.0, 1.0));
+                let mfp = mfp.eval(inter) / self.scale;
+                self.table.subsurface_from_diffuse(&kd, &mfp)
+            }
+        };
+        inter.bssrdf = Some(Arc::new(TabulatedBssrdf::new(
+            inter,
+            self.eta,
+            &sigma_a,
+            &sigma_s,
+            self.table.clone(),
+        )));
+    }
+}
+
+impl Material for Subsurface {
+    fn calculate_bsdf(&self, inter: &mut Interaction) {
+        self.add_bxdfs(inter, false);
+    }
+
+    fn calculate_bsdf_split_specular(&self, inter: &mut Interaction) {
+        self.add_bxdfs(inter, true);
+    }
+
+    fn scattering_pdf(&self, ray: &Ray, inter: &Interaction) -> F {
+        0.0
+    }
+}
+
+/// Polished metal with a measured complex index of refraction, e.g.
+/// `ConductorIor::named("Au")`.
+pub struct Metal {
+    pub ior: Arc<ConductorIor>,
+}
+
+impl Metal {
+    fn add_bxdfs(&self, inter: &mut Interaction, eta: Color3, k: Color3) {
+        let fresnel = FresnelConductor {
+            eta_i: color3(1.0, 1.0, 1.0),
+            eta_t: eta,
+            k,
+        };
+        inter.add_bxdf(Arc::new(SpecularReflection::new(
+            color3(1.0, 1.0, 1.0),
+            Arc::new(fresnel),
+        )));
+    }
+}
+
+impl Material for Metal {
+    fn calculate_bsdf(&self, inter: &mut Interaction) {
+        self.add_bxdfs(inter, self.ior.eta_rgb, self.ior.k_rgb);
+    }
+
+    fn calculate_bsdf_at_wavelength(&self, inter: &mut Interaction, lambda: F) {
+        let (eta, k) = self.ior.at(lambda);
+        self.add_bxdfs(inter, color3(eta, eta, eta), color3(k, k, k));
+    }
+
+    fn is_wavelength_dependent(&self) -> bool {
+        true
+    }
+
+    fn scattering_pdf(&self, ray: &Ray, inter: &Interaction) -> F {
+        0.0
+    }
+}
+
+/// Brushed or rough metal: microfacet reflection with a measured complex index of
+/// refraction. Roughness is in `[0, 1]` unless `remap_roughness` is off, in which case it's
+/// the distribution's alpha directly.
+pub struct RoughMetal {
+    pub ior: Arc<ConductorIor>,
+    pub u_roughness: Arc<dyn ScalarTexture + Send + Sync>,
+    pub v_roughness: Arc<dyn ScalarTexture + Send + Sync>,
+    pub remap_roughness: bool,
+}
+
+impl RoughMetal {
+    fn add_bxdfs(&self, inter: &mut Interaction, eta: Color3, k: Color3) {
+        let mut alpha_x = self.u_roughness.eval(inter);
+        let mut alpha_y = self.v_roughness.eval(inter);
+        if self.remap_roughness {
+            alpha_x = roughness_to_alpha(alpha_x);
+            alpha_y = roughness_to_alpha(alpha_y);
+        }
+        let fresnel = FresnelConductor {
+            eta_i: color3(1.0, 1.0, 1.0),
+            eta_t: eta,
+            k,
+        };
+        inter.add_bxdf(Arc::new(MicrofacetReflection::new(
+            color3(1.0, 1.0, 1.0),
+            TrowbridgeReitzDistribution { alpha_x, alpha_y },
+            Arc::new(fresnel),
+        )));
+    }
+}
+
+impl Material for RoughMetal {
+    fn calculate_bsdf(&self, inter: &mut Interaction) {
+        self.add_bxdfs(inter, self.ior.eta_rgb, self.ior.k_rgb);
+    }
+
+    fn calculate_bsdf_at_wavelength(&self, inter: &mut Interaction, lambda: F) {
+        let (eta, k) = self.ior.at(lambda);
+        self.add_bxdfs(inter, color3(eta, eta, eta), color3(k, k, k));
+    }
+
+    fn is_wavelength_dependent(&self) -> bool {
+        true
+    }
+
+    fn scattering_pdf(&self, ray: &Ray, inter: &Interaction) -> F {
+        0.0
+    }
+}
@@ -14,6 +14,7 @@ use crate::common::*;
 //         }
 //     }
 // }
+pub type Matrix3 = nalgebra::Matrix3<F>;
 pub type Matrix4 = nalgebra::Matrix4<F>;
 pub fn matrix4(m: [[F; 4]; 4]) -> Matrix4 {
     glm::mat4(
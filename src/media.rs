@@ -1,15 +1,20 @@
-use std::sync::Arc;
+use std::{fs, sync::Arc};
 
 use crate::{
-    color::Color3,
-    common::{F, PI},
+    aabb::AABB3,
+    color::{black, color3, Color3},
+    common::{lerp, F, I, ONE_MINUS_EPSILON, PI, S},
+    interaction::Interaction,
     ray::Ray,
     rng::RngGen,
+    transform::Transform,
     vector::*,
 };
 
 pub trait PhaseFunction {
     fn p(&self, wo: &Vec3, wi: &Vec3) -> F;
+    /// Samples an incident direction `wi` for the outgoing direction `wo`, returning it along
+    /// with the value of the phase function (which doubles as its pdf).
     fn sample_p(&self, wo: &Vec3, u: &Point2) -> (Vec3, F);
 }
 
@@ -18,6 +23,39 @@ pub fn phase_hg(cos_theta: F, g: F) -> F {
     (1.0 / (4.0 * PI)) * (1.0 - g * g) / (denom * denom.sqrt())
 }
 
+/// Builds the direction whose cosine with `wo` is `cos_theta`, rotated by `phi` about `wo`.
+fn direction_around(wo: &Vec3, cos_theta: F, phi: F) -> Vec3 {
+    let sin_theta = F::sqrt(F::max(0.0, 1.0 - cos_theta * cos_theta));
+    let (v1, v2) = coordinate_system(wo);
+    spherical_direction(sin_theta, cos_theta, phi, &v1, &v2, wo)
+}
+
+/// Samples the Henyey-Greenstein lobe, returning the cosine between `wo` and `wi`.
+fn sample_hg_cos_theta(u: F, g: F) -> F {
+    if g.abs() < 1e-3 {
+        1.0 - 2.0 * u
+    } else {
+        let sqr_term = (1.0 - g * g) / (1.0 + g - 2.0 * g * u);
+        -(1.0 + g * g - sqr_term * sqr_term) / (2.0 * g)
+    }
+}
+
+/// Scatters light equally in all directions.
+pub struct Isotropic;
+
+impl PhaseFunction for Isotropic {
+    fn p(&self, wo: &Vec3, wi: &Vec3) -> F {
+        1.0 / (4.0 * PI)
+    }
+
+    fn sample_p(&self, wo: &Vec3, u: &Point2) -> (Vec3, F) {
+        (
+            direction_around(wo, 1.0 - 2.0 * u.x, 2.0 * PI * u.y),
+            1.0 / (4.0 * PI),
+        )
+    }
+}
+
 pub struct HenyeyGreenstein {
     pub g: F,
 }
@@ -28,7 +66,147 @@ impl PhaseFunction for HenyeyGreenstein {
     }
 
     fn sample_p(&self, wo: &Vec3, u: &Point2) -> (Vec3, F) {
-        todo!()
+        let cos_theta = sample_hg_cos_theta(u.x, self.g);
+        (
+            direction_around(wo, cos_theta, 2.0 * PI * u.y),
+            phase_hg(cos_theta, self.g),
+        )
+    }
+}
+
+/// A blend of two Henyey-Greenstein lobes, typically one forward and one backward
+/// scattering, weighted by `w` and `1 - w`.
+pub struct DoubleHenyeyGreenstein {
+    pub g1: F,
+    pub g2: F,
+    pub w: F,
+}
+
+impl DoubleHenyeyGreenstein {
+    fn p_cos(&self, cos_theta: F) -> F {
+        self.w * phase_hg(cos_theta, self.g1) + (1.0 - self.w) * phase_hg(cos_theta, self.g2)
+    }
+}
+
+impl PhaseFunction for DoubleHenyeyGreenstein {
+    fn p(&self, wo: &Vec3, wi: &Vec3) -> F {
+        self.p_cos(wo.dot(wi))
+    }
+
+    fn sample_p(&self, wo: &Vec3, u: &Point2) -> (Vec3, F) {
+        let cos_theta = if u.x < self.w {
+            sample_hg_cos_theta(u.x / self.w, self.g1)
+        } else {
+            sample_hg_cos_theta(
+                ((u.x - self.w) / (1.0 - self.w)).min(ONE_MINUS_EPSILON),
+                self.g2,
+            )
+        };
+        (
+            direction_around(wo, cos_theta, 2.0 * PI * u.y),
+            self.p_cos(cos_theta),
+        )
+    }
+}
+
+/// Scattering by particles much smaller than the wavelength, e.g. clear-sky air molecules.
+pub struct Rayleigh;
+
+impl PhaseFunction for Rayleigh {
+    fn p(&self, wo: &Vec3, wi: &Vec3) -> F {
+        let cos_theta = wo.dot(wi);
+        3.0 / (16.0 * PI) * (1.0 + cos_theta * cos_theta)
+    }
+
+    fn sample_p(&self, wo: &Vec3, u: &Point2) -> (Vec3, F) {
+        // Invert the CDF (cos^3 + 3 cos + 4) / 8 with Cardano's formula.
+        let z = 4.0 * u.x - 2.0;
+        let a = F::cbrt(z + F::sqrt(z * z + 1.0));
+        let cos_theta = (a - 1.0 / a).clamp(-1.0, 1.0);
+        (
+            direction_around(wo, cos_theta, 2.0 * PI * u.y),
+            3.0 / (16.0 * PI) * (1.0 + cos_theta * cos_theta),
+        )
+    }
+}
+
+/// Draine's phase function, a Henyey-Greenstein lobe reshaped by `1 + alpha cos^2`. With
+/// `alpha = 1` this is the Cornette-Shanks function; it is a good fit for Mie scattering in
+/// fog and clouds when blended with a Henyey-Greenstein lobe.
+pub struct Draine {
+    pub g: F,
+    pub alpha: F,
+}
+
+impl Draine {
+    /// Evaluates the phase function for the scattering cosine `mu`, the cosine between the
+    /// propagation directions before and after scattering.
+    fn p_mu(&self, mu: f64) -> f64 {
+        let (g, alpha) = (self.g as f64, self.alpha as f64);
+        let denom = 1.0 + g * g - 2.0 * g * mu;
+        let norm = 1.0 + alpha * (1.0 + 2.0 * g * g) / 3.0;
+        (1.0 - g * g) * (1.0 + alpha * mu * mu)
+            / (4.0 * std::f64::consts::PI * denom * denom.sqrt() * norm)
+    }
+
+    /// The cumulative distribution of `mu` over [-1, 1].
+    fn cdf_mu(&self, mu: f64) -> f64 {
+        let (g, alpha) = (self.g as f64, self.alpha as f64);
+        let norm = 1.0 + alpha * (1.0 + 2.0 * g * g) / 3.0;
+        if g.abs() < 1e-3 {
+            return ((mu + 1.0) + alpha * (mu * mu * mu + 1.0) / 3.0) / (2.0 * norm);
+        }
+        // Antiderivatives of s^-3/2 and mu^2 s^-3/2 with s = 1 + g^2 - 2 g mu.
+        let (a, b) = (1.0 + g * g, 2.0 * g);
+        let antiderivative = |mu: f64| {
+            let s = a - b * mu;
+            let i0 = 2.0 / b / s.sqrt();
+            let i2 = (2.0 * a * a / s.sqrt() + 4.0 * a * s.sqrt() - 2.0 / 3.0 * s * s.sqrt())
+                / (b * b * b);
+            i0 + alpha * i2
+        };
+        (1.0 - g * g) / (2.0 * norm) * (antiderivative(mu) - antiderivative(-1.0))
+    }
+
+    /// Inverts the CDF with Newton's method, falling back to bisection whenever a step would
+    /// leave the bracket around the root.
+    fn sample_mu(&self, u: F) -> F {
+        let u = u as f64;
+        let (mut lo, mut hi) = (-1.0, 1.0);
+        let mut mu = 0.0;
+        for _ in 0..64 {
+            let err = self.cdf_mu(mu) - u;
+            if err.abs() < 1e-9 {
+                break;
+            }
+            if err > 0.0 {
+                hi = mu;
+            } else {
+                lo = mu;
+            }
+            let pdf = 2.0 * std::f64::consts::PI * self.p_mu(mu);
+            let next = mu - err / pdf;
+            mu = if next > lo && next < hi {
+                next
+            } else {
+                0.5 * (lo + hi)
+            };
+        }
+        mu as F
+    }
+}
+
+impl PhaseFunction for Draine {
+    fn p(&self, wo: &Vec3, wi: &Vec3) -> F {
+        self.p_mu(-wo.dot(wi) as f64) as F
+    }
+
+    fn sample_p(&self, wo: &Vec3, u: &Point2) -> (Vec3, F) {
+        let mu = self.sample_mu(u.x);
+        (
+            direction_around(wo, -mu, 2.0 * PI * u.y),
+            self.p_mu(mu as f64) as F,
+        )
     }
 }
 
@@ -39,6 +217,13 @@ pub struct MediumInterface {
 }
 
 impl MediumInterface {
+    pub fn new(
+        inside: Option<Arc<dyn Medium + Send + Sync>>,
+        outside: Option<Arc<dyn Medium + Send + Sync>>,
+    ) -> Self {
+        Self { inside, outside }
+    }
+
     pub fn new_non_transition(medium: Option<Arc<dyn Medium + Send + Sync>>) -> Self {
         if let Some(med) = medium {
             Self {
@@ -57,17 +242,351 @@ impl MediumInterface {
         }
     }
 
-    // pub fn is_transition(&self) -> bool {
-    //     if let Some(ref inside) = self.inside {
-    //         if let Some(ref outside) = self.outside {
-    //             !Arc::ptr_eq(inside, outside)
-    //         }
-    //         else { false }
-    //     } else { false }
-    // }
+    /// Returns whether crossing this interface changes the medium a ray travels through.
+    pub fn is_transition(&self) -> bool {
+        match (&self.inside, &self.outside) {
+            (Some(inside), Some(outside)) => {
+                Arc::as_ptr(inside) as *const () != Arc::as_ptr(outside) as *const ()
+            }
+            (None, None) => false,
+            _ => true,
+        }
+    }
 }
 
 pub trait Medium {
     fn transmittance(&self, ray: &Ray, rng: &RngGen) -> Color3;
-    // fn sample(&self, ray: &ray, rng: &RngGen) ->
+    /// Samples a scattering event along `ray` up to its `t_max`. Returns the path throughput
+    /// weight and, if the ray scattered before reaching the surface, the medium interaction.
+    fn sample(&self, ray: &Ray, rng: &RngGen) -> (Color3, Option<Interaction>);
+}
+
+/// A medium with constant absorption and scattering coefficients throughout.
+pub struct HomogeneousMedium {
+    sigma_a: Color3,
+    sigma_s: Color3,
+    sigma_t: Color3,
+    phase: Arc<dyn PhaseFunction + Send + Sync>,
+}
+
+impl HomogeneousMedium {
+    pub fn new(
+        sigma_a: Color3,
+        sigma_s: Color3,
+        phase: Arc<dyn PhaseFunction + Send + Sync>,
+    ) -> Self {
+        Self {
+            sigma_a,
+            sigma_s,
+            sigma_t: sigma_a + sigma_s,
+            phase,
+        }
+    }
+}
+
+impl Medium for HomogeneousMedium {
+    fn transmittance(&self, ray: &Ray, rng: &RngGen) -> Color3 {
+        let dist = F::min(ray.t_max * ray.direction.magnitude(), F::MAX);
+        (-self.sigma_t * dist).map(F::exp)
+    }
+
+    fn sample(&self, ray: &Ray, rng: &RngGen) -> (Color3, Option<Interaction>) {
+        let channel = ((rng.sample_0_1() * 3.0) as S).min(2);
+        let ray_len = ray.direction.magnitude();
+        let dist = -F::ln(1.0 - rng.sample_0_1()) / self.sigma_t[channel];
+        let t = F::min(dist / ray_len, ray.t_max);
+        let sampled_medium = t < ray.t_max;
+
+        let tr = (-self.sigma_t * F::min(t, F::MAX) * ray_len).map(F::exp);
+        let density = if sampled_medium {
+            self.sigma_t.component_mul(&tr)
+        } else {
+            tr
+        };
+        let pdf = density.sum() / 3.0;
+        if pdf == 0.0 {
+            return (black(), None);
+        }
+        if sampled_medium {
+            let inter = Interaction::new_medium(
+                ray.at(t),
+                -ray.direction,
+                ray.time,
+                ray.medium.clone(),
+                self.phase.clone(),
+            );
+            (tr.component_mul(&self.sigma_s) / pdf, Some(inter))
+        } else {
+            (tr / pdf, None)
+        }
+    }
+}
+
+/// A heterogeneous medium whose density is given by a voxel grid spanning the unit cube in
+/// medium space. The extinction coefficient is treated as grey and taken from the first
+/// channel of `sigma_a + sigma_s`, which lets distances be sampled with delta tracking.
+pub struct GridDensityMedium {
+    sigma_a: Color3,
+    sigma_s: Color3,
+    sigma_t: F,
+    phase: Arc<dyn PhaseFunction + Send + Sync>,
+    medium_to_world: Transform,
+    nx: S,
+    ny: S,
+    nz: S,
+    density: Vec<F>,
+    inv_max_density: F,
+}
+
+impl GridDensityMedium {
+    pub fn new(
+        sigma_a: Color3,
+        sigma_s: Color3,
+        phase: Arc<dyn PhaseFunction + Send + Sync>,
+        medium_to_world: Transform,
+        nx: S,
+        ny: S,
+        nz: S,
+        density: Vec<F>,
+    ) -> Self {
+        assert_eq!(
+            density.len(),
+            nx * ny * nz,
+            "Density grid has the wrong size!"
+        );
+        let max_density = density.iter().cloned().fold(0.0, F::max);
+        Self {
+            sigma_a,
+            sigma_s,
+            sigma_t: (sigma_a + sigma_s).x,
+            phase,
+            medium_to_world,
+            nx,
+            ny,
+            nz,
+            density,
+            inv_max_density: if max_density > 0.0 {
+                1.0 / max_density
+            } else {
+                0.0
+            },
+        }
+    }
+
+    /// Loads a grid stored as headerless little-endian `f32`s with x varying fastest.
+    pub fn load_raw(
+        path: &str,
+        nx: S,
+        ny: S,
+        nz: S,
+        sigma_a: Color3,
+        sigma_s: Color3,
+        phase: Arc<dyn PhaseFunction + Send + Sync>,
+        medium_to_world: Transform,
+    ) -> Option<Self> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                eprintln!("Failed to load {} due to {:?}", path, e);
+                return None;
+            }
+        };
+        if bytes.len() != nx * ny * nz * 4 {
+            eprintln!(
+                "ERROR: {} holds {} bytes, expected {} for a {}x{}x{} grid!",
+                path,
+                bytes.len(),
+                nx * ny * nz * 4,
+                nx,
+                ny,
+                nz
+            );
+            return None;
+        }
+        let density = read_f32s(&bytes);
+        Some(Self::new(
+            sigma_a,
+            sigma_s,
+            phase,
+            medium_to_world,
+            nx,
+            ny,
+            nz,
+            density,
+        ))
+    }
+
+    /// Loads a Mitsuba-style `.vol` grid. Only single-channel float32 volumes are supported;
+    /// the bounding box stored in the file is placed inside `medium_to_world`.
+    pub fn load_vol(
+        path: &str,
+        sigma_a: Color3,
+        sigma_s: Color3,
+        phase: Arc<dyn PhaseFunction + Send + Sync>,
+        medium_to_world: Transform,
+    ) -> Option<Self> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                eprintln!("Failed to load {} due to {:?}", path, e);
+                return None;
+            }
+        };
+        const HEADER_LEN: S = 48;
+        if bytes.len() < HEADER_LEN || &bytes[0..3] != b"VOL" || bytes[3] != 3 {
+            eprintln!("ERROR: {} is not a version 3 .vol file!", path);
+            return None;
+        }
+        let read_i32 = |offset: S| {
+            i32::from_le_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ])
+        };
+        let encoding = read_i32(4);
+        let (nx, ny, nz) = (read_i32(8) as S, read_i32(12) as S, read_i32(16) as S);
+        let channels = read_i32(20);
+        if encoding != 1 || channels != 1 {
+            eprintln!(
+                "ERROR: {} uses encoding {} with {} channels, only single-channel float32 is supported!",
+                path, encoding, channels
+            );
+            return None;
+        }
+        let bbox = read_f32s(&bytes[24..HEADER_LEN]);
+        let data = &bytes[HEADER_LEN..];
+        if data.len() != nx * ny * nz * 4 {
+            eprintln!("ERROR: {} is truncated!", path);
+            return None;
+        }
+        let p_min = vec3(bbox[0], bbox[1], bbox[2]);
+        let p_max = vec3(bbox[3], bbox[4], bbox[5]);
+        let grid_to_world =
+            medium_to_world * Transform::new_translate(p_min) * Transform::new_scale(p_max - p_min);
+        Some(Self::new(
+            sigma_a,
+            sigma_s,
+            phase,
+            grid_to_world,
+            nx,
+            ny,
+            nz,
+            read_f32s(data),
+        ))
+    }
+
+    fn d(&self, x: I, y: I, z: I) -> F {
+        if x < 0 || y < 0 || z < 0 || x >= self.nx as I || y >= self.ny as I || z >= self.nz as I {
+            return 0.0;
+        }
+        self.density[(z as S * self.ny + y as S) * self.nx + x as S]
+    }
+
+    /// Trilinearly interpolates the density at `p`, given in medium space.
+    pub fn density(&self, p: &Point3) -> F {
+        let p_samples = vec3(
+            p.x * self.nx as F - 0.5,
+            p.y * self.ny as F - 0.5,
+            p.z * self.nz as F - 0.5,
+        );
+        let pi = p_samples.map(F::floor);
+        let d = p_samples - pi;
+        let (x, y, z) = (pi.x as I, pi.y as I, pi.z as I);
+        let d00 = lerp(d.x, self.d(x, y, z), self.d(x + 1, y, z));
+        let d10 = lerp(d.x, self.d(x, y + 1, z), self.d(x + 1, y + 1, z));
+        let d01 = lerp(d.x, self.d(x, y, z + 1), self.d(x + 1, y, z + 1));
+        let d11 = lerp(d.x, self.d(x, y + 1, z + 1), self.d(x + 1, y + 1, z + 1));
+        let d0 = lerp(d.y, d00, d10);
+        let d1 = lerp(d.y, d01, d11);
+        lerp(d.z, d0, d1)
+    }
+
+    /// Transforms `ray` into medium space with a unit-length world-space direction, so that
+    /// parametric distances along it are world-space distances, and clips it against the grid.
+    fn clip_ray(&self, ray: &Ray) -> Option<(Ray, F, F)> {
+        if self.inv_max_density == 0.0 {
+            // The grid is empty, so there's nothing to track through.
+            return None;
+        }
+        let len = ray.direction.magnitude();
+        let world_ray = Ray::new_non_differential(
+            ray.origin,
+            ray.direction / len,
+            0.0,
+            ray.t_max * len,
+            ray.time,
+            None,
+        );
+        let medium_ray = self.medium_to_world.iray(&world_ray);
+        let bounds = AABB3::new(point3(0.0, 0.0, 0.0), point3(1.0, 1.0, 1.0));
+        match bounds.intersect_p(medium_ray.clone()) {
+            (Some(t_min), Some(t_max)) => Some((medium_ray, t_min, t_max)),
+            _ => None,
+        }
+    }
+}
+
+impl Medium for GridDensityMedium {
+    /// Estimates transmittance with ratio tracking.
+    fn transmittance(&self, ray: &Ray, rng: &RngGen) -> Color3 {
+        let (medium_ray, t_min, t_max) = match self.clip_ray(ray) {
+            Some(clipped) => clipped,
+            None => return color3(1.0, 1.0, 1.0),
+        };
+        let mut tr = 1.0;
+        let mut t = t_min;
+        loop {
+            t -= F::ln(1.0 - rng.sample_0_1()) * self.inv_max_density / self.sigma_t;
+            if t >= t_max {
+                break;
+            }
+            let density = self.density(&medium_ray.at(t));
+            tr *= 1.0 - F::max(0.0, density * self.inv_max_density);
+            // Russian roulette low transmittance estimates.
+            if tr < 0.1 {
+                let q = F::max(0.05, 1.0 - tr);
+                if rng.sample_0_1() < q {
+                    return black();
+                }
+                tr /= 1.0 - q;
+            }
+        }
+        color3(tr, tr, tr)
+    }
+
+    /// Samples a real collision with delta tracking.
+    fn sample(&self, ray: &Ray, rng: &RngGen) -> (Color3, Option<Interaction>) {
+        let (medium_ray, t_min, t_max) = match self.clip_ray(ray) {
+            Some(clipped) => clipped,
+            None => return (color3(1.0, 1.0, 1.0), None),
+        };
+        let len = ray.direction.magnitude();
+        let mut t = t_min;
+        loop {
+            t -= F::ln(1.0 - rng.sample_0_1()) * self.inv_max_density / self.sigma_t;
+            if t >= t_max {
+                break;
+            }
+            if self.density(&medium_ray.at(t)) * self.inv_max_density > rng.sample_0_1() {
+                let inter = Interaction::new_medium(
+                    ray.at(t / len),
+                    -ray.direction,
+                    ray.time,
+                    ray.medium.clone(),
+                    self.phase.clone(),
+                );
+                return (self.sigma_s / self.sigma_t, Some(inter));
+            }
+        }
+        (color3(1.0, 1.0, 1.0), None)
+    }
+}
+
+fn read_f32s(bytes: &[u8]) -> Vec<F> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as F)
+        .collect()
 }
@@ -0,0 +1,191 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rayon::prelude::*;
+
+use crate::{
+    camera::SimpleCamera,
+    color::{black, luminance, Color3},
+    common::*,
+    distributions::Distribution1D,
+    film::Film,
+    integrator::Integrator,
+    progress::ProgressReporter,
+    ray::Ray,
+    rng::RngGen,
+    sampler::hash,
+    scene::Scene,
+    tiles::for_each_in_order,
+    vector::*,
+};
+
+/// Everything one set of primary samples contributes to the image, as raster positions and
+/// radiance.
+type Contributions = Vec<(Point2, Color3)>;
+
+/// The scalar the Markov chains sample proportionally to.
+fn contribution(contributions: &Contributions) -> F {
+    contributions.iter().map(|(_, l)| luminance(l)).sum()
+}
+
+/// Primary sample space Metropolis light transport (Kelemen et al.). The estimator, e.g. a
+/// path or bidirectional integrator, is treated as a function of the uniform samples it
+/// consumes; Markov chains then mutate those samples, with small perturbations exploring the
+/// neighbourhood of paths that were found to carry light and occasional large steps starting
+/// afresh. This concentrates work on the paths that matter in hard lighting, such as light
+/// reaching the scene through a narrow gap.
+pub struct MltIntegrator {
+    estimator: Box<dyn Integrator + Send + Sync>,
+    /// Independent samples used to estimate the overall image brightness and to pick the
+    /// starting states of the chains.
+    n_bootstrap: S,
+    n_chains: S,
+    /// Standard deviation of small step perturbations.
+    sigma: F,
+    large_step_probability: F,
+}
+
+impl MltIntegrator {
+    /// The render's samples per pixel are the average number of mutations per pixel.
+    pub fn new(
+        estimator: Box<dyn Integrator + Send + Sync>,
+        n_bootstrap: S,
+        n_chains: S,
+        sigma: F,
+        large_step_probability: F,
+    ) -> Self {
+        Self {
+            estimator,
+            n_bootstrap,
+            n_chains,
+            sigma,
+            large_step_probability,
+        }
+    }
+
+    /// The primary sample vector for the `index`th of the render's bootstrap samples, which
+    /// also seeds the chains started from it, derived from the seed of `rng`.
+    fn primary_sampler(&self, rng: &RngGen, index: u64) -> RngGen {
+        let seed = hash(&[rng.seed(), index]);
+        RngGen::new_primary(seed, self.sigma, self.large_step_probability)
+    }
+
+    /// Picks a point on the film and evaluates the estimator for it, with every sample drawn
+    /// from `rng`.
+    fn l(&self, scene: &Scene, cam: &SimpleCamera, rng: &RngGen) -> Contributions {
+        let (x0, y0, x1, y1) = cam.pixel_bounds();
+        let p_raster = point2(
+            lerp(rng.sample_0_1(), x0 as F, x1 as F),
+            lerp(rng.sample_0_1(), y0 as F, y1 as F),
+        );
+        // `get_ray` aims through the pixel center, so offset back to the sampled position.
+        let mut ray = cam.get_ray(p_raster - point2(0.5, 0.5));
+        let (l, mut contributions) = self.estimator.li_with_splats(&mut ray, scene, rng);
+        contributions.push((p_raster, l));
+        contributions
+    }
+}
+
+fn add_contributions(splats: &mut Contributions, contributions: &Contributions, weight: F) {
+    for (p_raster, l) in contributions.iter() {
+        if *l != black() {
+            splats.push((*p_raster, l * weight));
+        }
+    }
+}
+
+impl Integrator for MltIntegrator {
+    fn preprocess(&mut self, scene: &Scene, cam: &SimpleCamera) {
+        self.estimator.preprocess(scene, cam);
+    }
+
+    /// Plain, unmutated estimate from the underlying integrator.
+    fn li(&self, ray: &mut Ray, scene: &Scene, depth: S, rng: &RngGen) -> Color3 {
+        self.estimator.li(ray, scene, depth, rng)
+    }
+
+    fn progressive(&self) -> bool {
+        false
+    }
+
+    fn render(
+        &self,
+        scene: &Scene,
+        cam: &SimpleCamera,
+        samples_per_pixel: S,
+        rng: &RngGen,
+    ) -> Vec<Color3> {
+        // The average contribution normalizes the chains, which only see relative values.
+        let bootstrap_weights: Vec<F> = (0..self.n_bootstrap)
+            .into_par_iter()
+            .map(|i| contribution(&self.l(scene, cam, &self.primary_sampler(rng, i as u64))))
+            .collect();
+        let bootstrap = Distribution1D::new(&bootstrap_weights, self.n_bootstrap);
+        let b = bootstrap.func_int;
+        if b <= 0.0 || self.n_chains == 0 {
+            let (width, height) = cam.image_size();
+            return vec![black(); width * height];
+        }
+
+        let film = Film::new(cam.pixel_bounds(), cam.filter.clone());
+        let n_mutations = samples_per_pixel * film.width() * film.height();
+        let progress = ProgressReporter::new(self.n_chains, "Rendering");
+        // Each chain's splats are buffered and added to the film in chain order, so the sums
+        // don't depend on how the chains are spread over threads.
+        let work = |chain| {
+            let n_chain_mutations =
+                (chain + 1) * n_mutations / self.n_chains - chain * n_mutations / self.n_chains;
+            let chain_seed = hash(&[rng.seed(), (self.n_bootstrap + chain) as u64]);
+            let mut chain_rng = StdRng::seed_from_u64(chain_seed);
+
+            // Start from a bootstrap sample chosen in proportion to its contribution, which
+            // avoids start-up bias; replaying its seed recreates the same path.
+            let (start, _, _) = bootstrap.sample_discrete(chain_rng.gen()).unwrap();
+            let sampler = self.primary_sampler(rng, start as u64);
+            let mut current = self.l(scene, cam, &sampler);
+            let mut current_f = contribution(&current);
+            let mut splats = vec![];
+
+            for _ in 0..n_chain_mutations {
+                sampler.start_iteration();
+                let proposed = self.l(scene, cam, &sampler);
+                let proposed_f = contribution(&proposed);
+                let accept = if current_f > 0.0 {
+                    F::min(1.0, proposed_f / current_f)
+                } else {
+                    1.0
+                };
+
+                // Record both states weighted by how likely each is to be the next one,
+                // rather than just the state the chain ends up in.
+                if accept > 0.0 && proposed_f > 0.0 {
+                    add_contributions(&mut splats, &proposed, accept / proposed_f);
+                }
+                if accept < 1.0 && current_f > 0.0 {
+                    add_contributions(&mut splats, &current, (1.0 - accept) / current_f);
+                }
+
+                if chain_rng.gen::<F>() < accept {
+                    current = proposed;
+                    current_f = proposed_f;
+                    sampler.accept();
+                } else {
+                    sampler.reject();
+                }
+            }
+            progress.update(1);
+            splats
+        };
+        for_each_in_order(
+            self.n_chains,
+            || false,
+            work,
+            |_, splats: Contributions| {
+                for (p_raster, l) in splats {
+                    film.add_splat(&p_raster, l);
+                }
+            },
+        );
+        progress.done();
+
+        film.image(b / samples_per_pixel as F)
+    }
+}
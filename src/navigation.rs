@@ -0,0 +1,111 @@
+use std::time::Instant;
+
+use winit::event::VirtualKeyCode;
+use winit_input_helper::WinitInputHelper;
+
+use crate::camera::SimpleCamera;
+use crate::common::*;
+use crate::vector::*;
+
+const LEFT_BUTTON: usize = 0;
+const RIGHT_BUTTON: usize = 1;
+const MIDDLE_BUTTON: usize = 2;
+
+/// Don't let a slow frame turn a key press into a leap across the scene.
+const MAX_FRAME_TIME: F = 0.1;
+
+/// Moves a camera in response to input in the preview window:
+/// - dragging with the left button orbits around the point the camera looks at,
+/// - dragging with the right or middle button, or with shift and the left, pans,
+/// - the scroll wheel dollies towards or away from that point,
+/// - W, A, S and D fly forwards, left, back and right, and Q and E down and up.
+pub struct CameraController {
+    /// Radians turned per pixel dragged.
+    pub orbit_speed: F,
+    /// Fraction of the distance to the target moved per notch of the scroll wheel.
+    pub dolly_speed: F,
+    /// Fraction of the distance to the target flown per second.
+    pub fly_speed: F,
+    last_update: Instant,
+}
+
+impl CameraController {
+    pub fn new() -> Self {
+        Self {
+            orbit_speed: 0.005,
+            dolly_speed: 0.1,
+            fly_speed: 0.5,
+            last_update: Instant::now(),
+        }
+    }
+
+    /// Applies the input since the last update to `cam`, returning whether it moved.
+    pub fn update(&mut self, input: &WinitInputHelper, cam: &mut SimpleCamera) -> bool {
+        let now = Instant::now();
+        let dt = (now - self.last_update).as_secs_f32().min(MAX_FRAME_TIME);
+        self.last_update = now;
+
+        let mut origin = cam.position();
+        let mut target = cam.target();
+        let distance = distance3d(&origin, &target);
+        let forward = cam.forward();
+        let right = forward.cross(&vec3(0.0, 1.0, 0.0)).normalize();
+        let up = right.cross(&forward);
+
+        let (dx, dy) = input.mouse_diff();
+        let dragging = dx != 0.0 || dy != 0.0;
+        let panning = input.mouse_held(RIGHT_BUTTON)
+            || input.mouse_held(MIDDLE_BUTTON)
+            || (input.mouse_held(LEFT_BUTTON) && input.held_shift());
+        if dragging && panning {
+            // Points at the target's depth follow the cursor.
+            let units_per_pixel = 2.0 * distance * deg2rad(cam.fov / 2.0).tan() / cam.height as F;
+            let offset = (-right * dx + up * dy) * units_per_pixel;
+            origin += offset;
+            target += offset;
+        } else if dragging && input.mouse_held(LEFT_BUTTON) {
+            let offset = origin - target;
+            let yaw = offset.x.atan2(offset.z) - dx * self.orbit_speed;
+            let max_pitch = PI / 2.0 - 0.01;
+            let pitch =
+                ((offset.y / distance).asin() + dy * self.orbit_speed).clamp(-max_pitch, max_pitch);
+            origin = target
+                + vec3(
+                    pitch.cos() * yaw.sin(),
+                    pitch.sin(),
+                    pitch.cos() * yaw.cos(),
+                ) * distance;
+        }
+
+        let scroll = input.scroll_diff();
+        if scroll != 0.0 {
+            let scale = (1.0 - self.dolly_speed).powf(scroll);
+            origin = target + (origin - target) * scale.max(1e-3 / distance);
+        }
+
+        let mut fly = Vec3::zeros();
+        for (key, direction) in [
+            (VirtualKeyCode::W, forward),
+            (VirtualKeyCode::S, -forward),
+            (VirtualKeyCode::D, right),
+            (VirtualKeyCode::A, -right),
+            (VirtualKeyCode::E, vec3(0.0, 1.0, 0.0)),
+            (VirtualKeyCode::Q, vec3(0.0, -1.0, 0.0)),
+        ] {
+            if input.key_held(key) {
+                fly += direction;
+            }
+        }
+        if fly != Vec3::zeros() {
+            let offset = fly.normalize() * (self.fly_speed * distance * dt);
+            origin += offset;
+            target += offset;
+        }
+
+        if origin == cam.position() && target == cam.target() {
+            return false;
+        }
+        cam.look_at(origin, target);
+        true
+    }
+}
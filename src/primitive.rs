@@ -5,6 +5,7 @@ use crate::common::*;
 use crate::interaction::Interaction;
 use crate::light::Light;
 use crate::material::*;
+use crate::media::MediumInterface;
 use crate::ray::Ray;
 use crate::rng::RngGen;
 use crate::shape::*;
@@ -12,7 +13,8 @@ use crate::shape::*;
 #[derive(Clone)]
 pub struct Primitive {
     pub shape: Arc<dyn Shape + Send + Sync>,
-    pub material: Arc<dyn Material + Send + Sync>,
+    /// Primitives without a material only mark the boundary between two participating media.
+    pub material: Option<Arc<dyn Material + Send + Sync>>,
     // TODO: add material, light properties
     pub light: Option<Arc<dyn Light + Send + Sync>>,
 }
@@ -20,7 +22,7 @@ pub struct Primitive {
 impl Primitive {
     pub fn new(
         shape: Arc<dyn Shape + Send + Sync>,
-        material: Arc<dyn Material + Send + Sync>,
+        material: Option<Arc<dyn Material + Send + Sync>>,
         light: Option<Arc<dyn Light + Send + Sync>>,
     ) -> Self {
         Self {
@@ -43,16 +45,27 @@ impl Shape for Primitive {
             .shape
             .intersect(&mut transformed_ray, test_alpha_texture)
         {
-            Some(mut inter) => {
+            Some(inter) => {
                 ray.t_max = transformed_ray.t_max;
+                let shape_data = self.shape.shape_data();
+                let mut inter = shape_data
+                    .object_to_world
+                    .forward_surface_interaction_transform(inter);
                 inter.primitive = Some(Arc::new(self.clone()));
-                self.material.calculate_bsdf(&mut inter);
-                Some(
-                    self.shape
-                        .shape_data()
-                        .object_to_world
-                        .forward_surface_interaction_transform(inter),
-                )
+                inter.medium_interface = if shape_data.medium_interface.is_transition() {
+                    Some(shape_data.medium_interface.clone())
+                } else {
+                    Some(MediumInterface::new_non_transition(ray.medium.clone()))
+                };
+                match self.material {
+                    Some(ref material) => {
+                        // Rebuild the shading frame now that it's in world space.
+                        inter.create_bsdf();
+                        material.calculate_bsdf(&mut inter);
+                    }
+                    None => inter.bsdf = None,
+                }
+                Some(inter)
             }
             None => None,
         }
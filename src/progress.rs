@@ -0,0 +1,99 @@
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::common::*;
+
+/// Silences every `ProgressReporter`, e.g. for scripted renders.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Don't redraw the bar more often than this.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+
+const BAR_WIDTH: S = 40;
+
+/// Draws a progress bar on stderr for a task made up of `total` units of work, such as tiles
+/// or iterations, which may be completed from several threads at once.
+pub struct ProgressReporter {
+    title: String,
+    total: S,
+    done: AtomicUsize,
+    start: Instant,
+    last_print: Mutex<Instant>,
+}
+
+impl ProgressReporter {
+    pub fn new(total: S, title: &str) -> Self {
+        let start = Instant::now();
+        Self {
+            title: title.to_string(),
+            total,
+            done: AtomicUsize::new(0),
+            start,
+            last_print: Mutex::new(start),
+        }
+    }
+
+    /// Records `n` more units as done.
+    pub fn update(&self, n: S) {
+        let done = self.done.fetch_add(n, Ordering::Relaxed) + n;
+        if QUIET.load(Ordering::Relaxed) {
+            return;
+        }
+        // Skip redrawing if another thread is or just was.
+        if let Ok(mut last_print) = self.last_print.try_lock() {
+            let now = Instant::now();
+            if now - *last_print >= REFRESH_INTERVAL {
+                *last_print = now;
+                self.print(done, false);
+            }
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Draws the final state of the bar and moves to the next line.
+    pub fn done(&self) {
+        if !QUIET.load(Ordering::Relaxed) {
+            self.print(self.done.load(Ordering::Relaxed), true);
+        }
+    }
+
+    fn print(&self, done: S, finished: bool) {
+        let fraction = if self.total > 0 {
+            (done as F / self.total as F).min(1.0)
+        } else {
+            1.0
+        };
+        let filled = (fraction * BAR_WIDTH as F) as S;
+        let elapsed = self.elapsed().as_secs_f32();
+        let timing = if finished {
+            format!("({:.1}s)", elapsed)
+        } else if fraction > 0.0 {
+            format!("({:.1}s|{:.1}s)", elapsed, elapsed / fraction - elapsed)
+        } else {
+            format!("({:.1}s)", elapsed)
+        };
+        let mut stderr = std::io::stderr().lock();
+        let _ = write!(
+            stderr,
+            "\r{}: [{}{}] {:3.0}% {}",
+            self.title,
+            "+".repeat(filled),
+            " ".repeat(BAR_WIDTH - filled),
+            fraction * 100.0,
+            timing
+        );
+        if finished {
+            let _ = writeln!(stderr);
+        }
+        let _ = stderr.flush();
+    }
+}
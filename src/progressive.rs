@@ -0,0 +1,89 @@
+use std::time::{Duration, Instant};
+
+use crate::camera::SimpleCamera;
+use crate::color::Color3;
+use crate::common::*;
+use crate::film::Film;
+use crate::integrator::{film_image, render_pass, Integrator};
+use crate::rng::RngGen;
+use crate::scene::Scene;
+use crate::tiles::Tile;
+
+/// An image refined a few samples per pixel at a time, so that it can be shown while it
+/// converges and stopped whenever it's good enough.
+pub struct ProgressiveRender {
+    film: Film,
+    /// Samples per pixel added by each pass.
+    pub samples_per_pass: S,
+    /// Passes rendered since the start or the last restart.
+    pub passes: S,
+    /// Passes started, counting cancelled ones, which have to be skipped over so that their
+    /// samples aren't repeated.
+    started_passes: S,
+    start: Instant,
+}
+
+impl ProgressiveRender {
+    pub fn new(cam: &SimpleCamera, samples_per_pass: S) -> Self {
+        Self {
+            film: Film::new(cam.pixel_bounds(), cam.filter.clone()),
+            samples_per_pass,
+            passes: 0,
+            started_passes: 0,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn samples_per_pixel(&self) -> S {
+        self.passes * self.samples_per_pass
+    }
+
+    /// Time spent since the start or the last restart.
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Adds another pass of samples to the image, calling `on_tile` as each tile is finished,
+    /// and returns whether the pass was finished. The tiles of a cancelled pass stay in the
+    /// image, where they just have more samples than the rest.
+    pub fn render_pass(
+        &mut self,
+        integrator: &(dyn Integrator + Send + Sync),
+        scene: &Scene,
+        cam: &SimpleCamera,
+        rng: &RngGen,
+        on_tile: &(dyn Fn(&Tile) + Sync),
+    ) -> bool {
+        let finished = render_pass(
+            integrator,
+            scene,
+            cam,
+            &self.film,
+            self.samples_per_pass,
+            self.started_passes * self.samples_per_pass,
+            rng,
+            on_tile,
+        );
+        self.started_passes += 1;
+        if finished {
+            self.passes += 1;
+        }
+        finished
+    }
+
+    /// The image so far, black before the first pass.
+    pub fn image(&self, integrator: &(dyn Integrator + Send + Sync)) -> Vec<Color3> {
+        film_image(integrator, &self.film, self.samples_per_pixel().max(1))
+    }
+
+    /// Throws away the samples taken so far, e.g. after the camera has moved.
+    pub fn restart(&mut self, integrator: &(dyn Integrator + Send + Sync), cam: &SimpleCamera) {
+        self.film = Film::new(cam.pixel_bounds(), cam.filter.clone());
+        if let Some(splats) = integrator.splats() {
+            splats.clear();
+        }
+        self.passes = 0;
+        self.started_passes = 0;
+        self.start = Instant::now();
+    }
+}
@@ -1,4 +1,7 @@
+use std::sync::Arc;
+
 use crate::common::*;
+use crate::media::Medium;
 use crate::vector::*;
 
 /// A simulated ray of light.
@@ -11,7 +14,8 @@ pub struct Ray {
     pub t_max: F,
     pub time: F,
 
-    // pub medium: Option<Arc<dyn Medium>>,
+    /// The participating medium the ray's origin lies in, if any.
+    pub medium: Option<Arc<dyn Medium + Send + Sync>>,
     pub has_differentials: bool,
     pub rx_origin: Option<Point3>,
     pub ry_origin: Option<Point3>,
@@ -33,7 +37,7 @@ impl Default for Ray {
             ry_origin: None,
             rx_direction: None,
             ry_direction: None,
-            // medium: None,
+            medium: None,
         }
     }
 }
@@ -45,7 +49,7 @@ impl Ray {
         t_min: F,
         t_max: F,
         time: F,
-        // medium: Option<Arc<dyn Medium>>,
+        medium: Option<Arc<dyn Medium + Send + Sync>>,
     ) -> Self {
         Self {
             origin,
@@ -54,13 +58,13 @@ impl Ray {
             t_max,
             time,
             has_differentials: false,
-            // medium,
+            medium,
             ..Default::default()
         }
     }
 
     /// Computes the location given a distance along the ray.
-    pub fn at(self, t: F) -> Point3 {
+    pub fn at(&self, t: F) -> Point3 {
         self.origin + self.direction * t
     }
 
@@ -1,24 +1,75 @@
-use rand_distr::{Distribution, Uniform};
+use std::sync::Mutex;
 
-use crate::common::{F, S};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand_distr::{Distribution, StandardNormal};
+
+use crate::common::{F, ONE_MINUS_EPSILON, S};
+use crate::sampler::{IndependentSampler, Sampler};
 use crate::vector::*;
 
+/// Primary sample streams. Estimators that build several subpaths draw each from its own
+/// stream, so a mutation that changes how many samples one subpath uses doesn't shift the
+/// samples of the others.
+pub const CAMERA_STREAM: S = 0;
+pub const LIGHT_STREAM: S = 1;
+pub const CONNECTION_STREAM: S = 2;
+const STREAM_COUNT: S = 3;
+
 pub struct RngGen {
-    uniform: Uniform<F>,
+    sampler: IndependentSampler,
+    /// When set, samples come from a replayable primary sample vector rather than the
+    /// sampler, e.g. for the Markov chains of Metropolis light transport.
+    primary: Option<Mutex<PrimarySampleVector>>,
 }
 
 impl RngGen {
+    /// A generator with a random seed.
     pub fn new() -> Self {
+        Self::new_seeded(rand::random())
+    }
+
+    /// A generator that gives the same samples for the same seed, however the work is spread
+    /// over threads, as long as each pixel sample or other unit of parallel work starts its
+    /// own sequence. See `Sampler::start_pixel_sample` and `start_sequence`.
+    pub fn new_seeded(seed: u64) -> Self {
+        Self {
+            sampler: IndependentSampler::new(seed),
+            primary: None,
+        }
+    }
+
+    /// A generator whose samples are the entries of a primary sample vector seeded with
+    /// `seed`, which can be mutated and rolled back. See `PrimarySampleVector`.
+    pub fn new_primary(seed: u64, sigma: F, large_step_probability: F) -> Self {
         Self {
-            uniform: Uniform::new(0.0, 1.0),
+            sampler: IndependentSampler::new(seed),
+            primary: Some(Mutex::new(PrimarySampleVector::new(
+                seed,
+                sigma,
+                large_step_probability,
+            ))),
+        }
+    }
+
+    /// The seed the samples are derived from, for seeding other generators from the same one.
+    pub fn seed(&self) -> u64 {
+        self.sampler.seed
+    }
+
+    /// Restarts the samples on this thread at the `index`th of a set of sequences for
+    /// parallel work other than pixel samples, e.g. photons. Has no effect on a primary
+    /// sample vector.
+    pub fn start_sequence(&self, index: S) {
+        if self.primary.is_none() {
+            self.sampler.start_sequence(index as u64);
         }
     }
 
     pub fn sample_0_1(&self) -> F {
-        self.uniform.sample(&mut rand::thread_rng())
+        self.get_1d()
     }
     pub fn sample_neg1_1(&self) -> F {
-        self.uniform.sample(&mut rand::thread_rng()) * 2.0 - 1.0
+        self.sample_0_1() * 2.0 - 1.0
     }
     pub fn uniform_sample_point2(&self) -> Point2 {
         point2(self.sample_0_1(), self.sample_0_1())
@@ -31,4 +82,147 @@ impl RngGen {
         }
         out
     }
+
+    /// Switches to drawing samples from the start of the given stream. Has no effect unless
+    /// backed by a primary sample vector.
+    pub fn start_stream(&self, index: S) {
+        if let Some(ref primary) = self.primary {
+            primary.lock().unwrap().start_stream(index);
+        }
+    }
+
+    /// Proposes a mutation of the primary sample vector and rewinds to the camera stream.
+    pub fn start_iteration(&self) {
+        if let Some(ref primary) = self.primary {
+            primary.lock().unwrap().start_iteration();
+        }
+    }
+
+    pub fn accept(&self) {
+        if let Some(ref primary) = self.primary {
+            primary.lock().unwrap().accept();
+        }
+    }
+
+    pub fn reject(&self) {
+        if let Some(ref primary) = self.primary {
+            primary.lock().unwrap().reject();
+        }
+    }
+}
+
+impl Sampler for RngGen {
+    /// Has no effect on a primary sample vector, whose samples are only changed by mutations.
+    fn start_pixel_sample(&self, pixel: (S, S), index: S) {
+        if self.primary.is_none() {
+            self.sampler.start_pixel_sample(pixel, index);
+        }
+    }
+
+    fn get_1d(&self) -> F {
+        match self.primary {
+            Some(ref primary) => primary.lock().unwrap().next(),
+            None => self.sampler.get_1d(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct PrimarySample {
+    value: F,
+    last_modification_iteration: S,
+    value_backup: F,
+    modify_backup: S,
+}
+
+/// The vector of uniform samples a path is built from, mutated as in Kelemen et al.: either
+/// every sample is replaced ("large steps") or each is perturbed by a small normally
+/// distributed offset. Samples are generated lazily, so untouched ones catch up on the
+/// mutations they missed when they're next used, and a rejected mutation can be undone.
+struct PrimarySampleVector {
+    rng: StdRng,
+    sigma: F,
+    large_step_probability: F,
+    x: Vec<PrimarySample>,
+    current_iteration: S,
+    large_step: bool,
+    last_large_step_iteration: S,
+    stream_index: S,
+    sample_index: S,
+}
+
+impl PrimarySampleVector {
+    fn new(seed: u64, sigma: F, large_step_probability: F) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            sigma,
+            large_step_probability,
+            x: vec![],
+            current_iteration: 0,
+            large_step: true,
+            last_large_step_iteration: 0,
+            stream_index: 0,
+            sample_index: 0,
+        }
+    }
+
+    fn start_iteration(&mut self) {
+        self.current_iteration += 1;
+        self.large_step = self.rng.gen::<F>() < self.large_step_probability;
+        self.start_stream(CAMERA_STREAM);
+    }
+
+    fn start_stream(&mut self, index: S) {
+        self.stream_index = index;
+        self.sample_index = 0;
+    }
+
+    fn next(&mut self) -> F {
+        let index = self.stream_index + STREAM_COUNT * self.sample_index;
+        self.sample_index += 1;
+        self.ensure_ready(index);
+        self.x[index].value
+    }
+
+    fn ensure_ready(&mut self, index: S) {
+        if index >= self.x.len() {
+            self.x.resize(index + 1, PrimarySample::default());
+        }
+        let xi = &mut self.x[index];
+        // Catch up on any large step made since the sample was last used.
+        if xi.last_modification_iteration < self.last_large_step_iteration {
+            xi.value = self.rng.gen();
+            xi.last_modification_iteration = self.last_large_step_iteration;
+        }
+
+        xi.value_backup = xi.value;
+        xi.modify_backup = xi.last_modification_iteration;
+        if self.large_step {
+            xi.value = self.rng.gen();
+        } else {
+            // Apply all the small steps missed since, which sum to a single wider one.
+            let n_small = (self.current_iteration - xi.last_modification_iteration) as F;
+            let normal: F = StandardNormal.sample(&mut self.rng);
+            xi.value += normal * self.sigma * n_small.sqrt();
+            xi.value -= xi.value.floor();
+            xi.value = xi.value.min(ONE_MINUS_EPSILON);
+        }
+        xi.last_modification_iteration = self.current_iteration;
+    }
+
+    fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step_iteration = self.current_iteration;
+        }
+    }
+
+    fn reject(&mut self) {
+        for xi in self.x.iter_mut() {
+            if xi.last_modification_iteration == self.current_iteration {
+                xi.value = xi.value_backup;
+                xi.last_modification_iteration = xi.modify_backup;
+            }
+        }
+        self.current_iteration -= 1;
+    }
 }
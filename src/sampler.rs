@@ -0,0 +1,86 @@
+use std::cell::RefCell;
+
+use rand::RngCore;
+use rand_pcg::Pcg32;
+
+use crate::common::*;
+use crate::vector::*;
+
+/// Hands out the uniform random numbers a sample is built from. Every pixel sample starts its
+/// own sequence, so what it draws doesn't depend on which thread renders it or what ran
+/// before.
+pub trait Sampler {
+    /// Starts the sequence for the `index`th sample of `pixel`.
+    fn start_pixel_sample(&self, pixel: (S, S), index: S);
+    /// A number in `[0, 1)`.
+    fn get_1d(&self) -> F;
+    fn get_2d(&self) -> Point2 {
+        point2(self.get_1d(), self.get_1d())
+    }
+    /// Where in the pixel to place the sample, as offsets in `[0, 1)` from its corner.
+    fn get_pixel_2d(&self) -> Point2 {
+        self.get_2d()
+    }
+}
+
+/// Numbers available to each pixel sample before its sequence runs into the next sample's.
+const SAMPLE_STRIDE: u64 = 1 << 16;
+
+/// PCG's default initial state; sequences differ by their stream instead.
+const PCG_STATE: u64 = 0x853c_49e6_748f_ea9b;
+
+thread_local! {
+    /// The sequence being drawn from on this thread, set by `start_pixel_sample`.
+    static PCG: RefCell<Pcg32> = RefCell::new(Pcg32::new(PCG_STATE, 0));
+}
+
+/// Mixes the bits of `values` into a well distributed hash, with the finalizer of SplitMix64.
+pub fn hash(values: &[u64]) -> u64 {
+    values.iter().fold(0x9e37_79b9_7f4a_7c15, |h, &v| {
+        let mut z = (h ^ v).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    })
+}
+
+/// Independent uniform random numbers from a PCG32 generator, with a stream for each pixel
+/// and seed, advanced to the sample's place in it. The state lives with the thread, so a
+/// shared sampler can be drawn from by every render thread at once.
+#[derive(Clone, Copy, Debug)]
+pub struct IndependentSampler {
+    pub seed: u64,
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u64) -> Self {
+        Self { seed }
+    }
+
+    /// Starts a sequence for work that isn't tied to a pixel, such as tracing the `index`th
+    /// photon, distinct from every pixel's.
+    pub fn start_sequence(&self, index: u64) {
+        self.start(hash(&[u64::MAX, index, self.seed]), 0);
+    }
+
+    fn start(&self, stream: u64, offset: u64) {
+        PCG.with(|pcg| {
+            let mut pcg = pcg.borrow_mut();
+            *pcg = Pcg32::new(PCG_STATE, stream);
+            pcg.advance(offset);
+        });
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&self, pixel: (S, S), index: S) {
+        let stream = hash(&[pixel.0 as u64, pixel.1 as u64, self.seed]);
+        self.start(stream, index as u64 * SAMPLE_STRIDE);
+    }
+
+    fn get_1d(&self) -> F {
+        // The top 24 bits, which f32 holds exactly, so the result stays below one.
+        let bits = PCG.with(|pcg| pcg.borrow_mut().next_u32()) >> 8;
+        bits as F * (1.0 / (1u32 << 24) as F)
+    }
+}
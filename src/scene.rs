@@ -1,6 +1,13 @@
 use crate::{
-    aabb::AABB3, interaction::Interaction, light::Light, primitive::Primitive, ray::Ray,
+    aabb::AABB3,
+    color::{color3, Color3},
+    interaction::Interaction,
+    light::Light,
+    primitive::Primitive,
+    ray::Ray,
+    rng::RngGen,
     shape::Shape,
+    vector::point3,
 };
 
 pub struct Scene {
@@ -29,6 +36,22 @@ impl Scene {
         result
     }
 
+    /// Finds the first intersection with a surface that has a BSDF, skipping over medium
+    /// boundaries and accumulating the transmittance of the media passed through.
+    pub fn intersect_tr(&self, ray: &mut Ray, rng: &RngGen) -> (Option<Interaction>, Color3) {
+        let mut tr = color3(1.0, 1.0, 1.0);
+        loop {
+            let inter = self.intersect(ray);
+            if let Some(ref medium) = ray.medium {
+                tr.component_mul_assign(&medium.transmittance(ray, rng));
+            }
+            match inter {
+                Some(inter) if inter.bsdf.is_none() => *ray = inter.spawn_ray(ray.direction),
+                inter => return (inter, tr),
+            }
+        }
+    }
+
     pub fn intersect_p(&self, ray: &Ray) -> bool {
         for node in self.objs.iter() {
             if node.intersect_p(ray, false) {
@@ -39,8 +62,13 @@ impl Scene {
     }
 
     pub fn world_bounds(&self) -> AABB3 {
-        let mut bounds = AABB3::default();
-        for node in self.objs.iter() {
+        // Start from the first object rather than AABB3::default(), which is infinitely large.
+        let mut objs = self.objs.iter();
+        let mut bounds = match objs.next() {
+            Some(node) => node.object_bound(),
+            None => AABB3::from(point3(0.0, 0.0, 0.0)),
+        };
+        for node in objs {
             bounds = bounds.combine(node.object_bound());
         }
         bounds
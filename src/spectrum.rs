@@ -0,0 +1,400 @@
+use std::{cell::RefCell, collections::HashMap, sync::OnceLock};
+
+use crate::{
+    color::{blackbody_normalized, luminance, Color3},
+    colorspace::working_space,
+    common::*,
+    vector::*,
+};
+
+extern crate nalgebra as na;
+
+pub const LAMBDA_MIN: F = 360.0;
+pub const LAMBDA_MAX: F = 830.0;
+/// Wavelengths carried by each path: a hero wavelength and evenly spaced companions.
+pub const N_SPECTRUM_SAMPLES: S = 4;
+
+/// A spectral quantity, e.g. radiance or path throughput, at the wavelengths of a
+/// `SampledWavelengths`.
+pub type SampledSpectrum = na::SVector<F, N_SPECTRUM_SAMPLES>;
+
+pub fn sampled_spectrum(v: F) -> SampledSpectrum {
+    SampledSpectrum::repeat(v)
+}
+
+/// The wavelengths a path carries, in nanometers, and the densities they were sampled with.
+#[derive(Clone, Copy, Debug)]
+pub struct SampledWavelengths {
+    pub lambda: [F; N_SPECTRUM_SAMPLES],
+    pub pdf: SampledSpectrum,
+}
+
+impl SampledWavelengths {
+    /// Hero wavelength sampling: one wavelength is importance sampled over the visible range
+    /// and the others are found by rotating `u` by even steps, so together they cover the
+    /// spectrum evenly.
+    pub fn sample_visible(u: F) -> Self {
+        let mut lambda = [0.0; N_SPECTRUM_SAMPLES];
+        let mut pdf = SampledSpectrum::zeros();
+        for i in 0..N_SPECTRUM_SAMPLES {
+            let up = (u + i as F / N_SPECTRUM_SAMPLES as F).fract();
+            lambda[i] = sample_visible_wavelength(up);
+            pdf[i] = visible_wavelength_pdf(lambda[i]);
+        }
+        Self { lambda, pdf }
+    }
+
+    /// Whether only the hero wavelength is left.
+    pub fn secondary_terminated(&self) -> bool {
+        self.pdf.iter().skip(1).all(|&p| p == 0.0)
+    }
+
+    /// Drops all but the hero wavelength, for when the path can no longer be shared, e.g.
+    /// after refraction through a dispersive interface.
+    pub fn terminate_secondary(&mut self) {
+        if self.secondary_terminated() {
+            return;
+        }
+        for i in 1..N_SPECTRUM_SAMPLES {
+            self.pdf[i] = 0.0;
+        }
+        self.pdf[0] /= N_SPECTRUM_SAMPLES as F;
+    }
+
+    /// Converts a spectral estimate made at these wavelengths to CIE XYZ, normalized so that
+    /// a constant spectrum of one has Y = 1.
+    pub fn to_xyz(self, s: &SampledSpectrum) -> Vec3 {
+        let mut xyz = Vec3::zeros();
+        for i in 0..N_SPECTRUM_SAMPLES {
+            if self.pdf[i] != 0.0 {
+                xyz += cie_xyz(self.lambda[i]) * (s[i] / self.pdf[i]);
+            }
+        }
+        xyz / (N_SPECTRUM_SAMPLES as F * cie_y_integral())
+    }
+
+    pub fn to_rgb(self, s: &SampledSpectrum) -> Color3 {
+        working_space().color_from_xyz(&self.to_xyz(s))
+    }
+}
+
+/// Density over wavelength roughly following the eye's sensitivity, which keeps color noise
+/// down compared to sampling uniformly (Radziszewski et al.).
+pub fn visible_wavelength_pdf(lambda: F) -> F {
+    if !(LAMBDA_MIN..=LAMBDA_MAX).contains(&lambda) {
+        return 0.0;
+    }
+    0.003_939_804 / F::cosh(0.0072 * (lambda - 538.0)).powi(2)
+}
+
+pub fn sample_visible_wavelength(u: F) -> F {
+    538.0 - 138.888_89 * F::atanh(0.856_910_6 - 1.827_502 * u)
+}
+
+fn piecewise_gaussian(x: F, mu: F, sigma_lo: F, sigma_hi: F) -> F {
+    let t = (x - mu) / if x < mu { sigma_lo } else { sigma_hi };
+    F::exp(-0.5 * t * t)
+}
+
+/// The CIE 1931 color matching functions, using the multi-lobe fit of Wyman et al., "Simple
+/// Analytic Approximations to the CIE XYZ Color Matching Functions".
+pub fn cie_xyz(lambda: F) -> Vec3 {
+    let x = 1.056 * piecewise_gaussian(lambda, 599.8, 37.9, 31.0)
+        + 0.362 * piecewise_gaussian(lambda, 442.0, 16.0, 26.7)
+        - 0.065 * piecewise_gaussian(lambda, 501.1, 20.4, 26.2);
+    let y = 0.821 * piecewise_gaussian(lambda, 568.8, 46.9, 40.5)
+        + 0.286 * piecewise_gaussian(lambda, 530.9, 16.3, 31.1);
+    let z = 1.217 * piecewise_gaussian(lambda, 437.0, 11.8, 36.0)
+        + 0.681 * piecewise_gaussian(lambda, 459.0, 26.0, 13.8);
+    vec3(x, y, z)
+}
+
+/// Integral of the Y matching function over the visible range, in nanometers.
+pub fn cie_y_integral() -> F {
+    static INTEGRAL: OnceLock<F> = OnceLock::new();
+    *INTEGRAL.get_or_init(|| {
+        (LAMBDA_MIN as S..=LAMBDA_MAX as S)
+            .map(|l| cie_xyz(l as F).y)
+            .sum()
+    })
+}
+
+/// CIE standard illuminant D65, the white point of sRGB, at 10nm intervals from 360nm.
+const CIE_D65: [F; 48] = [
+    46.64, 52.09, 49.98, 54.65, 82.75, 91.49, 93.43, 86.68, 104.86, 117.01, 117.81, 114.86, 115.92,
+    108.81, 109.35, 107.80, 104.79, 107.69, 104.41, 104.05, 100.00, 96.33, 95.79, 88.69, 90.01,
+    89.60, 87.70, 83.29, 83.70, 80.03, 80.21, 82.28, 78.28, 69.72, 71.61, 74.35, 61.60, 69.89,
+    75.09, 63.59, 46.42, 66.81, 63.38, 64.30, 59.45, 51.96, 57.44, 60.31,
+];
+
+/// D65 scaled so that it has a luminance of one.
+fn d65(lambda: F) -> F {
+    static SCALE: OnceLock<F> = OnceLock::new();
+    let scale = *SCALE.get_or_init(|| {
+        let y: F = (LAMBDA_MIN as S..=LAMBDA_MAX as S)
+            .map(|l| d65_unscaled(l as F) * cie_xyz(l as F).y)
+            .sum();
+        cie_y_integral() / y
+    });
+    d65_unscaled(lambda) * scale
+}
+
+fn d65_unscaled(lambda: F) -> F {
+    let x = ((lambda - LAMBDA_MIN) / 10.0).clamp(0.0, (CIE_D65.len() - 1) as F);
+    let i = (x as S).min(CIE_D65.len() - 2);
+    lerp(x - i as F, CIE_D65[i], CIE_D65[i + 1])
+}
+
+/// A spectral distribution over wavelength, e.g. a reflectance or an emission spectrum.
+pub trait Spectrum {
+    /// Value at `lambda`, in nanometers.
+    fn evaluate(&self, lambda: F) -> F;
+
+    fn sample(&self, wavelengths: &SampledWavelengths) -> SampledSpectrum {
+        SampledSpectrum::from_fn(|i, _| self.evaluate(wavelengths.lambda[i]))
+    }
+}
+
+/// Color of an emission spectrum in the working space, in the same units the RGB renderers use.
+pub fn spectrum_to_rgb(spectrum: &dyn Spectrum) -> Color3 {
+    let xyz: Vec3 = (LAMBDA_MIN as S..=LAMBDA_MAX as S)
+        .map(|l| cie_xyz(l as F) * spectrum.evaluate(l as F))
+        .sum();
+    working_space().color_from_xyz(&(xyz / cie_y_integral()))
+}
+
+pub struct ConstantSpectrum {
+    pub c: F,
+}
+
+impl Spectrum for ConstantSpectrum {
+    fn evaluate(&self, lambda: F) -> F {
+        self.c
+    }
+}
+
+/// Emission of a black body at temperature `t` in Kelvin, scaled to peak at one.
+pub struct BlackbodySpectrum {
+    pub t: F,
+}
+
+impl Spectrum for BlackbodySpectrum {
+    fn evaluate(&self, lambda: F) -> F {
+        blackbody_normalized(&[lambda], self.t)[0]
+    }
+}
+
+/// A smooth spectrum `s(λ) = sigmoid(c0 t² + c1 t + c2)`, with `t` the wavelength mapped to
+/// `[0, 1]` over the visible range. Any RGB reflectance can be matched by one of these, and
+/// being smooth and bounded they look like real materials (Jakob & Hanika, "A Low-Dimensional
+/// Function Space for Efficient Spectral Upsampling").
+#[derive(Clone, Copy, Debug)]
+pub struct RgbSigmoidPolynomial {
+    c: [F; 3],
+}
+
+fn sigmoid(x: f64) -> f64 {
+    if x.is_infinite() {
+        return if x > 0.0 { 1.0 } else { 0.0 };
+    }
+    0.5 + x / (2.0 * (1.0 + x * x).sqrt())
+}
+
+fn normalized_wavelength(lambda: F) -> f64 {
+    ((lambda - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN)) as f64
+}
+
+/// Integration nodes for fitting: wavelengths in 5nm steps and the working space RGB each
+/// contributes under D65, so that summing `s(λ)` times these gives the RGB of reflectance `s`.
+fn fitting_nodes() -> &'static [(f64, na::Vector3<f64>)] {
+    static NODES: OnceLock<Vec<(f64, na::Vector3<f64>)>> = OnceLock::new();
+    NODES.get_or_init(|| {
+        let steps = ((LAMBDA_MAX - LAMBDA_MIN) / 5.0) as S;
+        let nodes: Vec<(F, Color3)> = (0..=steps)
+            .map(|i| {
+                let lambda = LAMBDA_MIN + 5.0 * i as F;
+                (
+                    lambda,
+                    working_space().color_from_xyz(&(cie_xyz(lambda) * d65(lambda))),
+                )
+            })
+            .collect();
+        let white: Color3 = nodes.iter().map(|(_, rgb)| rgb).sum();
+        nodes
+            .iter()
+            .map(|(lambda, rgb)| {
+                let rgb = rgb.component_div(&white);
+                (normalized_wavelength(*lambda), rgb.map(|c| c as f64))
+            })
+            .collect()
+    })
+}
+
+impl RgbSigmoidPolynomial {
+    /// Fits the coefficients to a reflectance given in the working space, with components in
+    /// `[0, 1]`, using Gauss-Newton iterations started from a flat spectrum of the same
+    /// brightness.
+    pub fn fit(rgb: &Color3) -> Self {
+        let target = rgb.map(|c| (c as f64).clamp(1e-4, 1.0 - 1e-4));
+        let mean = target.mean();
+        let mut c = na::Vector3::new(0.0, 0.0, (mean - 0.5) / (mean * (1.0 - mean)).sqrt());
+        for _ in 0..30 {
+            let mut residual = -target;
+            let mut jacobian = na::Matrix3::<f64>::zeros();
+            for (t, weight) in fitting_nodes() {
+                let x = c[0] * t * t + c[1] * t + c[2];
+                residual += weight * sigmoid(x);
+                let ds = 0.5 / (1.0 + x * x).powf(1.5);
+                let dx = na::RowVector3::new(t * t, *t, 1.0) * ds;
+                jacobian += weight * dx;
+            }
+            if residual.norm() < 1e-6 {
+                break;
+            }
+            match jacobian.try_inverse() {
+                Some(inverse) => c -= inverse * residual,
+                None => break,
+            }
+            // Extremely saturated colors push the coefficients off towards infinity.
+            if c.amax() > 1e4 {
+                c *= 1e4 / c.amax();
+                break;
+            }
+        }
+        Self {
+            c: [c[0] as F, c[1] as F, c[2] as F],
+        }
+    }
+}
+
+impl Spectrum for RgbSigmoidPolynomial {
+    fn evaluate(&self, lambda: F) -> F {
+        let t = normalized_wavelength(lambda);
+        sigmoid(self.c[0] as f64 * t * t + self.c[1] as f64 * t + self.c[2] as f64) as F
+    }
+}
+
+thread_local! {
+    /// Fits are comparatively slow, and most colors come from a few constant textures.
+    static FIT_CACHE: RefCell<HashMap<[u32; 3], RgbSigmoidPolynomial>> =
+        RefCell::new(HashMap::new());
+}
+
+/// The fit for `rgb`, with components in `[0, 1]`, quantized so repeated colors hit the cache.
+fn cached_fit(rgb: &Color3) -> RgbSigmoidPolynomial {
+    const LEVELS: F = 4096.0;
+    let key = [0, 1, 2].map(|i| (rgb[i].clamp(0.0, 1.0) * LEVELS).round() as u32);
+    FIT_CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if cache.len() > 1 << 16 {
+            cache.clear();
+        }
+        *cache.entry(key).or_insert_with(|| {
+            RgbSigmoidPolynomial::fit(&Color3::from_fn(|i, _| key[i] as F / LEVELS))
+        })
+    })
+}
+
+/// Upsampled RGB without an upper bound, e.g. a BSDF value or a path throughput: a
+/// reflectance spectrum scaled up as needed.
+pub struct RgbUnboundedSpectrum {
+    scale: F,
+    rsp: RgbSigmoidPolynomial,
+}
+
+impl RgbUnboundedSpectrum {
+    pub fn new(rgb: &Color3) -> Self {
+        let m = rgb.max();
+        // Halving keeps saturated colors away from the edges of what can be fitted.
+        let scale = 2.0 * m;
+        let rsp = if scale > 0.0 {
+            cached_fit(&(rgb.map(|c| c.max(0.0)) / scale))
+        } else {
+            RgbSigmoidPolynomial { c: [0.0; 3] }
+        };
+        Self { scale, rsp }
+    }
+}
+
+impl Spectrum for RgbUnboundedSpectrum {
+    fn evaluate(&self, lambda: F) -> F {
+        if self.scale == 0.0 {
+            return 0.0;
+        }
+        self.scale * self.rsp.evaluate(lambda)
+    }
+}
+
+/// Upsampled RGB emission: the matching reflectance lit by D65, so that white light comes
+/// out with the spectrum of daylight rather than an unphysical flat one.
+pub struct RgbIlluminantSpectrum {
+    unbounded: RgbUnboundedSpectrum,
+}
+
+impl RgbIlluminantSpectrum {
+    pub fn new(rgb: &Color3) -> Self {
+        Self {
+            unbounded: RgbUnboundedSpectrum::new(rgb),
+        }
+    }
+}
+
+impl Spectrum for RgbIlluminantSpectrum {
+    fn evaluate(&self, lambda: F) -> F {
+        self.unbounded.evaluate(lambda) * d65(lambda)
+    }
+}
+
+/// Luminance of a spectrum relative to the RGB renderers, for scaling spectral emission to
+/// match the brightness an RGB light would have.
+pub fn spectrum_luminance(spectrum: &dyn Spectrum) -> F {
+    luminance(&spectrum_to_rgb(spectrum))
+}
+
+/// Linear interpolation between tabulated values, e.g. measured data. Clamps to the first
+/// and last values outside the table.
+pub struct PiecewiseLinearSpectrum {
+    lambdas: Vec<F>,
+    values: Vec<F>,
+}
+
+impl PiecewiseLinearSpectrum {
+    /// `lambdas` must be in increasing order, with a value for each.
+    pub fn new(lambdas: &[F], values: &[F]) -> Self {
+        assert_eq!(lambdas.len(), values.len());
+        assert!(!lambdas.is_empty());
+        Self {
+            lambdas: lambdas.to_vec(),
+            values: values.to_vec(),
+        }
+    }
+}
+
+impl Spectrum for PiecewiseLinearSpectrum {
+    fn evaluate(&self, lambda: F) -> F {
+        let n = self.lambdas.len();
+        if lambda <= self.lambdas[0] {
+            return self.values[0];
+        }
+        if lambda >= self.lambdas[n - 1] {
+            return self.values[n - 1];
+        }
+        let i = self.lambdas.partition_point(|&l| l <= lambda) - 1;
+        let t = (lambda - self.lambdas[i]) / (self.lambdas[i + 1] - self.lambdas[i]);
+        lerp(t, self.values[i], self.values[i + 1])
+    }
+}
+
+/// Reduces a spectrum to working space RGB the way a reflectance is seen under D65, so that a
+/// constant spectrum keeps its value in every channel. Used for quantities such as indices
+/// of refraction when rendering in RGB.
+pub fn reflectance_to_rgb(spectrum: &dyn Spectrum) -> Color3 {
+    let rgb: na::Vector3<f64> = fitting_nodes()
+        .iter()
+        .map(|(t, weight)| {
+            let lambda = LAMBDA_MIN + *t as F * (LAMBDA_MAX - LAMBDA_MIN);
+            weight * spectrum.evaluate(lambda) as f64
+        })
+        .sum();
+    rgb.map(|c| c as F)
+}
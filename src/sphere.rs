@@ -75,23 +75,21 @@ impl Shape for Sphere {
         if p.x == 0.0 && p.y == 0.0 {
             p.x = 1e-5 * self.radius;
         }
-        let n = normal3(p.x, p.y, p.z);
         let theta = F::acos((p.z / self.radius).clamp(-1.0, 1.0));
+        let phi = match F::atan2(p.y, p.x) {
+            x if x < 0.0 => x + 2.0 * PI,
+            x => x,
+        };
 
         let inv_z = 1.0 / F::sqrt(p.x * p.x + p.y * p.y);
         let cos_phi = p.x * inv_z;
         let sin_phi = p.y * inv_z;
-        let u = match F::atan2(p.x, p.y) / (2.0 * PI) {
-            x if x < 0.0 => x + 1.0,
-            x => x,
-        };
-        let v = theta / PI;
+        // v runs from the south pole to the north pole so that dpdu x dpdv faces outward.
+        let u = phi / (2.0 * PI);
+        let v = 1.0 - theta / PI;
         let dpdu = vec3(-2.0 * PI * p.y, 2.0 * PI * p.x, 0.0);
-        let dpdv = vec3(p.z * cos_phi, p.z * sin_phi, -self.radius * theta.sin()) * PI;
-        // let mi;
-        // if self.shape_data.medium_interface.is_transition() { mi = self.shape_data.medium_interface; }
-        // else { mi = MediumInterface::new_non_transition(ray.medium) }
-        Some(Interaction::new(
+        let dpdv = vec3(p.z * cos_phi, p.z * sin_phi, -self.radius * theta.sin()) * -PI;
+        let mut inter = Interaction::new(
             p,
             -ray.direction,
             point2(u, v),
@@ -100,8 +98,14 @@ impl Shape for Sphere {
             time,
             None,
             None,
-            // mi,
-        ))
+        );
+        if self.shape_data.reverse_orientation {
+            inter.n = inter.n.map(|n| -n);
+            if let Some(ref mut shading) = inter.shading {
+                shading.n = -shading.n;
+            }
+        }
+        Some(inter)
     }
 
     fn area(&self) -> F {
@@ -0,0 +1,430 @@
+use rayon::prelude::*;
+
+use crate::{
+    aabb::AABB3,
+    camera::SimpleCamera,
+    color::{black, color3, Color3},
+    common::*,
+    film::Film,
+    integrator::{uniform_sample_one_light, Integrator},
+    material::{
+        Bsdf, TransportMode, BXDF_ALL, BXDF_DIFFUSE, BXDF_GLOSSY, BXDF_REFLECTION, BXDF_SPECULAR,
+        BXDF_TRANSMISSION,
+    },
+    progress::ProgressReporter,
+    ray::Ray,
+    rng::RngGen,
+    sampler::Sampler,
+    scene::Scene,
+    tiles,
+    vector::*,
+};
+
+/// Where a camera path first lands on a non-specular surface, waiting for photons.
+struct VisiblePoint {
+    p: Point3,
+    wo: Vec3,
+    bsdf: Bsdf,
+    beta: Color3,
+}
+
+struct SppmPixel {
+    radius: F,
+    /// Where in the pixel the current iteration's camera ray passes through.
+    p_film: Point2,
+    /// Light reaching the camera directly or through specular bounces in the current
+    /// iteration.
+    ld: Color3,
+    vp: Option<VisiblePoint>,
+    n: F,
+    tau: Color3,
+}
+
+/// Photons traced by each parallel task before its contributions are added to the pixels.
+const PHOTON_CHUNK_SIZE: S = 1024;
+
+/// Uniform grid over the visible points, hashed into one bucket per pixel so memory doesn't
+/// depend on how finely the points are spread out.
+struct VisiblePointGrid {
+    bounds: AABB3,
+    res: [I; 3],
+    cells: Vec<Vec<S>>,
+}
+
+impl VisiblePointGrid {
+    fn new(pixels: &[SppmPixel]) -> Option<Self> {
+        let mut bounds: Option<AABB3> = None;
+        let mut max_radius: F = 0.0;
+        for pixel in pixels.iter() {
+            if let Some(ref vp) = pixel.vp {
+                let vp_bounds = AABB3::from(vp.p).expand(pixel.radius);
+                bounds = Some(match bounds {
+                    Some(b) => b.combine(vp_bounds),
+                    None => vp_bounds,
+                });
+                max_radius = max_radius.max(pixel.radius);
+            }
+        }
+        let bounds = bounds?;
+        let diag = bounds.diagonal();
+        let max_diag = diag.max();
+        let base_res = max_diag / max_radius;
+        let res = [0, 1, 2].map(|i| ((base_res * diag[i] / max_diag) as I).max(1));
+
+        let mut grid = Self {
+            bounds,
+            res,
+            cells: vec![vec![]; pixels.len()],
+        };
+        for (i, pixel) in pixels.iter().enumerate() {
+            if let Some(ref vp) = pixel.vp {
+                let r = vec3(pixel.radius, pixel.radius, pixel.radius);
+                let p_min = grid.to_grid(&(vp.p - r));
+                let p_max = grid.to_grid(&(vp.p + r));
+                for z in p_min[2]..=p_max[2] {
+                    for y in p_min[1]..=p_max[1] {
+                        for x in p_min[0]..=p_max[0] {
+                            let h = grid.hash([x, y, z]);
+                            grid.cells[h].push(i);
+                        }
+                    }
+                }
+            }
+        }
+        Some(grid)
+    }
+
+    fn to_grid(&self, p: &Point3) -> [I; 3] {
+        let offset = self.bounds.offset(*p);
+        [0, 1, 2].map(|i| ((self.res[i] as F * offset[i]) as I).clamp(0, self.res[i] - 1))
+    }
+
+    fn hash(&self, p: [I; 3]) -> S {
+        ((p[0].wrapping_mul(73856093) ^ p[1].wrapping_mul(19349663) ^ p[2].wrapping_mul(83492791))
+            as u64
+            % self.cells.len() as u64) as S
+    }
+
+    /// Indices of the pixels whose visible points may lie within their radius of `p`.
+    fn candidates(&self, p: &Point3) -> &[S] {
+        if !self.bounds.inside(*p) {
+            return &[];
+        }
+        &self.cells[self.hash(self.to_grid(p))]
+    }
+}
+
+/// Stochastic progressive photon mapping. Each iteration traces one camera path per pixel to
+/// find a visible point, then shoots photons from the lights and gathers those landing near the
+/// visible points. The gather radius shrinks every iteration, so the estimate converges, and
+/// paths like caustics seen through glass that can't be sampled from the camera come out
+/// cleanly. Participating media are ignored.
+pub struct SppmIntegrator {
+    max_depth: S,
+    photons_per_iteration: S,
+    initial_radius: F,
+}
+
+impl SppmIntegrator {
+    /// Each sample per pixel of the render is one camera and photon iteration.
+    pub fn new(max_depth: S, photons_per_iteration: S, initial_radius: F) -> Self {
+        Self {
+            max_depth,
+            photons_per_iteration,
+            initial_radius,
+        }
+    }
+
+    /// Follows a camera ray through specular bounces, gathering direct lighting along the way,
+    /// until it reaches a surface where a visible point can be recorded.
+    fn camera_pass(&self, pixel: &mut SppmPixel, ray: &mut Ray, scene: &Scene, rng: &RngGen) {
+        let mut beta = color3(1.0, 1.0, 1.0);
+        let mut specular_bounce = false;
+        let mut depth = 0;
+        while depth < self.max_depth {
+            let inter = match scene.intersect(ray) {
+                Some(inter) => inter,
+                None => {
+                    if depth == 0 || specular_bounce {
+                        for light in scene.lights.iter() {
+                            pixel.ld += beta.component_mul(&light.le(ray));
+                        }
+                    }
+                    return;
+                }
+            };
+            let bsdf = match inter.bsdf {
+                Some(ref bsdf) => bsdf,
+                None => {
+                    *ray = inter.spawn_ray(ray.direction);
+                    continue;
+                }
+            };
+
+            pixel.ld += beta.component_mul(&uniform_sample_one_light(&inter, scene, rng));
+            let wo = -ray.direction.normalize();
+            let is_diffuse =
+                bsdf.num_components(BXDF_DIFFUSE | BXDF_REFLECTION | BXDF_TRANSMISSION) > 0;
+            let is_glossy =
+                bsdf.num_components(BXDF_GLOSSY | BXDF_REFLECTION | BXDF_TRANSMISSION) > 0;
+            if is_diffuse || (is_glossy && depth == self.max_depth - 1) {
+                pixel.vp = Some(VisiblePoint {
+                    p: inter.p,
+                    wo,
+                    bsdf: bsdf.clone(),
+                    beta,
+                });
+                return;
+            }
+
+            match bsdf.sample_f(&wo, &rng.uniform_sample_point2(), BXDF_ALL) {
+                Some((f, pdf, wi, flags)) if f != black() && pdf > 0.0 => {
+                    specular_bounce = flags & BXDF_SPECULAR != 0;
+                    beta.component_mul_assign(
+                        &(f * wi.dot(&inter.shading.as_ref().unwrap().n).abs() / pdf),
+                    );
+                    if beta.max() < 0.25 {
+                        let continue_prob = beta.max().min(1.0);
+                        if rng.sample_0_1() > continue_prob {
+                            return;
+                        }
+                        beta /= continue_prob;
+                    }
+                    *ray = inter.spawn_ray(wi);
+                }
+                _ => return,
+            }
+            depth += 1;
+        }
+    }
+
+    /// Traces a single photon from a randomly chosen light, adding its contribution to every
+    /// visible point it lands near after the first bounce to `gathered`, with the point's pixel.
+    fn photon_pass(
+        &self,
+        pixels: &[SppmPixel],
+        grid: &VisiblePointGrid,
+        scene: &Scene,
+        rng: &RngGen,
+        gathered: &mut Vec<(S, Color3)>,
+    ) {
+        let light_pdf = 1.0 / scene.lights.len() as F;
+        let light_idx =
+            ((rng.sample_0_1() * scene.lights.len() as F) as S).min(scene.lights.len() - 1);
+        let le = match scene.lights[light_idx].sample_le(
+            rng.uniform_sample_point2(),
+            rng.uniform_sample_point2(),
+            0.0,
+        ) {
+            Some(le) if le.pdf_pos > 0.0 && le.pdf_dir > 0.0 && le.col != black() => le,
+            _ => return,
+        };
+        let mut beta = le.col * le.n_light.dot(&le.ray.direction).abs()
+            / (light_pdf * le.pdf_pos * le.pdf_dir);
+        if beta == black() {
+            return;
+        }
+
+        let mut ray = le.ray;
+        let mut depth = 0;
+        while depth < self.max_depth {
+            let inter = match scene.intersect(&mut ray) {
+                Some(inter) => inter,
+                None => return,
+            };
+            let bsdf = match inter.bsdf {
+                Some(ref bsdf) => bsdf,
+                None => {
+                    ray = inter.spawn_ray(ray.direction);
+                    continue;
+                }
+            };
+
+            let wi = -ray.direction.normalize();
+            // Photons arriving straight from the light would duplicate the direct lighting
+            // estimated in the camera pass.
+            if depth > 0 {
+                for &i in grid.candidates(&inter.p) {
+                    let pixel = &pixels[i];
+                    let vp = pixel.vp.as_ref().unwrap();
+                    if distance_squared3d(&vp.p, &inter.p) > pixel.radius * pixel.radius {
+                        continue;
+                    }
+                    gathered.push((i, beta.component_mul(&vp.bsdf.f(&vp.wo, &wi, BXDF_ALL))));
+                }
+            }
+
+            let (f, pdf, wo, flags) =
+                match bsdf.sample_f(&wi, &rng.uniform_sample_point2(), BXDF_ALL) {
+                    Some((f, pdf, wo, flags)) if f != black() && pdf > 0.0 => (f, pdf, wo, flags),
+                    _ => return,
+                };
+            let beta_new = beta
+                .component_mul(&(f * wo.dot(&inter.shading.as_ref().unwrap().n).abs() / pdf))
+                * bsdf.transport_scale(&wi, flags, TransportMode::Importance);
+            // Keep photons at roughly constant power, terminating them in proportion to how
+            // much the bounce absorbed.
+            let q = F::max(0.0, 1.0 - beta_new.max() / beta.max());
+            if rng.sample_0_1() < q {
+                return;
+            }
+            beta = beta_new / (1.0 - q);
+            ray = inter.spawn_ray(wo);
+            depth += 1;
+        }
+    }
+}
+
+impl Integrator for SppmIntegrator {
+    /// Only the light reaching the camera directly or via specular paths, i.e. what a single
+    /// camera pass estimates. Indirect light needs the photon passes run by `render`.
+    fn li(&self, ray: &mut Ray, scene: &Scene, depth: S, rng: &RngGen) -> Color3 {
+        let mut pixel = SppmPixel {
+            radius: self.initial_radius,
+            p_film: point2(0.0, 0.0),
+            ld: black(),
+            vp: None,
+            n: 0.0,
+            tau: black(),
+        };
+        self.camera_pass(&mut pixel, ray, scene, rng);
+        pixel.ld
+    }
+
+    fn progressive(&self) -> bool {
+        false
+    }
+
+    fn render(
+        &self,
+        scene: &Scene,
+        cam: &SimpleCamera,
+        samples_per_pixel: S,
+        rng: &RngGen,
+    ) -> Vec<Color3> {
+        let iterations = samples_per_pixel;
+        let (x0, y0, x1, y1) = cam.pixel_bounds();
+        let width = x1 - x0;
+        let mut pixels: Vec<SppmPixel> = (0..width * (y1 - y0))
+            .map(|_| SppmPixel {
+                radius: self.initial_radius,
+                p_film: point2(0.0, 0.0),
+                ld: black(),
+                vp: None,
+                n: 0.0,
+                tau: black(),
+            })
+            .collect();
+
+        // The direct lighting is sampled anew each iteration, so is filtered like any other
+        // integrator's samples. The photon estimate only converges per pixel, so is filtered
+        // from the pixel centers at the end.
+        let film = Film::new(cam.pixel_bounds(), cam.filter.clone());
+        let progress = ProgressReporter::new(iterations, "Rendering");
+        for iteration in 0..iterations {
+            pixels.par_iter_mut().enumerate().for_each(|(i, pixel)| {
+                pixel.vp = None;
+                pixel.ld = black();
+                let (x, y) = (x0 + i % width, y0 + i / width);
+                rng.start_pixel_sample((x, y), iteration);
+                pixel.p_film = point2(x as F, y as F) + rng.get_pixel_2d();
+                // `get_ray` aims through the pixel center, so offset back to the sample.
+                let mut ray = cam.get_ray(pixel.p_film - point2(0.5, 0.5));
+                self.camera_pass(pixel, &mut ray, scene, rng);
+            });
+            add_samples(&film, &pixels, |_, pixel| (pixel.p_film, pixel.ld));
+
+            // Photon contributions and count gathered at each pixel's visible point. Chunks of
+            // photons are traced in parallel but added in order, so the sums don't depend on
+            // the threads.
+            let mut phi = vec![black(); pixels.len()];
+            let mut m = vec![0; pixels.len()];
+            if let Some(grid) = VisiblePointGrid::new(&pixels) {
+                if !scene.lights.is_empty() {
+                    let first_photon = iteration * self.photons_per_iteration;
+                    let n_chunks = self.photons_per_iteration.div_ceil(PHOTON_CHUNK_SIZE);
+                    tiles::for_each_in_order(
+                        n_chunks,
+                        || false,
+                        |chunk| {
+                            let start = chunk * PHOTON_CHUNK_SIZE;
+                            let end = (start + PHOTON_CHUNK_SIZE).min(self.photons_per_iteration);
+                            let mut gathered = vec![];
+                            for j in start..end {
+                                rng.start_sequence(first_photon + j);
+                                self.photon_pass(&pixels, &grid, scene, rng, &mut gathered);
+                            }
+                            gathered
+                        },
+                        |_, gathered| {
+                            for (i, col) in gathered {
+                                phi[i] += col;
+                                m[i] += 1;
+                            }
+                        },
+                    );
+                }
+            }
+
+            // Shrink the radius of every pixel that received photons, keeping a fraction
+            // gamma of the new ones.
+            pixels
+                .par_iter_mut()
+                .zip(phi)
+                .zip(m)
+                .for_each(|((pixel, phi), m)| {
+                    let m = m as F;
+                    if m > 0.0 {
+                        let gamma = 2.0 / 3.0;
+                        let n_new = pixel.n + gamma * m;
+                        let radius_new = pixel.radius * F::sqrt(n_new / (pixel.n + m));
+                        let vp = pixel.vp.as_ref().unwrap();
+                        pixel.tau = (pixel.tau + vp.beta.component_mul(&phi))
+                            * (radius_new * radius_new)
+                            / (pixel.radius * pixel.radius);
+                        pixel.n = n_new;
+                        pixel.radius = radius_new;
+                    }
+                });
+            progress.update(1);
+        }
+        progress.done();
+
+        let n_photons = (iterations * self.photons_per_iteration) as F;
+        let indirect = Film::new(cam.pixel_bounds(), cam.filter.clone());
+        add_samples(&indirect, &pixels, |(x, y), pixel| {
+            let l = pixel.tau / (n_photons * PI * pixel.radius * pixel.radius);
+            (point2(x as F + 0.5, y as F + 0.5), l)
+        });
+        film.image(1.0)
+            .iter()
+            .zip(indirect.image(1.0))
+            .map(|(ld, l)| ld + l)
+            .collect()
+    }
+}
+
+/// Adds a sample for every pixel to `film`, a tile at a time, taken at the raster position
+/// and with the radiance `sample` gives for the pixel.
+fn add_samples(
+    film: &Film,
+    pixels: &[SppmPixel],
+    sample: impl Fn((S, S), &SppmPixel) -> (Point2, Color3) + Sync,
+) {
+    let (x0, y0, x1, _) = film.bounds;
+    tiles::for_each_tile(
+        film.bounds,
+        |t| {
+            let (tx0, ty0, tx1, ty1) = t.bounds;
+            let mut tile = film.tile(tx0, ty0, tx1, ty1);
+            for y in ty0..ty1 {
+                for x in tx0..tx1 {
+                    let (p_film, l) = sample((x, y), &pixels[(y - y0) * (x1 - x0) + (x - x0)]);
+                    tile.add_sample(&p_film, &l, 1.0);
+                }
+            }
+            tile
+        },
+        |_, tile| film.merge_tile(tile),
+    );
+}
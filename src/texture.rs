@@ -1,4 +1,5 @@
 use crate::color::Color3;
+use crate::colorspace::ColorSpace;
 use crate::common::*;
 use crate::interaction::Interaction;
 pub trait ScalarTexture {
@@ -19,10 +20,20 @@ pub trait ColorTexture {
     fn eval(&self, inter: &Interaction) -> Color3;
 }
 
+/// A constant color, in the working space.
 pub struct SolidColor {
     pub color: Color3,
 }
 
+impl SolidColor {
+    /// A constant color given in `space`, converted to the working space.
+    pub fn new_in(color: Color3, space: &ColorSpace) -> Self {
+        Self {
+            color: space.to_working(&color),
+        }
+    }
+}
+
 impl ColorTexture for SolidColor {
     fn eval(&self, inter: &Interaction) -> Color3 {
         self.color
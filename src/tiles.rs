@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use clap::ValueEnum;
+use rayon::prelude::*;
+
+use crate::common::*;
+use crate::film::PixelBounds;
+
+/// The order tiles are handed out to the render threads in. It doesn't affect the samples,
+/// since each pixel sample draws from a sequence of its own, only which parts of the image
+/// are done first, how well neighboring tiles share caches, and the rounding of sums over
+/// several tiles, such as splats, which are added up in this order.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, ValueEnum)]
+pub enum TileOrder {
+    /// Row by row from the top left.
+    Scanline,
+    /// Outwards from the center, where the subject usually is.
+    Spiral,
+    /// Along a Hilbert curve, so consecutive tiles are mostly neighbors.
+    #[default]
+    Hilbert,
+}
+
+static TILE_SIZE: AtomicUsize = AtomicUsize::new(16);
+static TILE_ORDER: AtomicU8 = AtomicU8::new(TileOrder::Hilbert as u8);
+/// Set to stop handing out tiles. See `cancel`.
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// Sets the width and height in pixels of the tiles images are split into.
+pub fn set_tile_size(size: S) {
+    TILE_SIZE.store(size.max(1), Ordering::Relaxed);
+}
+
+pub fn set_tile_order(order: TileOrder) {
+    TILE_ORDER.store(order as u8, Ordering::Relaxed);
+}
+
+fn tile_order() -> TileOrder {
+    TileOrder::value_variants()[TILE_ORDER.load(Ordering::Relaxed) as S]
+}
+
+/// Stops every tiled render from starting any more tiles, e.g. at a deadline. Tiles already
+/// started are finished, and the rest of the image is left as it was. Stays in effect until
+/// `set_cancelled(false)`.
+pub fn cancel() {
+    set_cancelled(true);
+}
+
+pub fn set_cancelled(cancelled: bool) {
+    CANCELLED.store(cancelled, Ordering::Relaxed);
+}
+
+pub fn cancelled() -> bool {
+    CANCELLED.load(Ordering::Relaxed)
+}
+
+/// A rectangle of pixels rendered by one thread at a time.
+#[derive(Clone, Copy, Debug)]
+pub struct Tile {
+    /// Position of the tile counting row by row from the top left, whatever the order.
+    pub index: S,
+    pub bounds: PixelBounds,
+}
+
+/// Splits `bounds` into tiles of the current size, listed in the current order.
+pub fn tiles(bounds: PixelBounds) -> Vec<Tile> {
+    let (bx0, by0, bx1, by1) = bounds;
+    let size = TILE_SIZE.load(Ordering::Relaxed);
+    let nx = bx1.saturating_sub(bx0).div_ceil(size);
+    let ny = by1.saturating_sub(by0).div_ceil(size);
+    let tile = |(tx, ty): (S, S)| {
+        let (x0, y0) = (bx0 + tx * size, by0 + ty * size);
+        Tile {
+            index: ty * nx + tx,
+            bounds: (x0, y0, (x0 + size).min(bx1), (y0 + size).min(by1)),
+        }
+    };
+    let positions: Vec<(S, S)> = match tile_order() {
+        TileOrder::Scanline => (0..nx * ny).map(|i| (i % nx, i / nx)).collect(),
+        TileOrder::Spiral => spiral(nx, ny),
+        TileOrder::Hilbert => hilbert(nx, ny),
+    };
+    positions.into_iter().map(tile).collect()
+}
+
+/// Walks square rings outwards from the center of an `nx` by `ny` grid.
+fn spiral(nx: S, ny: S) -> Vec<(S, S)> {
+    let total = nx * ny;
+    let mut out = Vec::with_capacity(total);
+    let (mut x, mut y) = ((nx as I - 1) / 2, (ny as I - 1) / 2);
+    let directions = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+    let visit = |x: I, y: I, out: &mut Vec<(S, S)>| {
+        if x >= 0 && y >= 0 && (x as S) < nx && (y as S) < ny {
+            out.push((x as S, y as S));
+        }
+    };
+    visit(x, y, &mut out);
+    let mut leg = 0;
+    while out.len() < total {
+        let (dx, dy) = directions[leg % 4];
+        // Legs grow by one every second turn: 1, 1, 2, 2, 3, 3, ...
+        for _ in 0..leg / 2 + 1 {
+            x += dx;
+            y += dy;
+            visit(x, y, &mut out);
+        }
+        leg += 1;
+    }
+    out
+}
+
+/// Follows a Hilbert curve over the smallest power of two square covering an `nx` by `ny`
+/// grid, skipping the cells outside it.
+fn hilbert(nx: S, ny: S) -> Vec<(S, S)> {
+    let n = nx.max(ny).next_power_of_two();
+    (0..n * n)
+        .map(|d| hilbert_position(n, d))
+        .filter(|&(x, y)| x < nx && y < ny)
+        .collect()
+}
+
+/// The cell at distance `d` along the Hilbert curve over an `n` by `n` grid.
+fn hilbert_position(n: S, d: S) -> (S, S) {
+    let (mut x, mut y) = (0, 0);
+    let mut t = d;
+    let mut s = 1;
+    while s < n {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+        // Rotate the quadrant so the curve joins up.
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    (x, y)
+}
+
+/// Calls `render_tile` for every tile of `bounds` in parallel, and `merge` with each result
+/// in the order the tiles were handed out. See `for_each_in_order`. Handing out stops once the
+/// render is cancelled. Returns whether every tile was rendered.
+pub fn for_each_tile<T: Send>(
+    bounds: PixelBounds,
+    render_tile: impl Fn(&Tile) -> T + Sync,
+    mut merge: impl FnMut(&Tile, T) + Send,
+) -> bool {
+    let tiles = tiles(bounds);
+    let rendered = for_each_in_order(
+        tiles.len(),
+        cancelled,
+        |i| render_tile(&tiles[i]),
+        |i, result| merge(&tiles[i], result),
+    );
+    rendered == tiles.len()
+}
+
+/// Calls `work` for `0..n` in parallel and `merge` with the results in index order, each as
+/// soon as those before it have been merged. Indices are handed out in order from a shared
+/// counter while rayon balances the threads, so few results wait at a time, and they're
+/// combined the same way however many threads there are, e.g. to sum floating point values
+/// reproducibly. Handing out stops once `stop` returns true. Returns how many results were
+/// merged, which is every index handed out.
+pub fn for_each_in_order<T: Send>(
+    n: S,
+    stop: impl Fn() -> bool + Sync,
+    work: impl Fn(S) -> T + Sync,
+    merge: impl FnMut(S, T) + Send,
+) -> S {
+    let next = AtomicUsize::new(0);
+    // The next index to merge, the results waiting for earlier ones, and `merge`.
+    let pending = Mutex::new((0, BTreeMap::new(), merge));
+    (0..n).into_par_iter().for_each(|_| {
+        if stop() {
+            return;
+        }
+        let i = next.fetch_add(1, Ordering::Relaxed);
+        let result = work(i);
+        let mut pending = pending.lock().unwrap();
+        let (merged, waiting, merge) = &mut *pending;
+        waiting.insert(i, result);
+        while let Some(result) = waiting.remove(merged) {
+            merge(*merged, result);
+            *merged += 1;
+        }
+    });
+    pending.into_inner().unwrap().0
+}
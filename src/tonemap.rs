@@ -0,0 +1,185 @@
+use clap::ValueEnum;
+
+use crate::color::*;
+use crate::colorspace::{chromatic_adaptation, working_space, ColorSpace};
+use crate::common::*;
+use crate::matrix::Matrix3;
+use crate::vector::*;
+
+/// The curve that squeezes scene radiance into the displayable `[0, 1]` range.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, ValueEnum)]
+pub enum ToneMapOperator {
+    /// Clips everything above one.
+    #[default]
+    Linear,
+    /// `L / (1 + L)` on luminance, which never quite reaches white.
+    Reinhard,
+    /// Reinhard, reaching white at the tone mapper's `white_point`.
+    ExtendedReinhard,
+    /// Fit of the ACES reference rendering and sRGB output transforms.
+    Aces,
+    /// John Hable's filmic curve from Uncharted 2.
+    Hable,
+    /// Troy Sobotka's AgX, which desaturates highlights towards white instead of clipping hues.
+    Agx,
+}
+
+/// Turns linear scene colors into display colors: exposure, then conversion from the working
+/// space to sRGB with white balance, then the tone curve, then the sRGB transfer function.
+#[derive(Clone, Debug)]
+pub struct ToneMapper {
+    pub operator: ToneMapOperator,
+    /// Exposure compensation in stops; each one doubles the brightness.
+    pub exposure: F,
+    /// Luminance that maps to white with `ExtendedReinhard`.
+    pub white_point: F,
+    /// Converts working space colors to linear sRGB, white balancing them on the way.
+    to_display: Matrix3,
+}
+
+impl Default for ToneMapper {
+    fn default() -> Self {
+        Self::new(ToneMapOperator::Linear, 0.0)
+    }
+}
+
+impl ToneMapper {
+    pub fn new(operator: ToneMapOperator, exposure: F) -> Self {
+        Self {
+            operator,
+            exposure,
+            white_point: 4.0,
+            to_display: working_space().conversion_to(ColorSpace::srgb()),
+        }
+    }
+
+    /// Adapts the image so that light of the given color temperature, in Kelvin, appears
+    /// white, as a camera's white balance setting does. `None` leaves colors as they are.
+    pub fn set_white_balance(&mut self, temperature: Option<F>) {
+        let srgb = ColorSpace::srgb();
+        let adaptation = match temperature {
+            Some(t) => chromatic_adaptation(planckian_xy(t), srgb.white),
+            None => chromatic_adaptation(working_space().white, srgb.white),
+        };
+        self.to_display = srgb.xyz_to_rgb * adaptation * working_space().rgb_to_xyz;
+    }
+
+    /// Maps a linear scene color to a linear display color in `[0, 1]`.
+    pub fn apply(&self, col: &Color3) -> Color3 {
+        let col = (self.to_display * col * F::exp2(self.exposure)).map(|c| c.max(0.0));
+        let col = match self.operator {
+            ToneMapOperator::Linear => col,
+            ToneMapOperator::Reinhard => scale_luminance(&col, |l| l / (1.0 + l)),
+            ToneMapOperator::ExtendedReinhard => {
+                let w2 = self.white_point * self.white_point;
+                scale_luminance(&col, |l| l * (1.0 + l / w2) / (1.0 + l))
+            }
+            ToneMapOperator::Aces => aces_fitted(&col),
+            ToneMapOperator::Hable => {
+                const WHITE: F = 11.2;
+                const EXPOSURE_BIAS: F = 2.0;
+                (col * EXPOSURE_BIAS).map(hable_partial) / hable_partial(WHITE)
+            }
+            ToneMapOperator::Agx => agx(&col),
+        };
+        col.map(|c| c.clamp(0.0, 1.0))
+    }
+
+    /// Tone maps and encodes a color as 8-bit sRGB.
+    pub fn to_srgb8(&self, col: &Color3) -> [u8; 3] {
+        let col = self.apply(col);
+        let quantize = |v: F| (linear_to_srgb(v) * 255.0).round() as u8;
+        [quantize(col.x), quantize(col.y), quantize(col.z)]
+    }
+
+    /// Like `to_srgb8`, as an opaque RGBA pixel for the preview window.
+    pub fn to_pixel(&self, col: &Color3) -> [u8; 4] {
+        let [r, g, b] = self.to_srgb8(col);
+        [r, g, b, 255]
+    }
+}
+
+/// Applies a tone curve to the luminance of the linear sRGB `col`, keeping its hue and
+/// saturation.
+fn scale_luminance(col: &Color3, curve: impl Fn(F) -> F) -> Color3 {
+    let l = ColorSpace::srgb().luminance(col);
+    if l <= 0.0 {
+        return black();
+    }
+    col * (curve(l) / l)
+}
+
+fn hable_partial(x: F) -> F {
+    const A: F = 0.15;
+    const B: F = 0.50;
+    const C: F = 0.10;
+    const D: F = 0.20;
+    const E: F = 0.02;
+    const F_: F = 0.30;
+    ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F_)) - E / F_
+}
+
+/// Stephen Hill's fit of the ACES RRT and sRGB ODT, which works in the ACES AP1 space.
+fn aces_fitted(col: &Color3) -> Color3 {
+    let input = Matrix3::new(
+        0.59719, 0.35458, 0.04823, //
+        0.07600, 0.90834, 0.01566, //
+        0.02840, 0.13383, 0.83777,
+    );
+    let output = Matrix3::new(
+        1.60475, -0.53108, -0.07367, //
+        -0.10208, 1.10813, -0.00605, //
+        -0.00327, -0.07276, 1.07602,
+    );
+    let v = input * col;
+    let rrt_odt = v.map(|x| {
+        (x * (x + 0.024_578_6) - 0.000_090_537) / (x * (0.983_729 * x + 0.432_951) + 0.238_081)
+    });
+    output * rrt_odt
+}
+
+/// A polynomial fit of the AgX base contrast curve, following Benjamin Wrensch's version.
+fn agx(col: &Color3) -> Color3 {
+    const MIN_EV: F = -12.473_93;
+    const MAX_EV: F = 4.026_069;
+    let inset = Matrix3::new(
+        0.8424791, 0.0784336, 0.0792237, //
+        0.0423282, 0.8784686, 0.0791661, //
+        0.0423757, 0.0784336, 0.879143,
+    );
+    let outset = Matrix3::new(
+        1.196879, -0.0980209, -0.0990297, //
+        -0.0528969, 1.1519031, -0.0989612, //
+        -0.0529716, -0.0980435, 1.1510737,
+    );
+    let v = (inset * col).map(|x| {
+        let x = (x.max(1e-10).log2().clamp(MIN_EV, MAX_EV) - MIN_EV) / (MAX_EV - MIN_EV);
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+            - 0.00232
+    });
+    // The curve produces display encoded values; undo the encoding it assumes.
+    (outset * v).map(|x| x.max(0.0).powf(2.2))
+}
+
+/// Chromaticity of a black body at `t` Kelvin, from Kang et al.'s fit of the Planckian locus,
+/// valid from 1667K to 25000K.
+fn planckian_xy(t: F) -> Point2 {
+    let t = t.clamp(1667.0, 25000.0);
+    let (t2, t3) = (t * t, t * t * t);
+    let x = if t <= 4000.0 {
+        -0.266_123_9e9 / t3 - 0.234_358_9e6 / t2 + 0.877_695_6e3 / t + 0.179_910
+    } else {
+        -3.025_846_9e9 / t3 + 2.107_038e6 / t2 + 0.222_634_7e3 / t + 0.240_390
+    };
+    let (x2, x3) = (x * x, x * x * x);
+    let y = if t <= 2222.0 {
+        -1.106_381_4 * x3 - 1.348_110_2 * x2 + 2.185_558_3 * x - 0.202_196_83
+    } else if t <= 4000.0 {
+        -0.954_947_6 * x3 - 1.374_185_9 * x2 + 2.091_37 * x - 0.167_488_67
+    } else {
+        3.081_758 * x3 - 5.873_387 * x2 + 3.751_13 * x - 0.370_014_83
+    };
+    point2(x, y)
+}
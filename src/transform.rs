@@ -308,6 +308,7 @@ impl Transform {
         Ray {
             origin: o,
             direction: d,
+            medium: a.medium.clone(),
             ..*a
         }
     }
@@ -321,6 +322,7 @@ impl Transform {
         Ray {
             origin: o,
             direction: d,
+            medium: a.medium.clone(),
             ..*a
         }
     }
@@ -419,7 +421,9 @@ impl Transform {
             // p_error: a.p_error,
             // shape: a.shape,
             bsdf: a.bsdf,
-            // medium_interface: a.medium_interface,
+            medium_interface: a.medium_interface,
+            phase: a.phase,
+            bssrdf: a.bssrdf,
         }
     }
 }
@@ -429,7 +433,7 @@ impl Mul<Transform> for Transform {
     fn mul(self, rhs: Transform) -> Transform {
         Transform::new(
             self.m_forward * rhs.m_forward,
-            self.m_inverse * rhs.m_inverse,
+            rhs.m_inverse * self.m_inverse,
         )
     }
 }
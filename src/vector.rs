@@ -47,6 +47,29 @@ pub fn spherical_phi(v: &Vec3) -> F {
     }
 }
 
+/// Builds two vectors that form an orthonormal basis together with the (normalized) `v1`.
+pub fn coordinate_system(v1: &Vec3) -> (Vec3, Vec3) {
+    let v2 = if v1.x.abs() > v1.y.abs() {
+        vec3(-v1.z, 0.0, v1.x) / F::sqrt(v1.x * v1.x + v1.z * v1.z)
+    } else {
+        vec3(0.0, v1.z, -v1.y) / F::sqrt(v1.y * v1.y + v1.z * v1.z)
+    };
+    let v3 = v1.cross(&v2);
+    (v2, v3)
+}
+
+/// Converts spherical coordinates to a direction in the frame given by `x`, `y` and `z`.
+pub fn spherical_direction(
+    sin_theta: F,
+    cos_theta: F,
+    phi: F,
+    x: &Vec3,
+    y: &Vec3,
+    z: &Vec3,
+) -> Vec3 {
+    sin_theta * phi.cos() * x + sin_theta * phi.sin() * y + cos_theta * z
+}
+
 // Standard 3d float vector.
 // #[derive(Clone, Copy, PartialEq, Debug)]
 // pub struct Vec3 {
@@ -0,0 +1,57 @@
+//! Seeded renders have to come out bit-identical however many threads render them, including
+//! with integrators that splat contributions onto arbitrary pixels or share what earlier
+//! samples found, such as photons or cached irradiance.
+
+use std::process::Command;
+
+fn render(integrator: &str, spp: usize, threads: usize) -> Vec<u8> {
+    let output = std::env::temp_dir().join(format!(
+        "rustyrays-determinism-{}-{integrator}-{threads}.pfm",
+        std::process::id()
+    ));
+    let status = Command::new(env!("CARGO_BIN_EXE_rustyrays"))
+        .args(["--headless", "--quiet", "--width", "24", "--height", "16"])
+        .args(["--seed", "7", "--integrator", integrator])
+        .args(["--spp", &spp.to_string(), "--threads", &threads.to_string()])
+        .arg("--output")
+        .arg(&output)
+        .status()
+        .expect("failed to run rustyrays");
+    assert!(status.success(), "{integrator} render failed");
+    let image = std::fs::read(&output).unwrap();
+    std::fs::remove_file(&output).ok();
+    image
+}
+
+fn assert_same_across_thread_counts(integrator: &str, spp: usize) {
+    assert!(
+        render(integrator, spp, 1) == render(integrator, spp, 4),
+        "{integrator} renders differ between 1 and 4 threads"
+    );
+}
+
+#[test]
+fn bdpt_is_independent_of_thread_count() {
+    assert_same_across_thread_counts("bdpt", 2);
+}
+
+#[test]
+fn mlt_is_independent_of_thread_count() {
+    assert_same_across_thread_counts("mlt", 2);
+}
+
+#[test]
+fn sppm_is_independent_of_thread_count() {
+    assert_same_across_thread_counts("sppm", 2);
+}
+
+#[test]
+fn irradiance_cache_is_independent_of_thread_count() {
+    assert_same_across_thread_counts("irradiance-cache", 2);
+}
+
+#[test]
+fn guided_is_independent_of_thread_count() {
+    // Enough samples for passes guided by what the earlier ones learned.
+    assert_same_across_thread_counts("guided", 8);
+}